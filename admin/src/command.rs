@@ -0,0 +1,75 @@
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+/// The administration of the proxy and agent users
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+pub(crate) struct CommandArgs {
+    /// The user repository directory of the proxy
+    #[arg(short = 'p', long, default_value = "./resources/proxy/user")]
+    pub proxy_user_repo_directory: PathBuf,
+    /// The user repository directory of the agent
+    #[arg(short = 'a', long, default_value = "./resources/agent/user")]
+    pub agent_user_repo_directory: PathBuf,
+    #[command(subcommand)]
+    pub command: Command,
+}
+#[derive(Subcommand)]
+pub(crate) enum Command {
+    /// Manage the users
+    #[command(subcommand)]
+    User(UserCommand),
+}
+#[derive(Subcommand)]
+pub(crate) enum UserCommand {
+    /// Create the user with new keys on both the proxy and the agent side
+    Add {
+        username: String,
+        /// The proxy servers of the agent user, separated by comma
+        #[arg(short = 's', long, value_delimiter = ',')]
+        proxy_servers: Vec<SocketAddr>,
+        /// The expired time in RFC 3339, such as 2030-01-01T00:00:00Z
+        #[arg(short = 'e', long)]
+        expired_time: Option<DateTime<Utc>>,
+        /// The bytes per second from the client to the destinations
+        #[arg(long)]
+        upload_rate_limit: Option<u64>,
+        /// The bytes per second from the destinations to the client
+        #[arg(long)]
+        download_rate_limit: Option<u64>,
+        /// The bytes can be transferred in both directions each month
+        #[arg(long)]
+        monthly_traffic_quota: Option<u64>,
+        /// The max concurrent sessions
+        #[arg(long)]
+        max_sessions: Option<usize>,
+        /// The bits of the rsa keys
+        #[arg(short = 'k', long, default_value_t = 2048)]
+        key_size: usize,
+    },
+    /// Remove the user from both the proxy and the agent side
+    Remove { username: String },
+    /// List the users on the proxy and the agent side
+    List,
+    /// Replace the keys of the user on both the proxy and the agent side,
+    /// the agent need the new bundle exported after the rotation.
+    RotateKeys {
+        username: String,
+        /// The bits of the rsa keys
+        #[arg(short = 'k', long, default_value_t = 2048)]
+        key_size: usize,
+        /// How long the proxy keeps accepting the previous keys, so the
+        /// agents not updated yet keep working, 0 means a hard cutover.
+        #[arg(long, default_value_t = 604800)]
+        previous_keys_valid_sec: u32,
+    },
+    /// Copy the agent side of the user into the output directory,
+    /// so that it can be put into the user repository of the agent.
+    ExportAgentBundle {
+        username: String,
+        /// The directory to export the bundle into
+        #[arg(short = 'o', long)]
+        output_directory: PathBuf,
+    },
+}
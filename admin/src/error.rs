@@ -0,0 +1,19 @@
+use crypto::Error as CryptoError;
+use thiserror::Error;
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Toml(#[from] toml::de::Error),
+    #[error(transparent)]
+    TomlSerialize(#[from] toml::ser::Error),
+    #[error(transparent)]
+    Crypto(#[from] CryptoError),
+    #[error("User already exist: [{0}]")]
+    UserExist(String),
+    #[error("User not exist: [{0}]")]
+    UserNotExist(String),
+    #[error("Invalid username: [{0}]")]
+    InvalidUsername(String),
+}
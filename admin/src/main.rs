@@ -0,0 +1,77 @@
+use crate::command::{Command, CommandArgs, UserCommand};
+use crate::error::Error;
+use crate::user::{AgentUserInfo, ProxyUserInfo, UserAdmin};
+use clap::Parser;
+mod command;
+mod error;
+mod user;
+
+/// Manage the users of the proxy and the agent
+fn main() -> Result<(), Error> {
+    let command_args = CommandArgs::parse();
+    let user_admin = UserAdmin::new(
+        command_args.proxy_user_repo_directory,
+        command_args.agent_user_repo_directory,
+    );
+    match command_args.command {
+        Command::User(UserCommand::Add {
+            username,
+            proxy_servers,
+            expired_time,
+            upload_rate_limit,
+            download_rate_limit,
+            monthly_traffic_quota,
+            max_sessions,
+            key_size,
+        }) => {
+            let proxy_user_info = ProxyUserInfo {
+                username: username.clone(),
+                expired_time,
+                upload_rate_limit,
+                download_rate_limit,
+                monthly_traffic_quota,
+                max_sessions,
+            };
+            let agent_user_info = AgentUserInfo {
+                username: username.clone(),
+                proxy_servers,
+            };
+            user_admin.add(&proxy_user_info, &agent_user_info, key_size)?;
+            println!("User [{username}] added.");
+        }
+        Command::User(UserCommand::Remove { username }) => {
+            user_admin.remove(&username)?;
+            println!("User [{username}] removed.");
+        }
+        Command::User(UserCommand::List) => {
+            for user in user_admin.list()? {
+                let expired_time = user
+                    .expired_time
+                    .map(|expired_time| expired_time.to_rfc3339())
+                    .unwrap_or_else(|| "never".to_owned());
+                println!(
+                    "{}\texpired_time: {expired_time}\tproxy: {}\tagent: {}",
+                    user.username, user.on_proxy, user.on_agent
+                );
+            }
+        }
+        Command::User(UserCommand::RotateKeys {
+            username,
+            key_size,
+            previous_keys_valid_sec,
+        }) => {
+            user_admin.rotate_keys(&username, key_size, previous_keys_valid_sec)?;
+            println!(
+                "Keys of user [{username}] rotated, export the agent bundle again for the agent."
+            );
+        }
+        Command::User(UserCommand::ExportAgentBundle {
+            username,
+            output_directory,
+        }) => {
+            let bundle_directory = user_admin.export_agent_bundle(&username, &output_directory)?;
+            println!("Agent bundle of user [{username}] exported into {bundle_directory:?}.");
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,537 @@
+use crate::error::Error;
+use chrono::{DateTime, TimeDelta, Utc};
+use crypto::{
+    DEFAULT_AGENT_PRIVATE_KEY_PATH, DEFAULT_AGENT_PUBLIC_KEY_PATH, DEFAULT_PROXY_PRIVATE_KEY_PATH,
+    DEFAULT_PROXY_PUBLIC_KEY_PATH, EncodePrivateKey, EncodePublicKey, Error as CryptoError,
+    LineEnding, OsRng, RsaCrypto, RsaPrivateKey,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+const USER_INFO_FILE_NAME: &str = "user_info.toml";
+/// The directory in the proxy user directory holds the rotated keys
+const USER_RSA_KEYS_DIRECTORY_NAME: &str = "keys";
+const USER_RSA_KEY_INFO_FILE_NAME: &str = "key_info.toml";
+/// The user info in the proxy user repository
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(crate) struct ProxyUserInfo {
+    pub username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expired_time: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload_rate_limit: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download_rate_limit: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub monthly_traffic_quota: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_sessions: Option<usize>,
+}
+/// The user info in the agent user repository
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(crate) struct AgentUserInfo {
+    pub username: String,
+    pub proxy_servers: Vec<SocketAddr>,
+}
+/// The validity window of the rotated key on the proxy side
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct RsaKeyInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    not_before: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    not_after: Option<DateTime<Utc>>,
+}
+/// The user found in the user repositories
+#[derive(Debug)]
+pub(crate) struct UserSummary {
+    pub username: String,
+    pub expired_time: Option<DateTime<Utc>>,
+    pub on_proxy: bool,
+    pub on_agent: bool,
+}
+/// The rsa key pair in PEM
+struct RsaKeyPair {
+    public_key_pem: String,
+    private_key_pem: String,
+}
+impl RsaKeyPair {
+    fn generate(key_size: usize) -> Result<Self, Error> {
+        let private_key = RsaPrivateKey::new(&mut OsRng, key_size).map_err(CryptoError::from)?;
+        let public_key_pem = private_key
+            .to_public_key()
+            .to_public_key_pem(LineEnding::LF)
+            .map_err(CryptoError::from)?;
+        let private_key_pem = private_key
+            .to_pkcs8_pem(LineEnding::LF)
+            .map_err(CryptoError::from)?
+            .to_string();
+        Ok(Self {
+            public_key_pem,
+            private_key_pem,
+        })
+    }
+}
+/// The keys of the user, the agent encrypts with the public key of
+/// the proxy and the proxy encrypts with the public key of the agent.
+struct UserKeys {
+    agent: RsaKeyPair,
+    proxy: RsaKeyPair,
+}
+impl UserKeys {
+    fn generate(key_size: usize) -> Result<Self, Error> {
+        let user_keys = Self {
+            agent: RsaKeyPair::generate(key_size)?,
+            proxy: RsaKeyPair::generate(key_size)?,
+        };
+        // Make sure both sides can load the keys
+        RsaCrypto::new(
+            user_keys.agent.public_key_pem.as_bytes(),
+            user_keys.proxy.private_key_pem.as_bytes(),
+        )?;
+        RsaCrypto::new(
+            user_keys.proxy.public_key_pem.as_bytes(),
+            user_keys.agent.private_key_pem.as_bytes(),
+        )?;
+        Ok(user_keys)
+    }
+    /// The key files in the proxy user directory
+    fn proxy_files(&self) -> [UserFile<'_>; 2] {
+        [
+            UserFile::public(DEFAULT_AGENT_PUBLIC_KEY_PATH, &self.agent.public_key_pem),
+            UserFile::private(DEFAULT_PROXY_PRIVATE_KEY_PATH, &self.proxy.private_key_pem),
+        ]
+    }
+    /// The key files in the agent user directory
+    fn agent_files(&self) -> [UserFile<'_>; 2] {
+        [
+            UserFile::private(DEFAULT_AGENT_PRIVATE_KEY_PATH, &self.agent.private_key_pem),
+            UserFile::public(DEFAULT_PROXY_PUBLIC_KEY_PATH, &self.proxy.public_key_pem),
+        ]
+    }
+}
+/// The file in the user directory
+struct UserFile<'a> {
+    file_name: &'a str,
+    content: &'a str,
+    /// Only the owner can read the private file
+    private: bool,
+}
+impl<'a> UserFile<'a> {
+    fn public(file_name: &'a str, content: &'a str) -> Self {
+        Self {
+            file_name,
+            content,
+            private: false,
+        }
+    }
+    fn private(file_name: &'a str, content: &'a str) -> Self {
+        Self {
+            file_name,
+            content,
+            private: true,
+        }
+    }
+    fn write(&self, directory: &Path) -> Result<(), Error> {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        if self.private {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options
+            .open(directory.join(self.file_name))?
+            .write_all(self.content.as_bytes())?;
+        Ok(())
+    }
+    /// Write into a hidden file then rename, so the user
+    /// repository never reads the half-written file.
+    fn replace(&self, directory: &Path) -> Result<(), Error> {
+        let hidden_file = UserFile {
+            file_name: &format!(".{}.tmp", self.file_name),
+            ..*self
+        };
+        hidden_file.write(directory)?;
+        std::fs::rename(
+            directory.join(hidden_file.file_name),
+            directory.join(self.file_name),
+        )?;
+        Ok(())
+    }
+}
+/// Write the files into a hidden directory then rename, so the user
+/// repository only see the user directory with all the files.
+fn create_user_directory(user_directory: &Path, files: &[UserFile]) -> Result<(), Error> {
+    let hidden_directory = user_directory.with_file_name(format!(
+        ".{}.tmp",
+        user_directory
+            .file_name()
+            .and_then(|file_name| file_name.to_str())
+            .unwrap_or_default()
+    ));
+    if hidden_directory.exists() {
+        std::fs::remove_dir_all(&hidden_directory)?;
+    }
+    std::fs::create_dir_all(&hidden_directory)?;
+    for file in files {
+        file.write(&hidden_directory)?;
+    }
+    std::fs::rename(&hidden_directory, user_directory)?;
+    Ok(())
+}
+/// The usernames in the user repository directory, also
+/// used to list the key ids in the keys directory.
+fn list_usernames(user_repo_directory: &Path) -> Result<Vec<String>, Error> {
+    let user_repo_directory = match std::fs::read_dir(user_repo_directory) {
+        Ok(user_repo_directory) => user_repo_directory,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut usernames = Vec::new();
+    for sub_entry in user_repo_directory {
+        let sub_entry = sub_entry?;
+        if !sub_entry.file_type()?.is_dir() {
+            continue;
+        }
+        if let Some(username) = sub_entry.file_name().to_str()
+            && !username.starts_with('.')
+        {
+            usernames.push(username.to_owned());
+        }
+    }
+    Ok(usernames)
+}
+/// Remove the rotated keys expired before the time, the
+/// keys without a valid key info are kept.
+fn remove_expired_keys(keys_directory: &Path, time: &DateTime<Utc>) -> Result<(), Error> {
+    for key_id in list_usernames(keys_directory)? {
+        let key_directory = keys_directory.join(key_id);
+        let Ok(key_info) =
+            std::fs::read_to_string(key_directory.join(USER_RSA_KEY_INFO_FILE_NAME))
+        else {
+            continue;
+        };
+        if let Ok(RsaKeyInfo {
+            not_after: Some(not_after),
+            ..
+        }) = toml::from_str(&key_info)
+            && not_after <= *time
+        {
+            std::fs::remove_dir_all(key_directory)?;
+        }
+    }
+    Ok(())
+}
+/// The id of the key rotated at the time, the suffix keeps the
+/// keys rotated at the same time apart.
+fn rotated_key_id(keys_directory: &Path, time: &DateTime<Utc>) -> String {
+    let timestamp = time.format("%Y%m%d%H%M%S%6f").to_string();
+    let mut key_id = timestamp.clone();
+    let mut suffix = 0;
+    while keys_directory.join(&key_id).exists() {
+        suffix += 1;
+        key_id = format!("{timestamp}-{suffix}");
+    }
+    key_id
+}
+/// Manage the users in the proxy and the agent user repository,
+/// the user directory is named by the username.
+pub(crate) struct UserAdmin {
+    proxy_user_repo_directory: PathBuf,
+    agent_user_repo_directory: PathBuf,
+}
+impl UserAdmin {
+    pub fn new(proxy_user_repo_directory: PathBuf, agent_user_repo_directory: PathBuf) -> Self {
+        Self {
+            proxy_user_repo_directory,
+            agent_user_repo_directory,
+        }
+    }
+    /// The username is used as the directory name
+    fn check_username(username: &str) -> Result<(), Error> {
+        if username.is_empty()
+            || username.starts_with('.')
+            || username.contains(['/', '\\'])
+        {
+            return Err(Error::InvalidUsername(username.to_owned()));
+        }
+        Ok(())
+    }
+    fn proxy_user_directory(&self, username: &str) -> Result<PathBuf, Error> {
+        Self::check_username(username)?;
+        Ok(self.proxy_user_repo_directory.join(username))
+    }
+    fn agent_user_directory(&self, username: &str) -> Result<PathBuf, Error> {
+        Self::check_username(username)?;
+        Ok(self.agent_user_repo_directory.join(username))
+    }
+    /// Create the user with new keys on both sides
+    pub fn add(
+        &self,
+        proxy_user_info: &ProxyUserInfo,
+        agent_user_info: &AgentUserInfo,
+        key_size: usize,
+    ) -> Result<(), Error> {
+        let proxy_user_directory = self.proxy_user_directory(&proxy_user_info.username)?;
+        let agent_user_directory = self.agent_user_directory(&agent_user_info.username)?;
+        if proxy_user_directory.exists() || agent_user_directory.exists() {
+            return Err(Error::UserExist(proxy_user_info.username.clone()));
+        }
+        let user_keys = UserKeys::generate(key_size)?;
+        let proxy_user_info = toml::to_string(proxy_user_info)?;
+        let agent_user_info = toml::to_string(agent_user_info)?;
+        let [agent_public_key, proxy_private_key] = user_keys.proxy_files();
+        create_user_directory(
+            &proxy_user_directory,
+            &[
+                UserFile::public(USER_INFO_FILE_NAME, &proxy_user_info),
+                agent_public_key,
+                proxy_private_key,
+            ],
+        )?;
+        let [agent_private_key, proxy_public_key] = user_keys.agent_files();
+        create_user_directory(
+            &agent_user_directory,
+            &[
+                UserFile::public(USER_INFO_FILE_NAME, &agent_user_info),
+                agent_private_key,
+                proxy_public_key,
+            ],
+        )
+        .inspect_err(|_| {
+            // The user only on the proxy side can not be added again
+            let _ = std::fs::remove_dir_all(&proxy_user_directory);
+        })
+    }
+    /// Remove the user from both sides
+    pub fn remove(&self, username: &str) -> Result<(), Error> {
+        let mut removed = false;
+        for user_directory in [
+            self.proxy_user_directory(username)?,
+            self.agent_user_directory(username)?,
+        ] {
+            if user_directory.exists() {
+                std::fs::remove_dir_all(user_directory)?;
+                removed = true;
+            }
+        }
+        if !removed {
+            return Err(Error::UserNotExist(username.to_owned()));
+        }
+        Ok(())
+    }
+    /// List the users on both sides
+    pub fn list(&self) -> Result<Vec<UserSummary>, Error> {
+        let mut users = BTreeMap::new();
+        for username in list_usernames(&self.proxy_user_repo_directory)? {
+            let proxy_user_info_content = std::fs::read_to_string(
+                self.proxy_user_repo_directory
+                    .join(&username)
+                    .join(USER_INFO_FILE_NAME),
+            );
+            let expired_time = proxy_user_info_content
+                .ok()
+                .and_then(|content| toml::from_str::<ProxyUserInfo>(&content).ok())
+                .and_then(|proxy_user_info| proxy_user_info.expired_time);
+            users.insert(
+                username.clone(),
+                UserSummary {
+                    username,
+                    expired_time,
+                    on_proxy: true,
+                    on_agent: false,
+                },
+            );
+        }
+        for username in list_usernames(&self.agent_user_repo_directory)? {
+            users
+                .entry(username.clone())
+                .or_insert_with(|| UserSummary {
+                    username,
+                    expired_time: None,
+                    on_proxy: false,
+                    on_agent: false,
+                })
+                .on_agent = true;
+        }
+        Ok(users.into_values().collect())
+    }
+    /// Replace the keys of the user on both sides, the user info is kept. The
+    /// previous keys on the proxy side are moved into the keys directory and
+    /// stay valid for a while, the expired ones there are removed.
+    pub fn rotate_keys(
+        &self,
+        username: &str,
+        key_size: usize,
+        previous_keys_valid_sec: u32,
+    ) -> Result<(), Error> {
+        let proxy_user_directory = self.proxy_user_directory(username)?;
+        let agent_user_directory = self.agent_user_directory(username)?;
+        if !proxy_user_directory.exists() || !agent_user_directory.exists() {
+            return Err(Error::UserNotExist(username.to_owned()));
+        }
+        let user_keys = UserKeys::generate(key_size)?;
+        let now = Utc::now();
+        let keys_directory = proxy_user_directory.join(USER_RSA_KEYS_DIRECTORY_NAME);
+        remove_expired_keys(&keys_directory, &now)?;
+        if previous_keys_valid_sec > 0 {
+            let key_info = toml::to_string(&RsaKeyInfo {
+                not_before: None,
+                not_after: Some(now + TimeDelta::seconds(previous_keys_valid_sec.into())),
+            })?;
+            let agent_public_key =
+                std::fs::read_to_string(proxy_user_directory.join(DEFAULT_AGENT_PUBLIC_KEY_PATH))?;
+            let proxy_private_key =
+                std::fs::read_to_string(proxy_user_directory.join(DEFAULT_PROXY_PRIVATE_KEY_PATH))?;
+            std::fs::create_dir_all(&keys_directory)?;
+            create_user_directory(
+                &keys_directory.join(rotated_key_id(&keys_directory, &now)),
+                &[
+                    UserFile::public(USER_RSA_KEY_INFO_FILE_NAME, &key_info),
+                    UserFile::public(DEFAULT_AGENT_PUBLIC_KEY_PATH, &agent_public_key),
+                    UserFile::private(DEFAULT_PROXY_PRIVATE_KEY_PATH, &proxy_private_key),
+                ],
+            )?;
+        }
+        for file in user_keys.proxy_files() {
+            file.replace(&proxy_user_directory)?;
+        }
+        // The validity window of the previous keys does not apply to the new keys
+        match std::fs::remove_file(proxy_user_directory.join(USER_RSA_KEY_INFO_FILE_NAME)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        for file in user_keys.agent_files() {
+            file.replace(&agent_user_directory)?;
+        }
+        Ok(())
+    }
+    /// Copy the agent side of the user into the output directory,
+    /// return the directory of the exported user.
+    pub fn export_agent_bundle(
+        &self,
+        username: &str,
+        output_directory: &Path,
+    ) -> Result<PathBuf, Error> {
+        let agent_user_directory = self.agent_user_directory(username)?;
+        if !agent_user_directory.exists() {
+            return Err(Error::UserNotExist(username.to_owned()));
+        }
+        let bundle_directory = output_directory.join(username);
+        if bundle_directory.exists() {
+            return Err(Error::UserExist(username.to_owned()));
+        }
+        let user_info = std::fs::read_to_string(agent_user_directory.join(USER_INFO_FILE_NAME))?;
+        let agent_private_key =
+            std::fs::read_to_string(agent_user_directory.join(DEFAULT_AGENT_PRIVATE_KEY_PATH))?;
+        let proxy_public_key =
+            std::fs::read_to_string(agent_user_directory.join(DEFAULT_PROXY_PUBLIC_KEY_PATH))?;
+        std::fs::create_dir_all(output_directory)?;
+        create_user_directory(
+            &bundle_directory,
+            &[
+                UserFile::public(USER_INFO_FILE_NAME, &user_info),
+                UserFile::private(DEFAULT_AGENT_PRIVATE_KEY_PATH, &agent_private_key),
+                UserFile::public(DEFAULT_PROXY_PUBLIC_KEY_PATH, &proxy_public_key),
+            ],
+        )?;
+        Ok(bundle_directory)
+    }
+}
+#[test]
+fn test() {
+    let test_directory =
+        std::env::temp_dir().join(format!("ppaass-admin-{}", std::process::id()));
+    let user_admin = UserAdmin::new(test_directory.join("proxy"), test_directory.join("agent"));
+    let proxy_user_info = ProxyUserInfo {
+        username: "user1".to_owned(),
+        expired_time: Some("2030-01-01T00:00:00Z".parse().unwrap()),
+        ..Default::default()
+    };
+    let agent_user_info = AgentUserInfo {
+        username: "user1".to_owned(),
+        proxy_servers: vec!["127.0.0.1:80".parse().unwrap()],
+    };
+    user_admin.add(&proxy_user_info, &agent_user_info, 1024).unwrap();
+    assert!(matches!(
+        user_admin.add(&proxy_user_info, &agent_user_info, 1024),
+        Err(Error::UserExist(_))
+    ));
+    assert!(matches!(
+        user_admin.remove("../user1"),
+        Err(Error::InvalidUsername(_))
+    ));
+    let users = user_admin.list().unwrap();
+    assert_eq!(users.len(), 1);
+    assert_eq!(users[0].expired_time, proxy_user_info.expired_time);
+    assert!(users[0].on_proxy && users[0].on_agent);
+    // The keys of both sides still pair after rotation
+    let agent_public_key_path = test_directory
+        .join("proxy/user1")
+        .join(DEFAULT_AGENT_PUBLIC_KEY_PATH);
+    let agent_public_key = std::fs::read_to_string(&agent_public_key_path).unwrap();
+    user_admin.rotate_keys("user1", 1024, 60).unwrap();
+    let rotated_agent_public_key = std::fs::read_to_string(&agent_public_key_path).unwrap();
+    assert_ne!(rotated_agent_public_key, agent_public_key);
+    // The keys rotated again at once are kept apart
+    user_admin.rotate_keys("user1", 1024, 60).unwrap();
+    assert_ne!(
+        std::fs::read_to_string(&agent_public_key_path).unwrap(),
+        rotated_agent_public_key
+    );
+    // The previous keys are kept on the proxy side
+    let keys_directory = test_directory
+        .join("proxy/user1")
+        .join(USER_RSA_KEYS_DIRECTORY_NAME);
+    let mut previous_key_ids = list_usernames(&keys_directory).unwrap();
+    previous_key_ids.sort();
+    assert_eq!(previous_key_ids.len(), 2);
+    let previous_key_directory = keys_directory.join(&previous_key_ids[0]);
+    assert_eq!(
+        std::fs::read_to_string(previous_key_directory.join(DEFAULT_AGENT_PUBLIC_KEY_PATH))
+            .unwrap(),
+        agent_public_key
+    );
+    let now = Utc::now();
+    let taken_key_directory = keys_directory.join(rotated_key_id(&keys_directory, &now));
+    std::fs::create_dir(&taken_key_directory).unwrap();
+    assert_ne!(
+        keys_directory.join(rotated_key_id(&keys_directory, &now)),
+        taken_key_directory
+    );
+    std::fs::remove_dir(&taken_key_directory).unwrap();
+    remove_expired_keys(&keys_directory, &Utc::now()).unwrap();
+    assert!(previous_key_directory.exists());
+    remove_expired_keys(&keys_directory, &(Utc::now() + TimeDelta::seconds(61))).unwrap();
+    assert!(!previous_key_directory.exists());
+    let proxy_rsa_crypto = RsaCrypto::new(
+        std::fs::File::open(&agent_public_key_path).unwrap(),
+        std::fs::File::open(test_directory.join("proxy/user1").join(DEFAULT_PROXY_PRIVATE_KEY_PATH))
+            .unwrap(),
+    )
+    .unwrap();
+    let bundle_directory = user_admin
+        .export_agent_bundle("user1", &test_directory.join("bundle"))
+        .unwrap();
+    let agent_rsa_crypto = RsaCrypto::new(
+        std::fs::File::open(bundle_directory.join(DEFAULT_PROXY_PUBLIC_KEY_PATH)).unwrap(),
+        std::fs::File::open(bundle_directory.join(DEFAULT_AGENT_PRIVATE_KEY_PATH)).unwrap(),
+    )
+    .unwrap();
+    let signature = agent_rsa_crypto.sign(b"ppaass").unwrap();
+    proxy_rsa_crypto.verify(b"ppaass", &signature).unwrap();
+    let encrypted = agent_rsa_crypto.encrypt(b"ppaass").unwrap();
+    assert_eq!(proxy_rsa_crypto.decrypt(&encrypted).unwrap(), b"ppaass");
+    user_admin.remove("user1").unwrap();
+    assert!(user_admin.list().unwrap().is_empty());
+    // The proxy side is removed when the agent side fail to write
+    std::fs::write(test_directory.join("broken_agent"), "").unwrap();
+    let broken_user_admin =
+        UserAdmin::new(test_directory.join("proxy"), test_directory.join("broken_agent"));
+    assert!(broken_user_admin.add(&proxy_user_info, &agent_user_info, 1024).is_err());
+    assert!(!test_directory.join("proxy/user1").exists());
+    std::fs::remove_dir_all(&test_directory).unwrap();
+}
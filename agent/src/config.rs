@@ -1,4 +1,6 @@
 use crate::command::CommandArgs;
+use crate::selector::ProxyServerStrategy;
+use common::config::WithUsernameConfig;
 use clap::Parser;
 use common_macro::{
     FileSystemUserRepoConfig, LogConfig, ServerConfig, UserRepositoryConfig,
@@ -6,11 +8,13 @@ use common_macro::{
 };
 use core::panic;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs::read_to_string;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::OnceLock;
+use subtle::ConstantTimeEq;
 /// The default configuration file patch
 const DEFAULT_CONFIG_FILE: &str = "./resources/agent.toml";
 /// The global configuration object
@@ -39,6 +43,27 @@ pub fn get_config() -> &'static Config {
         config
     })
 }
+/// The user authenticated by agent, each local user can
+/// use a different agent user to connect the proxy.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LocalUser {
+    username: String,
+    password: String,
+    /// The agent user used to connect the proxy, the
+    /// `username` of agent configuration is used by default.
+    #[serde(default)]
+    agent_username: Option<String>,
+}
+impl LocalUser {
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+    pub fn agent_username<'a>(&'a self, config: &'a Config) -> &'a str {
+        self.agent_username
+            .as_deref()
+            .unwrap_or_else(|| config.username())
+    }
+}
 /// The configuration object
 #[derive(
     Serialize,
@@ -63,6 +88,36 @@ pub struct Config {
     max_log_level: String,
     #[serde(default = "default_proxy_connect_timeout")]
     proxy_connect_timeout: u64,
+    #[serde(default = "default_proxy_multiplexing")]
+    proxy_multiplexing: bool,
+    #[serde(default = "default_proxy_pool_idle_ttl")]
+    proxy_pool_idle_ttl: u64,
+    #[serde(default = "default_proxy_pool_max_size")]
+    proxy_pool_max_size: usize,
+    #[serde(default = "default_proxy_pool_min_idle")]
+    proxy_pool_min_idle: usize,
+    #[serde(default = "default_proxy_server_eject_duration")]
+    proxy_server_eject_duration: u64,
+    #[serde(default = "default_proxy_server_probe_interval")]
+    proxy_server_probe_interval: u64,
+    #[serde(default)]
+    proxy_server_strategy: ProxyServerStrategy,
+    #[serde(default)]
+    proxy_server_groups: HashMap<String, Vec<SocketAddr>>,
+    #[serde(default = "default_direct_connect_timeout")]
+    direct_connect_timeout: u64,
+    #[serde(default = "default_udp_idle_timeout")]
+    udp_idle_timeout: u64,
+    /// The max concurrent connections from one client ip,
+    /// no limit when not configured.
+    #[serde(default)]
+    client_max_connections_per_ip: Option<usize>,
+    #[serde(default)]
+    route_rules_file: Option<PathBuf>,
+    #[serde(default = "default_route_rules_refresh_interval")]
+    route_rules_refresh_interval: u64,
+    #[serde(default)]
+    local_users: Vec<LocalUser>,
     #[serde(default = "default_user_info_file_name")]
     user_info_file_name: String,
     #[serde(default = "default_user_info_private_key_file_name")]
@@ -82,6 +137,61 @@ impl Config {
     pub fn proxy_connect_timeout(&self) -> u64 {
         self.proxy_connect_timeout
     }
+    pub fn proxy_multiplexing(&self) -> bool {
+        self.proxy_multiplexing
+    }
+    pub fn proxy_pool_idle_ttl(&self) -> u64 {
+        self.proxy_pool_idle_ttl
+    }
+    pub fn proxy_pool_max_size(&self) -> usize {
+        self.proxy_pool_max_size
+    }
+    pub fn proxy_pool_min_idle(&self) -> usize {
+        self.proxy_pool_min_idle
+    }
+    pub fn proxy_server_eject_duration(&self) -> u64 {
+        self.proxy_server_eject_duration
+    }
+    pub fn proxy_server_probe_interval(&self) -> u64 {
+        self.proxy_server_probe_interval
+    }
+    pub fn proxy_server_strategy(&self) -> ProxyServerStrategy {
+        self.proxy_server_strategy
+    }
+    pub fn proxy_server_groups(&self) -> &HashMap<String, Vec<SocketAddr>> {
+        &self.proxy_server_groups
+    }
+    pub fn direct_connect_timeout(&self) -> u64 {
+        self.direct_connect_timeout
+    }
+    pub fn udp_idle_timeout(&self) -> u64 {
+        self.udp_idle_timeout
+    }
+    pub fn client_max_connections_per_ip(&self) -> Option<usize> {
+        self.client_max_connections_per_ip
+    }
+    pub fn route_rules_file(&self) -> Option<&Path> {
+        self.route_rules_file.as_deref()
+    }
+    pub fn route_rules_refresh_interval(&self) -> u64 {
+        self.route_rules_refresh_interval
+    }
+    /// The users allowed to use the agent, the client must
+    /// authenticate when there is any local user configured.
+    pub fn local_users(&self) -> &[LocalUser] {
+        &self.local_users
+    }
+    /// Find the local user with the credential from client, all the users
+    /// are compared in constant time so the timing leaks nothing.
+    pub fn authenticate_local_user(&self, username: &str, password: &str) -> Option<&LocalUser> {
+        self.local_users
+            .iter()
+            .fold(None, |authenticated, local_user| {
+                let matched = local_user.username.as_bytes().ct_eq(username.as_bytes())
+                    & local_user.password.as_bytes().ct_eq(password.as_bytes());
+                authenticated.or(bool::from(matched).then_some(local_user))
+            })
+    }
     pub fn merge_command_args(&mut self, command: CommandArgs) {
         if let Some(listening_address) = command.listening_address {
             self.listening_address = listening_address;
@@ -155,6 +265,51 @@ fn default_username() -> String {
 fn default_proxy_connect_timeout() -> u64 {
     10
 }
+/// By default the client connections share one
+/// multiplexed session with the proxy.
+fn default_proxy_multiplexing() -> bool {
+    true
+}
+/// The default seconds an idle proxy connection
+/// can stay in the pool.
+fn default_proxy_pool_idle_ttl() -> u64 {
+    60
+}
+/// The default max idle proxy connections in the pool,
+/// set to 0 will disable the pool.
+fn default_proxy_pool_max_size() -> usize {
+    16
+}
+/// The default min idle proxy connections the pool
+/// try to keep.
+fn default_proxy_pool_min_idle() -> usize {
+    4
+}
+/// The default seconds a failed proxy server
+/// will not be selected.
+fn default_proxy_server_eject_duration() -> u64 {
+    30
+}
+/// The default seconds between two rounds of proxy
+/// server probing, set to 0 will disable the probing.
+fn default_proxy_server_probe_interval() -> u64 {
+    15
+}
+/// The default timeout in seconds to connect
+/// the destination directly.
+fn default_direct_connect_timeout() -> u64 {
+    10
+}
+/// The default seconds to close the udp association
+/// when no datagram in both directions.
+fn default_udp_idle_timeout() -> u64 {
+    120
+}
+/// The default seconds between two checks
+/// of the route rules file.
+fn default_route_rules_refresh_interval() -> u64 {
+    5
+}
 /// The default max client connection number
 /// If the incoming client connection exceed this
 /// number, the client will waiting until there
@@ -1,6 +1,8 @@
 use common::Error as CommonError;
 use fast_socks5::server::SocksServerError;
 use hyper::Uri;
+use protocol::UnifiedAddress;
+use std::net::IpAddr;
 use thiserror::Error;
 #[derive(Error, Debug)]
 pub enum Error {
@@ -14,4 +16,22 @@ pub enum Error {
     FastSocks(#[from] SocksServerError),
     #[error("No destination host: {0}")]
     NoDestinationHost(Uri),
+    #[error("No proxy server available")]
+    NoProxyServer,
+    #[error("Proxy server group not exist: [{0}]")]
+    ProxyServerGroupNotExist(String),
+    #[error("Destination rejected by route rules: [{0}]")]
+    RouteRejected(UnifiedAddress),
+    #[error("Connect to destination directly timeout in {0} seconds.")]
+    DirectConnectTimeout(u64),
+    #[error("Udp association idle for {0} seconds.")]
+    UdpIdleTimeout(u64),
+    #[error("Bind can not route directly: [{0}]")]
+    DirectBindNotSupported(UnifiedAddress),
+    #[error("Socks 4 command not supported: {0}")]
+    Socks4CommandNotSupported(u8),
+    #[error("Socks 4 client rejected because authentication is required")]
+    Socks4AuthenticationRequired,
+    #[error("Client [{0}] reach the max connections: {1}")]
+    ClientConnectionsExceeded(IpAddr, usize),
 }
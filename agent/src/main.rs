@@ -1,10 +1,16 @@
 mod command;
 mod config;
 mod error;
+mod pool;
+mod route;
+mod selector;
 mod tunnel;
 mod user;
 use crate::config::get_config;
 use crate::error::Error;
+use crate::pool::get_proxy_connection_pool;
+use crate::route::get_router;
+use crate::selector::get_proxy_server_selector;
 use common::{ServerState, build_server_runtime, init_log, start_server};
 use tokio::signal;
 use tracing::{debug, error, info};
@@ -17,6 +23,11 @@ fn main() -> Result<(), Error> {
     let _log_guard = init_log(get_config())?;
     let server_runtime = build_server_runtime(get_config())?;
     server_runtime.block_on(async move {
+        // Start probing the proxy servers and warm up the
+        // proxy connections before accept client
+        get_proxy_server_selector();
+        get_proxy_connection_pool();
+        get_router();
         let server_guard = start_server(get_config(), handle_connection);
         if let Err(e) = signal::ctrl_c().await {
             error!("Error happen when listening stop signal: {}", e);
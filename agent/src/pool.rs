@@ -0,0 +1,291 @@
+use crate::config::{Config, get_config};
+use crate::error::Error;
+use crate::selector::{SelectedProxyServer, get_proxy_server_selector};
+use crate::user::get_agent_user_repo;
+use common::Error as CommonError;
+use common::config::WithUsernameConfig;
+use common::proxy::{Init, ProxyConnection, ProxyFramed};
+use common::user::{UserRepository, UserWithProxyServers};
+use protocol::HandshakeRejection;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::time::sleep;
+use tracing::{debug, error, warn};
+/// The interval to check the idle connections in the pool
+const POOL_MAINTAIN_INTERVAL: Duration = Duration::from_secs(5);
+/// The longest wait before filling the pool again when the
+/// proxy refuse more sessions of the user
+const POOL_MAX_BACKOFF: Duration = Duration::from_secs(300);
+static PROXY_CONNECTION_POOL: OnceLock<ProxyConnectionPool> = OnceLock::new();
+/// Get the proxy connection pool, the pool start
+/// to fill itself when it is created.
+pub fn get_proxy_connection_pool() -> &'static ProxyConnectionPool {
+    PROXY_CONNECTION_POOL.get_or_init(|| ProxyConnectionPool::new(get_config()))
+}
+/// The connection can be kept in the pool
+trait PooledConnection {
+    /// The connection is not closed by the peer
+    fn is_alive(&self) -> bool;
+}
+impl PooledConnection for ProxyConnection<ProxyFramed> {
+    fn is_alive(&self) -> bool {
+        ProxyConnection::is_alive(self)
+    }
+}
+/// The idle connection in the pool, the proxy server is not
+/// counted as active until the connection is handed out.
+struct IdleConnection<C> {
+    connection: C,
+    proxy_server: SelectedProxyServer,
+    idle_since: Instant,
+}
+type IdleProxyConnection = IdleConnection<ProxyConnection<ProxyFramed>>;
+/// The pool of proxy connections which complete the handshake
+/// already, so that the client only wait for the destination setup.
+pub struct ProxyConnectionPool {
+    idle_connections: Arc<Mutex<VecDeque<IdleProxyConnection>>>,
+    /// Wake up the maintain task to fill the pool
+    fill_signal: Arc<Notify>,
+    idle_ttl: Duration,
+}
+impl ProxyConnectionPool {
+    fn new(config: &'static Config) -> Self {
+        let idle_connections = Arc::new(Mutex::new(VecDeque::new()));
+        let fill_signal = Arc::new(Notify::new());
+        let idle_ttl = Duration::from_secs(config.proxy_pool_idle_ttl());
+        if config.proxy_pool_max_size() > 0 {
+            tokio::spawn(Self::maintain(
+                config,
+                idle_connections.clone(),
+                fill_signal.clone(),
+            ));
+        }
+        Self {
+            idle_connections,
+            fill_signal,
+            idle_ttl,
+        }
+    }
+    /// Fetch a proxy connection from the pool, a new connection will
+    /// be created when there is no usable connection in the pool.
+    pub async fn fetch(
+        &self,
+    ) -> Result<(ProxyConnection<ProxyFramed>, SelectedProxyServer), Error> {
+        let idle_connection = {
+            let mut idle_connections = self
+                .idle_connections
+                .lock()
+                .map_err(|e| CommonError::Lock(format!("Fail to lock proxy connection pool: {e:?}")))?;
+            Self::pop_usable(&mut idle_connections, self.idle_ttl)
+        };
+        self.fill_signal.notify_one();
+        match idle_connection {
+            Some(idle_connection) => Ok(idle_connection),
+            None => create_proxy_connection(get_config().username(), None).await,
+        }
+    }
+    /// Take the oldest usable connection out of the pool
+    fn pop_usable<C: PooledConnection>(
+        idle_connections: &mut VecDeque<IdleConnection<C>>,
+        idle_ttl: Duration,
+    ) -> Option<(C, SelectedProxyServer)> {
+        Self::remove_unusable(idle_connections, idle_ttl);
+        idle_connections.pop_front().map(|idle_connection| {
+            (idle_connection.connection, idle_connection.proxy_server)
+        })
+    }
+    /// Remove the connections which expired or closed by proxy
+    fn remove_unusable<C: PooledConnection>(
+        idle_connections: &mut VecDeque<IdleConnection<C>>,
+        idle_ttl: Duration,
+    ) {
+        idle_connections.retain(|idle_connection| {
+            idle_connection.idle_since.elapsed() < idle_ttl
+                && idle_connection.connection.is_alive()
+        });
+    }
+    /// Keep the idle connections in the pool between the
+    /// minimum idle size and the maximum size.
+    async fn maintain(
+        config: &'static Config,
+        idle_connections: Arc<Mutex<VecDeque<IdleProxyConnection>>>,
+        fill_signal: Arc<Notify>,
+    ) {
+        let idle_ttl = Duration::from_secs(config.proxy_pool_idle_ttl());
+        let min_idle = config.proxy_pool_min_idle().min(config.proxy_pool_max_size());
+        // The pool stop filling for a while when the user reach the max
+        // sessions, the idle connections should not take all of them.
+        let mut backoff: Option<Duration> = None;
+        loop {
+            let idle_size = match idle_connections.lock() {
+                Ok(mut idle_connections) => {
+                    Self::remove_unusable(&mut idle_connections, idle_ttl);
+                    idle_connections.len()
+                }
+                Err(e) => {
+                    error!("Fail to lock proxy connection pool: {e:?}");
+                    return;
+                }
+            };
+            if idle_size >= min_idle {
+                backoff = None;
+            }
+            for _ in idle_size..min_idle {
+                let (proxy_connection, proxy_server) =
+                    match create_proxy_connection(config.username(), None).await {
+                    Ok(selected_proxy_connection) => selected_proxy_connection,
+                    Err(Error::Common(CommonError::HandshakeRejected(
+                        HandshakeRejection::TooManySessions { max_sessions },
+                    ))) => {
+                        let next_backoff = next_backoff(backoff);
+                        warn!("Stop filling proxy connection pool for {next_backoff:?}, the user reach the max sessions: {max_sessions}");
+                        backoff = Some(next_backoff);
+                        break;
+                    }
+                    Err(e) => {
+                        error!("Fail to create proxy connection for pool: {e:?}");
+                        break;
+                    }
+                };
+                backoff = None;
+                let Ok(mut idle_connections) = idle_connections.lock() else {
+                    return;
+                };
+                if idle_connections.len() >= config.proxy_pool_max_size() {
+                    break;
+                }
+                idle_connections.push_back(IdleConnection {
+                    connection: proxy_connection,
+                    proxy_server,
+                    idle_since: Instant::now(),
+                });
+            }
+            debug!("Proxy connection pool maintained, idle size before fill: {idle_size}");
+            if let Some(backoff) = backoff {
+                sleep(backoff).await;
+                continue;
+            }
+            tokio::select! {
+                _ = sleep(POOL_MAINTAIN_INTERVAL) => {}
+                _ = fill_signal.notified() => {}
+            }
+        }
+    }
+}
+/// Double the backoff of filling the pool until the max one
+fn next_backoff(backoff: Option<Duration>) -> Duration {
+    backoff.map_or(POOL_MAINTAIN_INTERVAL, |backoff| {
+        (backoff * 2).min(POOL_MAX_BACKOFF)
+    })
+}
+/// Create a proxy connection of the agent user, the returned proxy connection
+/// complete handshake already. The servers of the agent user are used unless
+/// the proxy server group is given. When the handshake fail, the next selected
+/// proxy server will be tried until all the servers are tried.
+pub async fn create_proxy_connection(
+    agent_username: &str,
+    proxy_server_group: Option<&str>,
+) -> Result<(ProxyConnection<ProxyFramed>, SelectedProxyServer), Error> {
+    let config = get_config();
+    let agent_user = get_agent_user_repo()
+        .find_user(agent_username)
+        .ok_or(CommonError::UserNotExist(agent_username.to_owned()))?;
+    let proxy_servers = match proxy_server_group {
+        None => agent_user.proxy_servers(),
+        Some(proxy_server_group) => config
+            .proxy_server_groups()
+            .get(proxy_server_group)
+            .ok_or(Error::ProxyServerGroupNotExist(proxy_server_group.to_owned()))?,
+    };
+    let mut last_error = Error::NoProxyServer;
+    for _ in 0..proxy_servers.len() {
+        let proxy_server = get_proxy_server_selector().select(proxy_servers)?;
+        let start = Instant::now();
+        match ProxyConnection::<Init>::new(
+            agent_user.clone(),
+            &[proxy_server.address()],
+            config.proxy_connect_timeout(),
+        )
+        .await
+        {
+            Ok(proxy_connection) => {
+                proxy_server.record_success(start.elapsed());
+                return Ok((proxy_connection, proxy_server));
+            }
+            Err(e) => {
+                error!(
+                    "Fail to create proxy connection with proxy server [{}]: {e:?}",
+                    proxy_server.address()
+                );
+                // The server is healthy when it reject the user
+                if !matches!(e, CommonError::HandshakeRejected(_)) {
+                    proxy_server.record_failure();
+                }
+                last_error = e.into();
+            }
+        }
+    }
+    Err(last_error)
+}
+#[test]
+fn test() {
+    use crate::selector::{ProxyServerSelector, ProxyServerStrategy};
+    use std::net::SocketAddr;
+    struct TestConnection {
+        id: usize,
+        alive: bool,
+    }
+    impl PooledConnection for TestConnection {
+        fn is_alive(&self) -> bool {
+            self.alive
+        }
+    }
+    let servers: Vec<SocketAddr> = ["127.0.0.1:1", "127.0.0.1:2"]
+        .iter()
+        .map(|address| address.parse().unwrap())
+        .collect();
+    let selector = ProxyServerSelector::with_strategy(
+        ProxyServerStrategy::LeastConnections,
+        Duration::from_secs(60),
+    );
+    let idle_ttl = Duration::from_secs(60);
+    let mut idle_connections = VecDeque::new();
+    // The expired one, the closed one and two usable ones
+    for (id, idle_since, alive) in [
+        (0, Instant::now() - idle_ttl * 2, true),
+        (1, Instant::now(), false),
+        (2, Instant::now(), true),
+        (3, Instant::now(), true),
+    ] {
+        idle_connections.push_back(IdleConnection {
+            connection: TestConnection { id, alive },
+            proxy_server: selector.select(&servers).unwrap(),
+            idle_since,
+        });
+    }
+    // The idle connections do not count as active connections
+    assert!(
+        idle_connections
+            .iter()
+            .all(|idle_connection| idle_connection.proxy_server.address() == servers[0])
+    );
+    let (connection, proxy_server) =
+        ProxyConnectionPool::pop_usable(&mut idle_connections, idle_ttl).unwrap();
+    assert_eq!(connection.id, 2);
+    assert_eq!(idle_connections.len(), 1);
+    // The handed out connection counts until the lease dropped
+    let server_lease = proxy_server.lease();
+    assert_eq!(selector.select(&servers).unwrap().address(), servers[1]);
+    drop(server_lease);
+    assert_eq!(selector.select(&servers).unwrap().address(), servers[0]);
+    let (connection, _) =
+        ProxyConnectionPool::pop_usable(&mut idle_connections, idle_ttl).unwrap();
+    assert_eq!(connection.id, 3);
+    assert!(ProxyConnectionPool::pop_usable(&mut idle_connections, idle_ttl).is_none());
+    // The pool wait longer each time the proxy refuse more sessions
+    assert_eq!(next_backoff(None), POOL_MAINTAIN_INTERVAL);
+    assert_eq!(next_backoff(Some(POOL_MAINTAIN_INTERVAL)), POOL_MAINTAIN_INTERVAL * 2);
+    assert_eq!(next_backoff(Some(POOL_MAX_BACKOFF)), POOL_MAX_BACKOFF);
+}
@@ -0,0 +1,150 @@
+use crate::config::{Config, get_config};
+use common::rule::AddressMatcher;
+use protocol::UnifiedAddress;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::time::sleep;
+use tracing::{debug, error, info};
+static ROUTER: OnceLock<Router> = OnceLock::new();
+/// Get the router, the rules file is reloaded
+/// in background when it changed.
+pub fn get_router() -> &'static Router {
+    ROUTER.get_or_init(|| Router::new(get_config()))
+}
+/// How to handle the traffic to the destination
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteAction {
+    /// Connect to the destination from agent directly
+    Direct,
+    /// Connect to the destination through proxy
+    #[default]
+    Proxy,
+    /// Refuse the client
+    Reject,
+}
+/// The decision made by the router
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Route {
+    Direct,
+    /// Through the proxy servers, the name of the proxy server
+    /// group is given when not use the servers of agent user
+    Proxy(Option<String>),
+    Reject,
+}
+/// The rule in the rules file
+#[derive(Serialize, Deserialize, Debug)]
+struct RouteRule {
+    #[serde(flatten)]
+    matcher: AddressMatcher,
+    action: RouteAction,
+    /// Only used by proxy action
+    #[serde(default)]
+    proxy_group: Option<String>,
+}
+/// The rules file, the rules are matched in order and
+/// the first matched one decide the route.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct RouteRules {
+    #[serde(default)]
+    rules: Vec<RouteRule>,
+    /// The action when no rule matched
+    #[serde(default)]
+    default_action: RouteAction,
+    #[serde(default)]
+    default_proxy_group: Option<String>,
+}
+impl RouteRules {
+    fn load(rules_file_path: &Path) -> Result<Self, String> {
+        let rules_file_content = std::fs::read_to_string(rules_file_path)
+            .map_err(|e| format!("Fail to read route rules file: {e:?}"))?;
+        toml::from_str::<RouteRules>(&rules_file_content)
+            .map_err(|e| format!("Fail to parse route rules file: {e}"))
+    }
+    fn route(&self, destination_address: &UnifiedAddress) -> Route {
+        let (action, proxy_group) = self
+            .rules
+            .iter()
+            .find(|rule| rule.matcher.matches(destination_address))
+            .map(|rule| (rule.action, &rule.proxy_group))
+            .unwrap_or((self.default_action, &self.default_proxy_group));
+        match action {
+            RouteAction::Direct => Route::Direct,
+            RouteAction::Proxy => Route::Proxy(proxy_group.clone()),
+            RouteAction::Reject => Route::Reject,
+        }
+    }
+}
+/// Decide the route of destination with the rules
+pub struct Router {
+    rules: Arc<RwLock<Arc<RouteRules>>>,
+}
+impl Router {
+    fn new(config: &'static Config) -> Self {
+        let mut initial_rules = RouteRules::default();
+        if let Some(rules_file_path) = config.route_rules_file() {
+            match RouteRules::load(rules_file_path) {
+                Ok(rules) => initial_rules = rules,
+                Err(e) => error!("All the destinations go through proxy. {e}"),
+            }
+        }
+        let rules = Arc::new(RwLock::new(Arc::new(initial_rules)));
+        if let Some(rules_file_path) = config.route_rules_file() {
+            tokio::spawn(Self::reload(
+                rules_file_path,
+                Duration::from_secs(config.route_rules_refresh_interval()),
+                rules.clone(),
+            ));
+        }
+        Self { rules }
+    }
+    /// Decide the route of the destination
+    pub fn route(&self, destination_address: &UnifiedAddress) -> Route {
+        let rules = match self.rules.read() {
+            Ok(rules) => rules.clone(),
+            Err(e) => {
+                error!("Fail to lock route rules: {e:?}");
+                return Route::Proxy(None);
+            }
+        };
+        let route = rules.route(destination_address);
+        debug!("Route destination [{destination_address}] to: {route:?}");
+        route
+    }
+    /// Reload the rules when the modified time of rules file changed,
+    /// the previous rules are kept when the new rules file is broken.
+    async fn reload(
+        rules_file_path: &'static Path,
+        refresh_interval: Duration,
+        rules: Arc<RwLock<Arc<RouteRules>>>,
+    ) {
+        let mut last_modified: Option<SystemTime> = std::fs::metadata(rules_file_path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        loop {
+            sleep(refresh_interval).await;
+            match std::fs::metadata(rules_file_path).and_then(|metadata| metadata.modified()) {
+                Ok(modified) if Some(modified) != last_modified => {
+                    last_modified = Some(modified);
+                    match RouteRules::load(rules_file_path) {
+                        Ok(new_rules) => {
+                            info!(
+                                "Load {} route rules from: {rules_file_path:?}",
+                                new_rules.rules.len()
+                            );
+                            match rules.write() {
+                                Ok(mut rules) => *rules = Arc::new(new_rules),
+                                Err(e) => error!("Fail to lock route rules: {e:?}"),
+                            }
+                        }
+                        Err(e) => error!("Keep using the previous route rules. {e}"),
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => error!("Fail to read metadata of route rules file {rules_file_path:?}: {e:?}"),
+            }
+        }
+    }
+}
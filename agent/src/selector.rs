@@ -0,0 +1,343 @@
+use crate::config::{Config, get_config};
+use crate::error::Error;
+use crate::user::get_agent_user_repo;
+use common::config::WithUsernameConfig;
+use common::proxy::ProxyConnection;
+use common::user::{UserRepository, UserWithProxyServers};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+use tracing::{error, info, warn};
+/// The weight of the newest latency sample
+const LATENCY_SMOOTHING_FACTOR: f64 = 0.3;
+static PROXY_SERVER_SELECTOR: OnceLock<ProxyServerSelector> = OnceLock::new();
+/// Get the proxy server selector, the background
+/// probing start when the selector is created.
+pub fn get_proxy_server_selector() -> &'static ProxyServerSelector {
+    PROXY_SERVER_SELECTOR.get_or_init(|| ProxyServerSelector::new(get_config()))
+}
+/// The strategy to select proxy server
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyServerStrategy {
+    /// Use the servers one by one
+    #[default]
+    RoundRobin,
+    /// Use the server with least active connections
+    LeastConnections,
+    /// Use the server with lowest handshake latency
+    LowestLatency,
+    /// Use the first server in the list, the others
+    /// are only used when the previous ones ejected
+    PrimaryBackup,
+}
+/// The state of a proxy server
+#[derive(Debug)]
+struct ProxyServerStatus {
+    address: SocketAddr,
+    active_connections: AtomicUsize,
+    health: Mutex<ProxyServerHealth>,
+}
+#[derive(Debug, Default)]
+struct ProxyServerHealth {
+    /// The smoothed handshake latency, `None` when never succeed
+    latency: Option<Duration>,
+    /// The server will not be selected until this time
+    ejected_until: Option<Instant>,
+    consecutive_failures: u32,
+}
+impl ProxyServerStatus {
+    fn new(address: SocketAddr) -> Self {
+        Self {
+            address,
+            active_connections: AtomicUsize::new(0),
+            health: Mutex::new(ProxyServerHealth::default()),
+        }
+    }
+    fn health<R>(&self, f: impl FnOnce(&mut ProxyServerHealth) -> R) -> R {
+        let mut health = self
+            .health
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        f(&mut health)
+    }
+    fn is_ejected(&self) -> bool {
+        self.health(|health| {
+            health
+                .ejected_until
+                .is_some_and(|ejected_until| ejected_until > Instant::now())
+        })
+    }
+    fn record_success(&self, latency: Duration) {
+        let recovered = self.health(|health| {
+            health.latency = Some(match health.latency {
+                None => latency,
+                Some(previous) => previous.mul_f64(1.0 - LATENCY_SMOOTHING_FACTOR)
+                    + latency.mul_f64(LATENCY_SMOOTHING_FACTOR),
+            });
+            health.consecutive_failures = 0;
+            health.ejected_until.take().is_some()
+        });
+        if recovered {
+            info!("Proxy server [{}] recovered, latency: {latency:?}", self.address);
+        }
+    }
+    fn record_failure(&self, eject_duration: Duration) {
+        let consecutive_failures = self.health(|health| {
+            health.consecutive_failures += 1;
+            health.ejected_until = Some(Instant::now() + eject_duration);
+            health.consecutive_failures
+        });
+        warn!(
+            "Proxy server [{}] ejected for {eject_duration:?}, consecutive failures: {consecutive_failures}",
+            self.address
+        );
+    }
+}
+/// The proxy server selected for new proxy connection, the server
+/// is not counted as active until a lease is taken.
+#[derive(Debug, Clone)]
+pub struct SelectedProxyServer {
+    status: Arc<ProxyServerStatus>,
+    eject_duration: Duration,
+}
+impl SelectedProxyServer {
+    pub fn address(&self) -> SocketAddr {
+        self.status.address
+    }
+    /// The handshake with the server succeed in `latency`
+    pub fn record_success(&self, latency: Duration) {
+        self.status.record_success(latency);
+    }
+    /// The handshake with the server failed
+    pub fn record_failure(&self) {
+        self.status.record_failure(self.eject_duration);
+    }
+    /// Count the server as active until the returned lease dropped,
+    /// take it only when the connection is handed out to the client.
+    pub fn lease(&self) -> ProxyServerLease {
+        ProxyServerLease::new(self.status.clone())
+    }
+}
+/// The lease of the selected proxy server, the active connection
+/// number of the server is decreased when the lease dropped.
+#[derive(Debug)]
+pub struct ProxyServerLease {
+    status: Arc<ProxyServerStatus>,
+}
+impl ProxyServerLease {
+    fn new(status: Arc<ProxyServerStatus>) -> Self {
+        status.active_connections.fetch_add(1, Ordering::Relaxed);
+        Self { status }
+    }
+    pub fn address(&self) -> SocketAddr {
+        self.status.address
+    }
+}
+impl Drop for ProxyServerLease {
+    fn drop(&mut self) {
+        self.status
+            .active_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+/// Select the proxy server for new proxy connection
+pub struct ProxyServerSelector {
+    strategy: ProxyServerStrategy,
+    eject_duration: Duration,
+    servers: Mutex<HashMap<SocketAddr, Arc<ProxyServerStatus>>>,
+    round_robin_index: AtomicUsize,
+}
+impl ProxyServerSelector {
+    fn new(config: &'static Config) -> Self {
+        if config.proxy_server_probe_interval() > 0 {
+            tokio::spawn(Self::probe(config));
+        }
+        Self::with_strategy(
+            config.proxy_server_strategy(),
+            Duration::from_secs(config.proxy_server_eject_duration()),
+        )
+    }
+    /// Create the selector without the background probing
+    pub fn with_strategy(strategy: ProxyServerStrategy, eject_duration: Duration) -> Self {
+        Self {
+            strategy,
+            eject_duration,
+            servers: Mutex::new(HashMap::new()),
+            round_robin_index: AtomicUsize::new(0),
+        }
+    }
+    /// The status of the servers in the same order of the addresses,
+    /// the status of a server is shared by all the lists contain it.
+    fn server_status(&self, proxy_servers: &[SocketAddr]) -> Vec<Arc<ProxyServerStatus>> {
+        let mut servers = self
+            .servers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        proxy_servers
+            .iter()
+            .map(|address| {
+                servers
+                    .entry(*address)
+                    .or_insert_with(|| Arc::new(ProxyServerStatus::new(*address)))
+                    .clone()
+            })
+            .collect()
+    }
+    /// Select a proxy server from the addresses, the ejected servers are
+    /// skipped unless all the servers are ejected.
+    pub fn select(&self, proxy_servers: &[SocketAddr]) -> Result<SelectedProxyServer, Error> {
+        let servers = self.server_status(proxy_servers);
+        let available = servers
+            .iter()
+            .filter(|status| !status.is_ejected())
+            .collect::<Vec<_>>();
+        let selected = if available.is_empty() {
+            // Better to try a broken server than give up directly
+            servers.iter().min_by_key(|status| {
+                status.health(|health| health.ejected_until)
+            })
+        } else {
+            match self.strategy {
+                ProxyServerStrategy::RoundRobin => {
+                    let index = self.round_robin_index.fetch_add(1, Ordering::Relaxed);
+                    available.get(index % available.len()).copied()
+                }
+                ProxyServerStrategy::LeastConnections => available
+                    .iter()
+                    .min_by_key(|status| status.active_connections.load(Ordering::Relaxed))
+                    .copied(),
+                // The server never measured is tried first
+                ProxyServerStrategy::LowestLatency => available
+                    .iter()
+                    .min_by_key(|status| status.health(|health| health.latency.unwrap_or_default()))
+                    .copied(),
+                ProxyServerStrategy::PrimaryBackup => available.first().copied(),
+            }
+        };
+        let selected = selected.ok_or(Error::NoProxyServer)?;
+        Ok(SelectedProxyServer {
+            status: selected.clone(),
+            eject_duration: self.eject_duration,
+        })
+    }
+    /// Handshake with all the servers periodically, so that the ejected
+    /// servers can come back and the latency keeps up to date.
+    async fn probe(config: &'static Config) {
+        let probe_interval = Duration::from_secs(config.proxy_server_probe_interval());
+        loop {
+            sleep(probe_interval).await;
+            let Some(agent_user) = get_agent_user_repo().find_user(config.username()) else {
+                error!("Fail to probe proxy servers, user [{}] not exist", config.username());
+                continue;
+            };
+            let selector = get_proxy_server_selector();
+            let mut proxy_servers = agent_user.proxy_servers().to_vec();
+            for group_proxy_servers in config.proxy_server_groups().values() {
+                proxy_servers.extend(group_proxy_servers);
+            }
+            proxy_servers.sort();
+            proxy_servers.dedup();
+            for status in selector.server_status(&proxy_servers) {
+                let start = Instant::now();
+                match ProxyConnection::new(
+                    agent_user.clone(),
+                    &[status.address],
+                    config.proxy_connect_timeout(),
+                )
+                .await
+                {
+                    Ok(_) => status.record_success(start.elapsed()),
+                    Err(e) => {
+                        error!("Fail to probe proxy server [{}]: {e:?}", status.address);
+                        status.record_failure(selector.eject_duration);
+                    }
+                }
+                status.health(|health| {
+                    info!(
+                        "Proxy server [{}] status: ejected: {}, latency: {:?}, active connections: {}, consecutive failures: {}",
+                        status.address,
+                        health
+                            .ejected_until
+                            .is_some_and(|ejected_until| ejected_until > Instant::now()),
+                        health.latency,
+                        status.active_connections.load(Ordering::Relaxed),
+                        health.consecutive_failures
+                    )
+                });
+            }
+        }
+    }
+}
+#[test]
+fn test() {
+    let servers: Vec<SocketAddr> = ["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3"]
+        .iter()
+        .map(|address| address.parse().unwrap())
+        .collect();
+    let select = |selector: &ProxyServerSelector| selector.select(&servers).unwrap();
+    // Round robin uses the servers one by one
+    let selector = ProxyServerSelector::with_strategy(
+        ProxyServerStrategy::RoundRobin,
+        Duration::from_secs(60),
+    );
+    let selected = (0..4).map(|_| select(&selector).address()).collect::<Vec<_>>();
+    assert_eq!(selected, [servers[0], servers[1], servers[2], servers[0]]);
+    // The selected server is only counted as active by the lease
+    let selector = ProxyServerSelector::with_strategy(
+        ProxyServerStrategy::LeastConnections,
+        Duration::from_secs(60),
+    );
+    let first = select(&selector);
+    assert_eq!(first.address(), servers[0]);
+    assert_eq!(select(&selector).address(), servers[0]);
+    let first_lease = first.lease();
+    let second_lease = select(&selector).lease();
+    assert_eq!(second_lease.address(), servers[1]);
+    assert_eq!(select(&selector).address(), servers[2]);
+    drop(first_lease);
+    assert_eq!(select(&selector).address(), servers[0]);
+    drop(second_lease);
+    // The server never measured is tried first, then the fastest one
+    let selector = ProxyServerSelector::with_strategy(
+        ProxyServerStrategy::LowestLatency,
+        Duration::from_secs(60),
+    );
+    select(&selector).record_success(Duration::from_millis(30));
+    assert_eq!(select(&selector).address(), servers[1]);
+    select(&selector).record_success(Duration::from_millis(10));
+    select(&selector).record_success(Duration::from_millis(20));
+    assert_eq!(select(&selector).address(), servers[1]);
+    // The backup servers are only used when the primary ejected,
+    // the primary come back when it succeed again.
+    let selector = ProxyServerSelector::with_strategy(
+        ProxyServerStrategy::PrimaryBackup,
+        Duration::from_secs(60),
+    );
+    let primary = select(&selector);
+    assert_eq!(primary.address(), servers[0]);
+    primary.record_failure();
+    assert_eq!(select(&selector).address(), servers[1]);
+    select(&selector).record_failure();
+    assert_eq!(select(&selector).address(), servers[2]);
+    primary.record_success(Duration::from_millis(10));
+    assert_eq!(select(&selector).address(), servers[0]);
+    // The ejected server come back after the eject duration
+    let selector = ProxyServerSelector::with_strategy(
+        ProxyServerStrategy::PrimaryBackup,
+        Duration::from_millis(50),
+    );
+    select(&selector).record_failure();
+    assert_eq!(select(&selector).address(), servers[1]);
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(select(&selector).address(), servers[0]);
+    // The server ejected earliest is tried when all the servers ejected
+    for _ in 0..3 {
+        select(&selector).record_failure();
+    }
+    assert_eq!(select(&selector).address(), servers[0]);
+}
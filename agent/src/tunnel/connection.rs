@@ -0,0 +1,78 @@
+use crate::selector::ProxyServerLease;
+use common::proxy::ProxyConnection;
+use common::proxy::ProxyFramedReaderWriter;
+use common::proxy::mux::MuxStream;
+use std::io::Error as StdIoError;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+/// The connection relay data with the destination,
+/// either a dedicated proxy connection, a stream in
+/// the multiplexed session or a direct connection.
+pub enum DestinationConnection {
+    Proxy {
+        proxy_connection: Box<ProxyConnection<ProxyFramedReaderWriter>>,
+        /// Keep the proxy server counted as active while the connection alive
+        _server_lease: ProxyServerLease,
+    },
+    Mux {
+        mux_stream: MuxStream,
+        /// Keep the proxy server counted as active while the stream alive
+        _server_lease: ProxyServerLease,
+    },
+    /// Connect to the destination without proxy
+    Direct(TcpStream),
+}
+impl AsyncRead for DestinationConnection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            DestinationConnection::Proxy {
+                proxy_connection, ..
+            } => Pin::new(proxy_connection).poll_read(cx, buf),
+            DestinationConnection::Mux { mux_stream, .. } => {
+                Pin::new(mux_stream).poll_read(cx, buf)
+            }
+            DestinationConnection::Direct(tcp_stream) => Pin::new(tcp_stream).poll_read(cx, buf),
+        }
+    }
+}
+impl AsyncWrite for DestinationConnection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, StdIoError>> {
+        match self.get_mut() {
+            DestinationConnection::Proxy {
+                proxy_connection, ..
+            } => Pin::new(proxy_connection).poll_write(cx, buf),
+            DestinationConnection::Mux { mux_stream, .. } => {
+                Pin::new(mux_stream).poll_write(cx, buf)
+            }
+            DestinationConnection::Direct(tcp_stream) => Pin::new(tcp_stream).poll_write(cx, buf),
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), StdIoError>> {
+        match self.get_mut() {
+            DestinationConnection::Proxy {
+                proxy_connection, ..
+            } => Pin::new(proxy_connection).poll_flush(cx),
+            DestinationConnection::Mux { mux_stream, .. } => Pin::new(mux_stream).poll_flush(cx),
+            DestinationConnection::Direct(tcp_stream) => Pin::new(tcp_stream).poll_flush(cx),
+        }
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), StdIoError>> {
+        match self.get_mut() {
+            DestinationConnection::Proxy {
+                proxy_connection, ..
+            } => Pin::new(proxy_connection).poll_shutdown(cx),
+            DestinationConnection::Mux { mux_stream, .. } => Pin::new(mux_stream).poll_shutdown(cx),
+            DestinationConnection::Direct(tcp_stream) => Pin::new(tcp_stream).poll_shutdown(cx),
+        }
+    }
+}
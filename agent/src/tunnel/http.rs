@@ -1,16 +1,21 @@
+use crate::config::{Config, get_config};
 use crate::error::Error;
-use crate::tunnel::fetch_proxy_connection;
+use crate::tunnel::connect_destination;
+use base64::prelude::*;
+use common::Error as CommonError;
+use common::config::WithUsernameConfig;
 use common::ServerState;
 use common::proxy::DestinationType;
 use http_body_util::combinators::BoxBody;
 use http_body_util::{BodyExt, Empty};
 use hyper::body::Incoming;
 use hyper::client::conn::http1::Builder;
+use hyper::header::{HeaderMap, HeaderValue, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
-use hyper::{Method, Request, Response};
+use hyper::{Method, Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
-use protocol::UnifiedAddress;
+use protocol::{HandshakeRejection, SetupDestinationFailure, UnifiedAddress};
 use std::net::SocketAddr;
 use tokio_util::bytes::Bytes;
 use tower::ServiceBuilder;
@@ -37,10 +42,94 @@ fn success_empty_body() -> BoxBody<Bytes, hyper::Error> {
         .boxed()
 }
 
+fn status_response(status: StatusCode) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut response = Response::new(success_empty_body());
+    *response.status_mut() = status;
+    response
+}
+
+/// The challenge response when the client do not give a valid credential
+fn proxy_authentication_required_response() -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut response = status_response(StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+    response.headers_mut().insert(
+        PROXY_AUTHENTICATE,
+        HeaderValue::from_static(r#"Basic realm="ppaass""#),
+    );
+    response
+}
+
+/// Parse the username and password from the value of `Proxy-Authorization`
+/// header, only the `Basic` scheme is supported.
+fn parse_basic_credential(proxy_authorization: &HeaderValue) -> Option<(String, String)> {
+    let (scheme, credential) = proxy_authorization.to_str().ok()?.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+    let credential = BASE64_STANDARD.decode(credential.trim()).ok()?;
+    let credential = String::from_utf8(credential).ok()?;
+    let (username, password) = credential.split_once(':')?;
+    Some((username.to_owned(), password.to_owned()))
+}
+
+/// Authenticate the client with the `Proxy-Authorization` header, the agent
+/// user mapped from the local user is returned. All the clients use the agent
+/// user of the configuration when no local user configured.
+fn authenticate_client(
+    config: &'static Config,
+    client_http_headers: &HeaderMap,
+) -> Option<&'static str> {
+    if config.local_users().is_empty() {
+        return Some(config.username());
+    }
+    let (username, password) = client_http_headers
+        .get(PROXY_AUTHORIZATION)
+        .and_then(parse_basic_credential)?;
+    let local_user = config.authenticate_local_user(&username, &password)?;
+    Some(local_user.agent_username(config))
+}
+
+/// Map the error of destination setup to http status code
+fn http_error_status(error: &Error) -> StatusCode {
+    match error {
+        Error::Common(
+            CommonError::SetupDestination(_, failure) | CommonError::MultiplexingRefused(failure),
+        ) => match failure {
+            SetupDestinationFailure::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            SetupDestinationFailure::TooManyStreams => StatusCode::TOO_MANY_REQUESTS,
+            SetupDestinationFailure::AclDenied
+            | SetupDestinationFailure::UserExpired
+            | SetupDestinationFailure::QuotaExceeded => StatusCode::FORBIDDEN,
+            SetupDestinationFailure::DnsResolution
+            | SetupDestinationFailure::ConnectionRefused
+            | SetupDestinationFailure::Unreachable
+            | SetupDestinationFailure::Other => StatusCode::BAD_GATEWAY,
+        },
+        Error::Common(CommonError::HandshakeRejected(HandshakeRejection::UserExpired {
+            ..
+        })) => StatusCode::FORBIDDEN,
+        Error::Common(CommonError::HandshakeRejected(HandshakeRejection::TooManySessions {
+            ..
+        })) => StatusCode::TOO_MANY_REQUESTS,
+        Error::RouteRejected(_) => StatusCode::FORBIDDEN,
+        Error::DirectConnectTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+        // Fail to connect the destination directly
+        Error::Io(_) => StatusCode::BAD_GATEWAY,
+        // Can not build the connection with proxy
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
 async fn client_http_request_handler(
     client_addr: SocketAddr,
-    client_http_request: Request<Incoming>,
+    mut client_http_request: Request<Incoming>,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Error> {
+    let Some(agent_username) = authenticate_client(get_config(), client_http_request.headers())
+    else {
+        error!("Http client [{client_addr}] fail to authenticate.");
+        return Ok(proxy_authentication_required_response());
+    };
+    // The credential of the client should not go to the destination
+    client_http_request.headers_mut().remove(PROXY_AUTHORIZATION);
     let destination_uri = client_http_request.uri();
     let destination_host = destination_uri
         .host()
@@ -61,10 +150,16 @@ async fn client_http_request_handler(
         "Receive client http request to destination: {destination_address:?}, client socket address: {client_addr}"
     );
 
-    let proxy_connection = fetch_proxy_connection().await?;
-    let mut proxy_connection = proxy_connection
-        .setup_destination(destination_address, DestinationType::Tcp)
-        .await?;
+    let mut proxy_connection =
+        match connect_destination(agent_username, destination_address, DestinationType::Tcp)
+            .await
+        {
+            Ok(proxy_connection) => proxy_connection,
+            Err(e) => {
+                error!("Fail to connect destination for client [{client_addr}]: {e:?}");
+                return Ok(status_response(http_error_status(&e)));
+            }
+        };
 
     if Method::CONNECT == client_http_request.method() {
         // Received an HTTP request like:
@@ -130,3 +225,114 @@ async fn client_http_request_handler(
         Ok(proxy_response.map(|b| b.boxed()))
     }
 }
+#[test]
+fn test() {
+    let header_value = |scheme: &str, credential: &str| {
+        HeaderValue::from_str(&format!("{scheme} {}", BASE64_STANDARD.encode(credential))).unwrap()
+    };
+    let credential =
+        |username: &str, password: &str| Some((username.to_owned(), password.to_owned()));
+    assert_eq!(
+        parse_basic_credential(&header_value("Basic", "user1:pass:1")),
+        credential("user1", "pass:1")
+    );
+    assert_eq!(
+        parse_basic_credential(&header_value("basic", "user1:pass1")),
+        credential("user1", "pass1")
+    );
+    assert_eq!(parse_basic_credential(&header_value("Bearer", "user1:pass1")), None);
+    assert_eq!(parse_basic_credential(&HeaderValue::from_static("Basic !!!")), None);
+    assert_eq!(parse_basic_credential(&header_value("Basic", "user1")), None);
+    // The local user is mapped to its own agent user or the configured one
+    let config: &'static Config = Box::leak(Box::new(
+        toml::from_str(
+            r#"
+            username = "agent1"
+            [[local_users]]
+            username = "user1"
+            password = "pass1"
+            [[local_users]]
+            username = "user2"
+            password = "pass2"
+            agent_username = "agent2"
+            "#,
+        )
+        .unwrap(),
+    ));
+    let headers = |proxy_authorization: Option<HeaderValue>| {
+        let mut headers = HeaderMap::new();
+        if let Some(proxy_authorization) = proxy_authorization {
+            headers.insert(PROXY_AUTHORIZATION, proxy_authorization);
+        }
+        headers
+    };
+    let authenticate = |credential: Option<&str>| {
+        authenticate_client(
+            config,
+            &headers(credential.map(|credential| header_value("Basic", credential))),
+        )
+    };
+    assert_eq!(authenticate(Some("user1:pass1")), Some("agent1"));
+    assert_eq!(authenticate(Some("user2:pass2")), Some("agent2"));
+    assert_eq!(authenticate(Some("user1:pass2")), None);
+    assert_eq!(authenticate(Some("user3:pass1")), None);
+    assert_eq!(authenticate(None), None);
+    // All the clients use the configured agent user without local users
+    let config: &'static Config =
+        Box::leak(Box::new(toml::from_str(r#"username = "agent1""#).unwrap()));
+    assert_eq!(authenticate_client(config, &headers(None)), Some("agent1"));
+    // The client is challenged for the basic credential
+    let response = proxy_authentication_required_response();
+    assert_eq!(response.status(), StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+    assert_eq!(
+        response.headers().get(PROXY_AUTHENTICATE).unwrap(),
+        r#"Basic realm="ppaass""#
+    );
+    // The failure of destination setup is mapped to the http status
+    use SetupDestinationFailure as Failure;
+    let destination_address = UnifiedAddress::from(SocketAddr::from(([127, 0, 0, 1], 80)));
+    let setup_destination_error = |failure| {
+        Error::Common(CommonError::SetupDestination(destination_address.clone(), failure))
+    };
+    for (failure, status) in [
+        (Failure::DnsResolution, StatusCode::BAD_GATEWAY),
+        (Failure::ConnectionRefused, StatusCode::BAD_GATEWAY),
+        (Failure::Timeout, StatusCode::GATEWAY_TIMEOUT),
+        (Failure::Unreachable, StatusCode::BAD_GATEWAY),
+        (Failure::AclDenied, StatusCode::FORBIDDEN),
+        (Failure::UserExpired, StatusCode::FORBIDDEN),
+        (Failure::QuotaExceeded, StatusCode::FORBIDDEN),
+        (Failure::TooManyStreams, StatusCode::TOO_MANY_REQUESTS),
+        (Failure::Other, StatusCode::BAD_GATEWAY),
+    ] {
+        assert_eq!(http_error_status(&setup_destination_error(failure)), status, "{failure:?}");
+        let error = Error::Common(CommonError::MultiplexingRefused(failure));
+        assert_eq!(http_error_status(&error), status, "{failure:?}");
+    }
+    for (error, status) in [
+        (
+            Error::Common(CommonError::HandshakeRejected(HandshakeRejection::UserExpired {
+                expired_time: 0,
+            })),
+            StatusCode::FORBIDDEN,
+        ),
+        (
+            Error::Common(CommonError::HandshakeRejected(HandshakeRejection::TooManySessions {
+                max_sessions: 1,
+            })),
+            StatusCode::TOO_MANY_REQUESTS,
+        ),
+        (Error::RouteRejected(destination_address.clone()), StatusCode::FORBIDDEN),
+        (Error::DirectConnectTimeout(10), StatusCode::GATEWAY_TIMEOUT),
+        (
+            Error::Io(std::io::ErrorKind::ConnectionRefused.into()),
+            StatusCode::BAD_GATEWAY,
+        ),
+        (
+            Error::Common(CommonError::ConnectTimeout(10)),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ),
+    ] {
+        assert_eq!(http_error_status(&error), status, "{error:?}");
+    }
+}
@@ -1,19 +1,57 @@
+mod connection;
 mod http;
+mod socks4;
 mod socks5;
+mod udp;
 use crate::config::get_config;
 use crate::error::Error;
-use crate::user::get_agent_user_repo;
-use common::Error as CommonError;
+use crate::pool::{create_proxy_connection, get_proxy_connection_pool};
+use crate::route::{Route, get_router};
+use crate::selector::SelectedProxyServer;
+use crate::tunnel::connection::DestinationConnection;
 use common::ServerState;
+use common::limit::ConnectionLimiter;
+use common::proxy::mux::MuxSession;
+use common::proxy::{DestinationType, ProxyConnection, ProxyFramed};
+use protocol::UnifiedAddress;
 use common::config::WithUsernameConfig;
-use common::proxy::Init;
-use common::proxy::{ProxyConnection, ProxyFramed};
-use common::user::UserRepository;
-use tokio::io::AsyncWriteExt;
-use tracing::{debug, error};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tracing::debug;
 const SOCKS4_VERSION_FLAG: u8 = 4;
 const SOCKS5_VERSION_FLAG: u8 = 5;
-pub async fn process(mut server_state: ServerState) -> Result<(), Error> {
+/// The multiplexed sessions shared by all the client connections, one
+/// session for each agent user. The map is only locked to find the slot of
+/// the user, the session is created with the slot locked so that the slow
+/// proxy only blocks the clients of the same agent user.
+static SHARED_MUX_SESSIONS: LazyLock<Mutex<HashMap<String, SharedMuxSessionSlot>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+/// Count the active connections of each client ip
+static CLIENT_CONNECTION_LIMITER: LazyLock<Arc<ConnectionLimiter<IpAddr>>> =
+    LazyLock::new(Default::default);
+struct SharedMuxSession {
+    mux_session: Arc<MuxSession>,
+    /// The proxy server is counted as active once for each opened stream
+    proxy_server: SelectedProxyServer,
+}
+type SharedMuxSessionSlot = Arc<tokio::sync::Mutex<Option<SharedMuxSession>>>;
+pub async fn process(server_state: ServerState) -> Result<(), Error> {
+    // The connection is closed directly when the client ip reach the limit,
+    // otherwise it is counted until the connection finished.
+    let client_ip = server_state.incoming_connection_addr.ip();
+    let client_max_connections_per_ip = get_config().client_max_connections_per_ip();
+    let Some(_client_connection_permit) =
+        CLIENT_CONNECTION_LIMITER.try_acquire(client_ip, client_max_connections_per_ip)
+    else {
+        return Err(Error::ClientConnectionsExceeded(
+            client_ip,
+            client_max_connections_per_ip.unwrap_or_default(),
+        ));
+    };
     let mut protocol_flag_buf = [0u8; 1];
     let flag_size = server_state
         .incoming_stream
@@ -25,8 +63,11 @@ pub async fn process(mut server_state: ServerState) -> Result<(), Error> {
     let protocol_flag = protocol_flag_buf[0];
     match protocol_flag {
         SOCKS4_VERSION_FLAG => {
-            error!("Socks 4 protocol not supported");
-            server_state.incoming_stream.shutdown().await?;
+            debug!(
+                "Accept socks 4 protocol client connection [{}].",
+                server_state.incoming_connection_addr
+            );
+            socks4::process_socks4_tunnel(server_state).await?;
         }
         SOCKS5_VERSION_FLAG => {
             debug!(
@@ -45,14 +86,110 @@ pub async fn process(mut server_state: ServerState) -> Result<(), Error> {
     }
     Ok(())
 }
-/// Fetch a proxy connection, the returned
-/// proxy connection complete handshake already.
-async fn fetch_proxy_connection() -> Result<ProxyConnection<ProxyFramed>, Error> {
-    let config = get_config();
-    let agent_user = get_agent_user_repo()
-        .find_user(config.username())
-        .ok_or(CommonError::UserNotExist(config.username().to_owned()))?;
-    ProxyConnection::<Init>::new(agent_user, config.proxy_connect_timeout())
+/// Connect to the destination through the proxy as the agent user,
+/// the returned connection can relay data with the destination directly.
+async fn connect_destination(
+    agent_username: &str,
+    destination_address: UnifiedAddress,
+    destination_type: DestinationType,
+) -> Result<DestinationConnection, Error> {
+    let proxy_server_group = match get_router().route(&destination_address) {
+        Route::Reject => return Err(Error::RouteRejected(destination_address)),
+        Route::Direct => {
+            return Ok(DestinationConnection::Direct(
+                connect_direct(&destination_address).await?,
+            ));
+        }
+        Route::Proxy(proxy_server_group) => proxy_server_group,
+    };
+    if !get_config().proxy_multiplexing() || proxy_server_group.is_some() {
+        let (proxy_connection, proxy_server) =
+            fetch_proxy_connection(agent_username, proxy_server_group.as_deref()).await?;
+        let server_lease = proxy_server.lease();
+        return Ok(DestinationConnection::Proxy {
+            proxy_connection: Box::new(
+                proxy_connection
+                    .setup_destination(destination_address, destination_type)
+                    .await?,
+            ),
+            _server_lease: server_lease,
+        });
+    }
+    let shared_mux_session_slot = SHARED_MUX_SESSIONS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .entry(agent_username.to_owned())
+        .or_default()
+        .clone();
+    let mut shared_mux_session = shared_mux_session_slot.lock().await;
+    let (mux_session, proxy_server) = match shared_mux_session.as_ref() {
+        Some(SharedMuxSession {
+            mux_session,
+            proxy_server,
+        }) if !mux_session.is_closed() => (mux_session.clone(), proxy_server.clone()),
+        _ => {
+            let (proxy_connection, proxy_server) =
+                fetch_proxy_connection(agent_username, None).await?;
+            if !proxy_connection.capabilities().multiplexing {
+                // The proxy do not support multiplexing, use
+                // the dedicated connection instead.
+                drop(shared_mux_session);
+                let server_lease = proxy_server.lease();
+                return Ok(DestinationConnection::Proxy {
+                    proxy_connection: Box::new(
+                        proxy_connection
+                            .setup_destination(destination_address, destination_type)
+                            .await?,
+                    ),
+                    _server_lease: server_lease,
+                });
+            }
+            debug!(
+                "Create new multiplexed session with proxy server [{}] for agent user [{agent_username}].",
+                proxy_server.address()
+            );
+            let mux_session = Arc::new(proxy_connection.into_mux_session().await?);
+            *shared_mux_session = Some(SharedMuxSession {
+                mux_session: mux_session.clone(),
+                proxy_server: proxy_server.clone(),
+            });
+            (mux_session, proxy_server)
+        }
+    };
+    drop(shared_mux_session);
+    let server_lease = proxy_server.lease();
+    Ok(DestinationConnection::Mux {
+        mux_stream: mux_session
+            .open(destination_address, destination_type)
+            .await?,
+        _server_lease: server_lease,
+    })
+}
+/// Fetch a proxy connection, the returned proxy connection complete
+/// handshake already. The pool only keeps the connections of the default
+/// agent user to its own servers, the others are created on demand.
+async fn fetch_proxy_connection(
+    agent_username: &str,
+    proxy_server_group: Option<&str>,
+) -> Result<(ProxyConnection<ProxyFramed>, SelectedProxyServer), Error> {
+    if agent_username == get_config().username() && proxy_server_group.is_none() {
+        return get_proxy_connection_pool().fetch().await;
+    }
+    create_proxy_connection(agent_username, proxy_server_group).await
+}
+/// Connect to the destination from agent without proxy
+async fn connect_direct(destination_address: &UnifiedAddress) -> Result<TcpStream, Error> {
+    let direct_connect_timeout = get_config().direct_connect_timeout();
+    let connect = async {
+        match destination_address {
+            UnifiedAddress::Domain { host, port } => {
+                TcpStream::connect((host.as_str(), *port)).await
+            }
+            UnifiedAddress::SocketAddress(socket_addr) => TcpStream::connect(socket_addr).await,
+        }
+    };
+    timeout(Duration::from_secs(direct_connect_timeout), connect)
         .await
+        .map_err(|_| Error::DirectConnectTimeout(direct_connect_timeout))?
         .map_err(Into::into)
 }
@@ -0,0 +1,171 @@
+use crate::config::get_config;
+use crate::error::Error;
+use crate::tunnel::connect_destination;
+use common::ServerState;
+use common::config::WithUsernameConfig;
+use common::proxy::DestinationType;
+use protocol::UnifiedAddress;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, copy_bidirectional};
+use tracing::{debug, error, info};
+const SOCKS4_VERSION: u8 = 4;
+/// The version of the reply is 0 instead of 4
+const SOCKS4_REPLY_VERSION: u8 = 0;
+const SOCKS4_CMD_CONNECT: u8 = 1;
+const SOCKS4_REPLY_GRANTED: u8 = 90;
+const SOCKS4_REPLY_REJECTED: u8 = 91;
+/// The max length of the null terminated user id and domain name
+const SOCKS4_MAX_FIELD_LENGTH: usize = 255;
+/// Read the null terminated string from the client
+async fn read_null_terminated<R: AsyncRead + Unpin>(reader: &mut R) -> Result<String, Error> {
+    let mut field = Vec::new();
+    loop {
+        let byte = reader.read_u8().await?;
+        if byte == 0 {
+            break;
+        }
+        if field.len() >= SOCKS4_MAX_FIELD_LENGTH {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "Socks 4 request field is too long",
+            )
+            .into());
+        }
+        field.push(byte);
+    }
+    String::from_utf8(field)
+        .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e).into())
+}
+/// Send the reply to client, the destination address
+/// is ignored by the client for CONNECT command.
+async fn reply<W: AsyncWrite + Unpin>(
+    client_stream: &mut W,
+    reply_code: u8,
+) -> Result<(), Error> {
+    let mut reply = [0u8; 8];
+    reply[0] = SOCKS4_REPLY_VERSION;
+    reply[1] = reply_code;
+    client_stream.write_all(&reply).await?;
+    Ok(())
+}
+/// Read the socks 4/4a request, the destination address of the
+/// socks 4a request is the domain name follows the user id.
+async fn read_request<R: AsyncRead + Unpin>(
+    client_stream: &mut R,
+) -> Result<(u8, UnifiedAddress), Error> {
+    let version = client_stream.read_u8().await?;
+    if version != SOCKS4_VERSION {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid socks 4 version: {version}"),
+        )
+        .into());
+    }
+    let command = client_stream.read_u8().await?;
+    let port = client_stream.read_u16().await?;
+    let ip = Ipv4Addr::from(client_stream.read_u32().await?);
+    let user_id = read_null_terminated(client_stream).await?;
+    debug!("Receive socks 4 request with user id: [{user_id}]");
+    // The ip 0.0.0.x (x != 0) means the domain name follows
+    let octets = ip.octets();
+    let destination_address = if octets[..3] == [0, 0, 0] && octets[3] != 0 {
+        UnifiedAddress::Domain {
+            host: read_null_terminated(client_stream).await?,
+            port,
+        }
+    } else {
+        SocketAddr::V4(SocketAddrV4::new(ip, port)).into()
+    };
+    Ok((command, destination_address))
+}
+pub async fn process_socks4_tunnel(server_state: ServerState) -> Result<(), Error> {
+    let client_addr = server_state.incoming_connection_addr;
+    let mut client_stream = server_state.incoming_stream;
+    let (command, destination_address) = read_request(&mut client_stream).await?;
+    // Socks 4 can not carry the password, so the clients
+    // are refused when the local users are configured.
+    if !get_config().local_users().is_empty() {
+        error!("Socks 4 client [{client_addr}] rejected because authentication is required.");
+        reply(&mut client_stream, SOCKS4_REPLY_REJECTED).await?;
+        return Err(Error::Socks4AuthenticationRequired);
+    }
+    if command != SOCKS4_CMD_CONNECT {
+        reply(&mut client_stream, SOCKS4_REPLY_REJECTED).await?;
+        return Err(Error::Socks4CommandNotSupported(command));
+    }
+    debug!("Receive socks4 CONNECT command to [{destination_address}]: {client_addr}");
+    let mut proxy_connection = match connect_destination(
+        get_config().username(),
+        destination_address,
+        DestinationType::Tcp,
+    )
+    .await
+    {
+        Ok(proxy_connection) => proxy_connection,
+        Err(e) => {
+            reply(&mut client_stream, SOCKS4_REPLY_REJECTED).await?;
+            return Err(e);
+        }
+    };
+    reply(&mut client_stream, SOCKS4_REPLY_GRANTED).await?;
+    // Proxying data
+    let (from_client, from_proxy) =
+        match copy_bidirectional(&mut client_stream, &mut proxy_connection).await {
+            Err(e) => {
+                error!("Fail to proxy data between agent and proxy: {e:?}");
+                return Ok(());
+            }
+            Ok((from_client, from_proxy)) => (from_client, from_proxy),
+        };
+    info!(
+        "Agent wrote {} bytes to proxy, received {} bytes from proxy",
+        from_client, from_proxy
+    );
+    Ok(())
+}
+#[tokio::test]
+async fn test() {
+    // Socks 4 CONNECT to 127.0.0.1:80 with user id "user"
+    let request = [&[4u8, 1, 0, 80, 127, 0, 0, 1][..], b"user\0"].concat();
+    let (command, destination_address) = read_request(&mut request.as_slice()).await.unwrap();
+    assert_eq!(command, SOCKS4_CMD_CONNECT);
+    assert_eq!(
+        destination_address,
+        UnifiedAddress::from(SocketAddr::from(([127, 0, 0, 1], 80)))
+    );
+    // Socks 4a marks the domain name with ip 0.0.0.x
+    let request = [&[4u8, 1, 1, 187, 0, 0, 0, 1][..], b"\0example.com\0"].concat();
+    let (_, destination_address) = read_request(&mut request.as_slice()).await.unwrap();
+    assert_eq!(
+        destination_address,
+        UnifiedAddress::Domain {
+            host: "example.com".to_owned(),
+            port: 443
+        }
+    );
+    // The ip 0.0.0.0 is not the socks 4a marker
+    let request = [4u8, 1, 0, 80, 0, 0, 0, 0, 0, b'x'];
+    let (_, destination_address) = read_request(&mut request.as_slice()).await.unwrap();
+    assert_eq!(
+        destination_address,
+        UnifiedAddress::from(SocketAddr::from(([0, 0, 0, 0], 80)))
+    );
+    // The domain name with max length is accepted, the longer one is refused
+    let max_domain = "a".repeat(SOCKS4_MAX_FIELD_LENGTH);
+    let request = [&[4u8, 1, 0, 80, 0, 0, 0, 9, 0][..], max_domain.as_bytes(), b"\0"].concat();
+    assert!(read_request(&mut request.as_slice()).await.is_ok());
+    let request = [&[4u8, 1, 0, 80, 0, 0, 0, 9, 0][..], max_domain.as_bytes(), b"a\0"].concat();
+    assert!(read_request(&mut request.as_slice()).await.is_err());
+    // The user id without null terminator, the invalid version and the invalid utf8 domain
+    let request = [&[4u8, 1, 0, 80, 127, 0, 0, 1][..], b"user"].concat();
+    assert!(read_request(&mut request.as_slice()).await.is_err());
+    let request = [5u8, 1, 0, 80, 127, 0, 0, 1, 0];
+    assert!(read_request(&mut request.as_slice()).await.is_err());
+    let request = [4u8, 1, 0, 80, 0, 0, 0, 1, 0, 0xff, 0];
+    assert!(read_request(&mut request.as_slice()).await.is_err());
+    // The reply version is 0 and the address is ignored
+    let mut reply_bytes = Vec::new();
+    reply(&mut reply_bytes, SOCKS4_REPLY_REJECTED).await.unwrap();
+    assert_eq!(reply_bytes, [0, 91, 0, 0, 0, 0, 0, 0]);
+}
@@ -1,17 +1,22 @@
 use crate::config::get_config;
 use crate::error::Error;
-use crate::tunnel::fetch_proxy_connection;
+use crate::route::{Route, get_router};
+use crate::tunnel::{connect_destination, fetch_proxy_connection, udp};
 use common::proxy::DestinationType;
+use common::Error as CommonError;
+use common::config::WithUsernameConfig;
 use common::{ServerState, WithServerConfig};
+use fast_socks5::server::states::CommandRead;
 use fast_socks5::server::{Socks5ServerProtocol, SocksServerError, run_udp_proxy_custom};
 use fast_socks5::util::target_addr::TargetAddr;
-use fast_socks5::{Socks5Command, parse_udp_request};
-use protocol::UnifiedAddress;
+use fast_socks5::{ReplyError, Socks5Command, consts};
+use protocol::{HandshakeRejection, SetupDestinationFailure, UnifiedAddress};
+use std::io::ErrorKind;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-use tokio::io::{AsyncReadExt, AsyncWriteExt, copy_bidirectional};
-use tokio::net::UdpSocket;
+use tokio::io::{AsyncWriteExt, copy_bidirectional};
+use tokio::net::{TcpStream, UdpSocket};
 use tracing::{debug, error, info};
-fn convert_address(address: &TargetAddr) -> UnifiedAddress {
+pub(super) fn convert_address(address: &TargetAddr) -> UnifiedAddress {
     match address {
         TargetAddr::Ip(dst_addr) => dst_addr.into(),
         TargetAddr::Domain(host, port) => UnifiedAddress::Domain {
@@ -20,14 +25,161 @@ fn convert_address(address: &TargetAddr) -> UnifiedAddress {
         },
     }
 }
+/// Map the error of destination setup to socks5 reply code
+fn socks5_reply_error(error: &Error) -> ReplyError {
+    match error {
+        Error::Common(
+            CommonError::SetupDestination(_, failure) | CommonError::MultiplexingRefused(failure),
+        ) => match failure {
+            SetupDestinationFailure::DnsResolution => ReplyError::HostUnreachable,
+            SetupDestinationFailure::ConnectionRefused => ReplyError::ConnectionRefused,
+            SetupDestinationFailure::Timeout => ReplyError::ConnectionTimeout,
+            SetupDestinationFailure::Unreachable => ReplyError::NetworkUnreachable,
+            SetupDestinationFailure::AclDenied
+            | SetupDestinationFailure::UserExpired
+            | SetupDestinationFailure::QuotaExceeded => ReplyError::ConnectionNotAllowed,
+            SetupDestinationFailure::TooManyStreams | SetupDestinationFailure::Other => {
+                ReplyError::GeneralFailure
+            }
+        },
+        Error::Common(CommonError::HandshakeRejected(
+            HandshakeRejection::UserExpired { .. } | HandshakeRejection::TooManySessions { .. },
+        )) => ReplyError::ConnectionNotAllowed,
+        Error::RouteRejected(_) => ReplyError::ConnectionNotAllowed,
+        Error::DirectConnectTimeout(_) => ReplyError::ConnectionTimeout,
+        Error::Io(e) => match e.kind() {
+            ErrorKind::ConnectionRefused => ReplyError::ConnectionRefused,
+            ErrorKind::TimedOut => ReplyError::ConnectionTimeout,
+            ErrorKind::HostUnreachable => ReplyError::HostUnreachable,
+            ErrorKind::NetworkUnreachable => ReplyError::NetworkUnreachable,
+            _ => ReplyError::GeneralFailure,
+        },
+        _ => ReplyError::GeneralFailure,
+    }
+}
+/// Write the reply to client directly, the bind command need
+/// the second reply after the first one sent by the protocol.
+async fn write_socks5_reply(
+    client_stream: &mut TcpStream,
+    reply: &ReplyError,
+    address: SocketAddr,
+) -> Result<(), Error> {
+    let mut reply_bytes = vec![consts::SOCKS5_VERSION, reply.as_u8(), 0];
+    reply_bytes.extend(
+        TargetAddr::Ip(address)
+            .to_be_bytes()
+            .map_err(SocksServerError::from)?,
+    );
+    client_stream.write_all(&reply_bytes).await?;
+    Ok(())
+}
+/// The proxy listen for the inbound peer of bind, the client get the
+/// listening address in the first reply and the peer address in the
+/// second reply, then the data is relayed with the peer.
+async fn process_socks5_bind(
+    socks5_client_stream: Socks5ServerProtocol<TcpStream, CommandRead>,
+    agent_username: &str,
+    peer_address: UnifiedAddress,
+) -> Result<(), Error> {
+    let proxy_server_group = match get_router().route(&peer_address) {
+        Route::Reject => {
+            socks5_client_stream
+                .reply_error(&ReplyError::ConnectionNotAllowed)
+                .await?;
+            return Err(Error::RouteRejected(peer_address));
+        }
+        // The agent do not listen for the peer by itself
+        Route::Direct => {
+            socks5_client_stream
+                .reply_error(&ReplyError::CommandNotSupported)
+                .await?;
+            return Err(Error::DirectBindNotSupported(peer_address));
+        }
+        Route::Proxy(proxy_server_group) => proxy_server_group,
+    };
+    let setup_bind = async {
+        let (proxy_connection, proxy_server) =
+            fetch_proxy_connection(agent_username, proxy_server_group.as_deref()).await?;
+        let server_lease = proxy_server.lease();
+        let (proxy_connection, bound_addr) = proxy_connection.setup_bind(peer_address).await?;
+        Ok::<_, Error>((proxy_connection, server_lease, bound_addr))
+    };
+    let (proxy_connection, _server_lease, bound_addr) = match setup_bind.await {
+        Ok(bind_listening) => bind_listening,
+        Err(e) => {
+            socks5_client_stream
+                .reply_error(&socks5_reply_error(&e))
+                .await?;
+            return Err(e);
+        }
+    };
+    debug!("Proxy listening on [{bound_addr}] for the peer of bind");
+    let mut socks5_client_stream = socks5_client_stream.reply_success(bound_addr).await?;
+    let (mut proxy_connection, peer_addr) = match proxy_connection.accept_peer().await {
+        Ok(peer_connection) => peer_connection,
+        Err(e) => {
+            let e = Error::from(e);
+            write_socks5_reply(
+                &mut socks5_client_stream,
+                &socks5_reply_error(&e),
+                SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)),
+            )
+            .await?;
+            return Err(e);
+        }
+    };
+    write_socks5_reply(&mut socks5_client_stream, &ReplyError::Succeeded, peer_addr).await?;
+    // Proxying data
+    let (from_client, from_proxy) =
+        match copy_bidirectional(&mut socks5_client_stream, &mut proxy_connection).await {
+            Err(e) => {
+                error!("Fail to proxy data between agent and proxy: {e:?}");
+                return Ok(());
+            }
+            Ok((from_client, from_proxy)) => (from_client, from_proxy),
+        };
+    info!(
+        "Agent wrote {} bytes to proxy, received {} bytes from proxy for the peer [{peer_addr}] of bind",
+        from_client, from_proxy
+    );
+    Ok(())
+}
 pub async fn process_socks5_tunnel(server_state: ServerState) -> Result<(), Error> {
     debug!(
         "Client connect to agent with socks 5 protocol: {}",
         server_state.incoming_connection_addr
     );
 
-    let socks5_client_stream =
-        Socks5ServerProtocol::accept_no_auth(server_state.incoming_stream).await?;
+    // The client use the agent user mapped from its credential
+    // when the local users are configured.
+    let (socks5_client_stream, agent_username) = if get_config().local_users().is_empty() {
+        (
+            Socks5ServerProtocol::accept_no_auth(server_state.incoming_stream).await?,
+            get_config().username(),
+        )
+    } else {
+        let (socks5_client_stream, local_user) = Socks5ServerProtocol::accept_password_auth(
+            server_state.incoming_stream,
+            |username, password| get_config().authenticate_local_user(&username, &password),
+        )
+        .await
+        .inspect_err(|e| {
+            error!(
+                "Socks 5 client [{}] fail to authenticate: {e:?}",
+                server_state.incoming_connection_addr
+            )
+        })?;
+        let local_user = local_user.ok_or(SocksServerError::AuthenticationRejected)?;
+        debug!(
+            "Socks 5 client [{}] authenticated as local user [{}].",
+            server_state.incoming_connection_addr,
+            local_user.username()
+        );
+        (
+            socks5_client_stream,
+            local_user.agent_username(get_config()),
+        )
+    };
     let (socks5_client_stream, socks5_command, dst_addr) =
         socks5_client_stream.read_command().await?;
 
@@ -37,11 +189,19 @@ pub async fn process_socks5_tunnel(server_state: ServerState) -> Result<(), Erro
                 "Receive socks5 CONNECT command: {}",
                 server_state.incoming_connection_addr
             );
-            let proxy_connection = fetch_proxy_connection().await?;
             let destination_address = convert_address(&dst_addr);
-            let mut proxy_connection = proxy_connection
-                .setup_destination(destination_address, DestinationType::Tcp)
-                .await?;
+            let mut proxy_connection =
+                match connect_destination(agent_username, destination_address, DestinationType::Tcp)
+                    .await
+                {
+                    Ok(proxy_connection) => proxy_connection,
+                    Err(e) => {
+                        socks5_client_stream
+                            .reply_error(&socks5_reply_error(&e))
+                            .await?;
+                        return Err(e);
+                    }
+                };
 
             let mut socks5_client_stream = socks5_client_stream
                 .reply_success(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)))
@@ -62,12 +222,20 @@ pub async fn process_socks5_tunnel(server_state: ServerState) -> Result<(), Erro
             );
         }
         Socks5Command::TCPBind => {
-            unimplemented!(
-                "Socks5 bind protocol not supported, client_addr: {}",
+            debug!(
+                "Receive socks5 BIND command: {}",
                 server_state.incoming_connection_addr
-            )
+            );
+            process_socks5_bind(socks5_client_stream, agent_username, convert_address(&dst_addr))
+                .await?;
         }
         Socks5Command::UDPAssociate => {
+            debug!(
+                "Receive socks5 UDP ASSOCIATE command: {}",
+                server_state.incoming_connection_addr
+            );
+            let udp_client_addr = convert_address(&dst_addr);
+            let control_client_ip = server_state.incoming_connection_addr.ip();
             run_udp_proxy_custom(
                 socks5_client_stream,
                 &dst_addr,
@@ -81,58 +249,17 @@ pub async fn process_socks5_tunnel(server_state: ServerState) -> Result<(), Erro
                                 context: "Fail to create client udp socket.",
                             }
                         })?;
-                    let mut client_udp_socks5_packet = vec![0u8; 8192];
-                    client_udp_socket
-                        .recv(&mut client_udp_socks5_packet)
-                        .await
-                        .map_err(|e| SocksServerError::Io {
-                            source: e,
-                            context: "Fail to read client udp data to proxy.",
-                        })?;
-                    let (_, dst_addr, client_udp_data) =
-                        parse_udp_request(&client_udp_socks5_packet).await?;
-                    let proxy_connection =
-                        fetch_proxy_connection()
-                            .await
-                            .map_err(|e| SocksServerError::Io {
-                                source: std::io::Error::other(format!(
-                                    "Fail to build proxy connection: {e:?}"
-                                )),
-                                context: "Fail to build proxy connection.",
-                            })?;
-
-                    let destination_address = convert_address(&dst_addr);
-                    let mut proxy_connection = proxy_connection
-                        .setup_destination(destination_address, DestinationType::Udp)
-                        .await
-                        .map_err(|e| SocksServerError::Io {
-                            source: std::io::Error::other(format!(
-                                "Fail to do setup destination with proxy connection: {e:?}"
-                            )),
-                            context: "Fail to do setup destination with proxy connection.",
-                        })?;
-                    proxy_connection.write(client_udp_data).await.map_err(|e| {
-                        SocksServerError::Io {
-                            source: e,
-                            context: "Fail to write client udp data to proxy.",
-                        }
-                    })?;
-                    let mut proxy_udp_data_buf = vec![0u8; 8192];
-                    proxy_connection
-                        .read(&mut proxy_udp_data_buf)
-                        .await
-                        .map_err(|e| SocksServerError::Io {
-                            source: e,
-                            context: "Fail to read proxy udp data.",
-                        })?;
-                    client_udp_socket
-                        .send(&proxy_udp_data_buf)
-                        .await
-                        .map_err(|e| SocksServerError::Io {
-                            source: e,
-                            context: "Fail to write proxy udp data to client.",
-                        })?;
-                    Ok(())
+                    udp::relay_socks5_udp(
+                        client_udp_socket,
+                        agent_username,
+                        control_client_ip,
+                        udp_client_addr,
+                    )
+                    .await
+                    .map_err(|e| SocksServerError::Io {
+                        source: std::io::Error::other(format!("{e}")),
+                        context: "Fail to relay udp datagrams.",
+                    })
                 },
             )
             .await?;
@@ -140,3 +267,50 @@ pub async fn process_socks5_tunnel(server_state: ServerState) -> Result<(), Erro
     }
     Ok(())
 }
+#[test]
+fn test() {
+    use SetupDestinationFailure as Failure;
+    let destination_address = UnifiedAddress::from(SocketAddr::from(([127, 0, 0, 1], 80)));
+    // The failure of destination setup is mapped to the socks5 reply
+    for (failure, reply) in [
+        (Failure::DnsResolution, ReplyError::HostUnreachable),
+        (Failure::ConnectionRefused, ReplyError::ConnectionRefused),
+        (Failure::Timeout, ReplyError::ConnectionTimeout),
+        (Failure::Unreachable, ReplyError::NetworkUnreachable),
+        (Failure::AclDenied, ReplyError::ConnectionNotAllowed),
+        (Failure::UserExpired, ReplyError::ConnectionNotAllowed),
+        (Failure::QuotaExceeded, ReplyError::ConnectionNotAllowed),
+        (Failure::TooManyStreams, ReplyError::GeneralFailure),
+        (Failure::Other, ReplyError::GeneralFailure),
+    ] {
+        let error =
+            Error::Common(CommonError::SetupDestination(destination_address.clone(), failure));
+        assert_eq!(socks5_reply_error(&error).as_u8(), reply.as_u8(), "{failure:?}");
+        let error = Error::Common(CommonError::MultiplexingRefused(failure));
+        assert_eq!(socks5_reply_error(&error).as_u8(), reply.as_u8(), "{failure:?}");
+    }
+    for (error, reply) in [
+        (
+            Error::Common(CommonError::HandshakeRejected(HandshakeRejection::UserExpired {
+                expired_time: 0,
+            })),
+            ReplyError::ConnectionNotAllowed,
+        ),
+        (
+            Error::Common(CommonError::HandshakeRejected(HandshakeRejection::TooManySessions {
+                max_sessions: 1,
+            })),
+            ReplyError::ConnectionNotAllowed,
+        ),
+        (Error::RouteRejected(destination_address.clone()), ReplyError::ConnectionNotAllowed),
+        (Error::DirectConnectTimeout(10), ReplyError::ConnectionTimeout),
+        (Error::Io(ErrorKind::ConnectionRefused.into()), ReplyError::ConnectionRefused),
+        (Error::Io(ErrorKind::TimedOut.into()), ReplyError::ConnectionTimeout),
+        (Error::Io(ErrorKind::HostUnreachable.into()), ReplyError::HostUnreachable),
+        (Error::Io(ErrorKind::NetworkUnreachable.into()), ReplyError::NetworkUnreachable),
+        (Error::Io(ErrorKind::BrokenPipe.into()), ReplyError::GeneralFailure),
+        (Error::Common(CommonError::ConnectTimeout(10)), ReplyError::GeneralFailure),
+    ] {
+        assert_eq!(socks5_reply_error(&error).as_u8(), reply.as_u8(), "{error:?}");
+    }
+}
@@ -0,0 +1,257 @@
+use crate::config::get_config;
+use crate::error::Error;
+use crate::route::{Route, get_router};
+use crate::selector::ProxyServerLease;
+use crate::tunnel::fetch_proxy_connection;
+use crate::tunnel::socks5::convert_address;
+use common::proxy::udp::{UdpDatagram, UdpRelaySender};
+use fast_socks5::server::SocksServerError;
+use fast_socks5::util::target_addr::TargetAddr;
+use fast_socks5::{new_udp_header, parse_udp_request};
+use protocol::UnifiedAddress;
+use std::collections::HashMap;
+use std::future::pending;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{Sender, channel};
+use tokio::time::sleep;
+use tracing::{debug, error};
+/// The datagrams from proxy can wait before sent to the client
+const PROXY_DATAGRAM_QUEUE_SIZE: usize = 256;
+/// The udp relay with the proxy for a route
+struct ProxyUdpRelay {
+    sender: UdpRelaySender,
+    /// Keep the proxy server counted as active while the relay alive
+    _server_lease: ProxyServerLease,
+}
+/// Relay the datagrams of the socks5 udp association, each datagram goes to
+/// the destination directly or through the proxy by the route rules. The
+/// datagrams to the same proxy server group share one udp relay, and the
+/// association is closed when no datagram in both directions for a while.
+pub async fn relay_socks5_udp(
+    client_udp_socket: UdpSocket,
+    agent_username: &str,
+    control_client_ip: IpAddr,
+    udp_client_addr: UnifiedAddress,
+) -> Result<(), Error> {
+    let udp_idle_timeout = get_config().udp_idle_timeout();
+    // The client address is fixed by the first datagram from the client,
+    // the datagrams from other addresses are dropped.
+    let mut client_addr: Option<SocketAddr> = None;
+    let mut direct_udp_socket: Option<UdpSocket> = None;
+    let mut proxy_udp_relays = HashMap::<Option<String>, ProxyUdpRelay>::new();
+    let (proxy_datagram_sender, mut proxy_datagram_receiver) =
+        channel::<UdpDatagram>(PROXY_DATAGRAM_QUEUE_SIZE);
+    let mut client_udp_data = vec![0u8; 65536];
+    let mut direct_udp_data = vec![0u8; 65536];
+    loop {
+        tokio::select! {
+            received = client_udp_socket.recv_from(&mut client_udp_data) => {
+                let (size, src_addr) = received?;
+                if !is_from_client(src_addr, control_client_ip, &udp_client_addr)
+                    || *client_addr.get_or_insert(src_addr) != src_addr
+                {
+                    debug!("Drop udp datagram from unknown client [{src_addr}]");
+                    continue;
+                }
+                let (frag, dst_addr, payload) =
+                    match parse_udp_request(&client_udp_data[..size]).await {
+                        Ok(udp_request) => udp_request,
+                        Err(e) => {
+                            error!("Fail to parse udp datagram from client [{src_addr}]: {e:?}");
+                            continue;
+                        }
+                    };
+                // The fragmentation is optional to implement
+                if frag != 0 {
+                    debug!("Drop fragmented udp datagram from client [{src_addr}]");
+                    continue;
+                }
+                let destination_address = convert_address(&dst_addr);
+                let proxy_server_group = match get_router().route(&destination_address) {
+                    Route::Reject => {
+                        debug!("Drop udp datagram to [{destination_address}] rejected by route rules");
+                        continue;
+                    }
+                    Route::Direct => {
+                        let direct_udp_socket = match &direct_udp_socket {
+                            Some(direct_udp_socket) => direct_udp_socket,
+                            None => direct_udp_socket
+                                .insert(UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?),
+                        };
+                        let sent = match &dst_addr {
+                            TargetAddr::Ip(socket_addr) => {
+                                direct_udp_socket.send_to(payload, socket_addr).await
+                            }
+                            TargetAddr::Domain(host, port) => {
+                                direct_udp_socket.send_to(payload, (host.as_str(), *port)).await
+                            }
+                        };
+                        if let Err(e) = sent {
+                            error!("Fail to send udp datagram to [{destination_address}] directly: {e:?}");
+                        }
+                        continue;
+                    }
+                    Route::Proxy(proxy_server_group) => proxy_server_group,
+                };
+                let proxy_udp_relay = match proxy_udp_relays.get(&proxy_server_group) {
+                    Some(proxy_udp_relay) if !proxy_udp_relay.sender.is_closed() => proxy_udp_relay,
+                    _ => match create_proxy_udp_relay(
+                        agent_username,
+                        proxy_server_group.as_deref(),
+                        udp_client_addr.clone(),
+                        proxy_datagram_sender.clone(),
+                    )
+                    .await
+                    {
+                        Ok(proxy_udp_relay) => proxy_udp_relays
+                            .entry(proxy_server_group)
+                            .insert_entry(proxy_udp_relay)
+                            .into_mut(),
+                        Err(e) => {
+                            error!("Fail to create udp relay with proxy: {e:?}");
+                            continue;
+                        }
+                    },
+                };
+                if let Err(e) = proxy_udp_relay.sender.send(UdpDatagram {
+                    src_addr: src_addr.into(),
+                    dst_addr: destination_address,
+                    payload: payload.to_vec(),
+                }) {
+                    error!("Fail to send udp datagram to proxy: {e:?}");
+                }
+            }
+            received = recv_direct(&direct_udp_socket, &mut direct_udp_data) => {
+                let (size, dst_addr) = received?;
+                send_to_client(
+                    &client_udp_socket,
+                    client_addr,
+                    TargetAddr::Ip(dst_addr),
+                    &direct_udp_data[..size],
+                )
+                .await?;
+            }
+            Some(UdpDatagram { src_addr, payload, .. }) = proxy_datagram_receiver.recv() => {
+                let src_addr = match src_addr {
+                    UnifiedAddress::SocketAddress(socket_addr) => TargetAddr::Ip(socket_addr),
+                    UnifiedAddress::Domain { host, port } => TargetAddr::Domain(host, port),
+                };
+                send_to_client(&client_udp_socket, client_addr, src_addr, &payload).await?;
+            }
+            _ = sleep(Duration::from_secs(udp_idle_timeout)) => {
+                return Err(Error::UdpIdleTimeout(udp_idle_timeout));
+            }
+        }
+    }
+}
+/// The datagrams are only accepted from the ip of the control connection,
+/// the address in the UDP ASSOCIATE request restricts the source further
+/// unless its ip or port is zero.
+fn is_from_client(
+    src_addr: SocketAddr,
+    control_client_ip: IpAddr,
+    udp_client_addr: &UnifiedAddress,
+) -> bool {
+    if src_addr.ip().to_canonical() != control_client_ip.to_canonical() {
+        return false;
+    }
+    match udp_client_addr {
+        UnifiedAddress::SocketAddress(socket_addr) => {
+            (socket_addr.ip().is_unspecified()
+                || socket_addr.ip().to_canonical() == src_addr.ip().to_canonical())
+                && (socket_addr.port() == 0 || socket_addr.port() == src_addr.port())
+        }
+        UnifiedAddress::Domain { port, .. } => *port == 0 || *port == src_addr.port(),
+    }
+}
+/// Create the udp relay with proxy, the datagrams from
+/// proxy are delivered to the sender.
+async fn create_proxy_udp_relay(
+    agent_username: &str,
+    proxy_server_group: Option<&str>,
+    udp_client_addr: UnifiedAddress,
+    proxy_datagram_sender: Sender<UdpDatagram>,
+) -> Result<ProxyUdpRelay, Error> {
+    let (proxy_connection, proxy_server) =
+        fetch_proxy_connection(agent_username, proxy_server_group).await?;
+    let server_lease = proxy_server.lease();
+    debug!(
+        "Create udp relay with proxy server [{}] for agent user [{agent_username}]",
+        server_lease.address()
+    );
+    let (sender, mut receiver) = proxy_connection
+        .setup_udp_relay(udp_client_addr)
+        .await?
+        .split();
+    tokio::spawn(async move {
+        while let Some(datagram) = receiver.recv().await {
+            if proxy_datagram_sender.send(datagram).await.is_err() {
+                break;
+            }
+        }
+    });
+    Ok(ProxyUdpRelay {
+        sender,
+        _server_lease: server_lease,
+    })
+}
+/// Receive the datagram from the destinations connected directly,
+/// wait forever when no datagram sent directly yet.
+async fn recv_direct(
+    direct_udp_socket: &Option<UdpSocket>,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr)> {
+    match direct_udp_socket {
+        Some(direct_udp_socket) => direct_udp_socket.recv_from(buf).await,
+        None => pending().await,
+    }
+}
+/// Send the datagram to client with the socks5 udp header,
+/// the header carries the address of the destination.
+async fn send_to_client(
+    client_udp_socket: &UdpSocket,
+    client_addr: Option<SocketAddr>,
+    src_addr: TargetAddr,
+    payload: &[u8],
+) -> Result<(), Error> {
+    let Some(client_addr) = client_addr else {
+        return Ok(());
+    };
+    let mut client_udp_packet = new_udp_header(src_addr).map_err(SocksServerError::from)?;
+    client_udp_packet.extend_from_slice(payload);
+    client_udp_socket
+        .send_to(&client_udp_packet, client_addr)
+        .await?;
+    Ok(())
+}
+#[test]
+fn test() {
+    let control_client_ip = IpAddr::from([192, 168, 1, 2]);
+    let src_addr = SocketAddr::from(([192, 168, 1, 2], 5000));
+    let any_addr = UnifiedAddress::from(SocketAddr::from(([0, 0, 0, 0], 0)));
+    assert!(is_from_client(src_addr, control_client_ip, &any_addr));
+    // Other hosts can not hijack the association
+    let other_addr = SocketAddr::from(([192, 168, 1, 3], 5000));
+    assert!(!is_from_client(other_addr, control_client_ip, &any_addr));
+    // The ip mapped into ipv6 is the same client
+    let mapped_ip = IpAddr::from(Ipv4Addr::new(192, 168, 1, 2).to_ipv6_mapped());
+    assert!(is_from_client(src_addr, mapped_ip, &any_addr));
+    // The port and ip in the request are checked when given
+    let port_addr = UnifiedAddress::from(SocketAddr::from(([0, 0, 0, 0], 5001)));
+    assert!(!is_from_client(src_addr, control_client_ip, &port_addr));
+    assert!(is_from_client(
+        SocketAddr::from(([192, 168, 1, 2], 5001)),
+        control_client_ip,
+        &port_addr
+    ));
+    let ip_addr = UnifiedAddress::from(SocketAddr::from(([192, 168, 1, 3], 0)));
+    assert!(!is_from_client(src_addr, control_client_ip, &ip_addr));
+    let domain_addr = UnifiedAddress::Domain {
+        host: "client".to_owned(),
+        port: 5000,
+    };
+    assert!(is_from_client(src_addr, control_client_ip, &domain_addr));
+    assert!(!is_from_client(other_addr, control_client_ip, &domain_addr));
+}
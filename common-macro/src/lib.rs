@@ -88,3 +88,41 @@ pub fn derive_with_fs_user_repo_config(input: TokenStream) -> TokenStream {
     }
         .into()
 }
+#[proc_macro_derive(SqliteUserRepoConfig)]
+pub fn derive_with_sqlite_user_repo_config(input: TokenStream) -> TokenStream {
+    let derive_input = syn::parse_macro_input!(input as syn::DeriveInput);
+    let struct_ident = derive_input.ident;
+    quote! {
+        impl common::WithSqliteUserRepoConfig for #struct_ident {
+            fn user_repo_database(&self) -> &Path {
+                &self.user_repo_database
+            }
+        }
+    }
+        .into()
+}
+#[proc_macro_derive(HttpUserRepoConfig)]
+pub fn derive_with_http_user_repo_config(input: TokenStream) -> TokenStream {
+    let derive_input = syn::parse_macro_input!(input as syn::DeriveInput);
+    let struct_ident = derive_input.ident;
+    quote! {
+        impl common::WithHttpUserRepoConfig for #struct_ident {
+            fn user_repo_url(&self) -> &str {
+                &self.user_repo_url
+            }
+            fn user_repo_private_key_file(&self) -> &Path {
+                &self.user_repo_private_key_file
+            }
+            fn user_repo_snapshot_file(&self) -> &Path {
+                &self.user_repo_snapshot_file
+            }
+            fn user_repo_invalidation_address(&self) -> Option<SocketAddr> {
+                self.user_repo_invalidation_address
+            }
+            fn user_repo_invalidation_secret(&self) -> Option<&str> {
+                self.user_repo_invalidation_secret.as_deref()
+            }
+        }
+    }
+        .into()
+}
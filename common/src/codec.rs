@@ -1,5 +1,8 @@
 use crate::error::Error;
-use crypto::{decrypt_with_aes, decrypt_with_blowfish, encrypt_with_aes, encrypt_with_blowfish};
+use crypto::{
+    decrypt_with_aes, decrypt_with_aes_gcm, decrypt_with_blowfish, decrypt_with_chacha20_poly1305,
+    encrypt_with_aes, encrypt_with_aes_gcm, encrypt_with_blowfish, encrypt_with_chacha20_poly1305,
+};
 use protocol::Encryption;
 use std::sync::Arc;
 use tokio_util::bytes::{Bytes, BytesMut};
@@ -8,6 +11,11 @@ use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
 pub struct SecureLengthDelimitedCodec {
     decoder_encryption: Arc<Encryption>,
     encoder_encryption: Arc<Encryption>,
+    /// The nonce of the next frame to decode, AEAD cipher
+    /// will fail on the frame which is reordered or replayed.
+    decoder_nonce: u64,
+    /// The nonce of the next frame to encode
+    encoder_nonce: u64,
     length_delimited: LengthDelimitedCodec,
 }
 
@@ -16,6 +24,8 @@ impl SecureLengthDelimitedCodec {
         Self {
             decoder_encryption,
             encoder_encryption,
+            decoder_nonce: 0,
+            encoder_nonce: 0,
             length_delimited: LengthDelimitedCodec::new(),
         }
     }
@@ -38,6 +48,21 @@ impl Decoder for SecureLengthDelimitedCodec {
                     let raw_bytes = decrypt_with_blowfish(token, &decrypted_bytes)?;
                     Ok(Some(BytesMut::from(raw_bytes)))
                 }
+                Encryption::AesGcm(token) => {
+                    let raw_bytes =
+                        decrypt_with_aes_gcm(token, self.decoder_nonce, &decrypted_bytes)?;
+                    self.decoder_nonce += 1;
+                    Ok(Some(BytesMut::from(raw_bytes)))
+                }
+                Encryption::ChaCha20Poly1305(token) => {
+                    let raw_bytes = decrypt_with_chacha20_poly1305(
+                        token,
+                        self.decoder_nonce,
+                        &decrypted_bytes,
+                    )?;
+                    self.decoder_nonce += 1;
+                    Ok(Some(BytesMut::from(raw_bytes)))
+                }
             },
         }
     }
@@ -58,6 +83,17 @@ impl Encoder<&[u8]> for SecureLengthDelimitedCodec {
                 let encrypted_bytes = encrypt_with_blowfish(token, item)?;
                 Ok(self.length_delimited.encode(encrypted_bytes, dst)?)
             }
+            Encryption::AesGcm(token) => {
+                let encrypted_bytes = encrypt_with_aes_gcm(token, self.encoder_nonce, item)?;
+                self.encoder_nonce += 1;
+                Ok(self.length_delimited.encode(encrypted_bytes, dst)?)
+            }
+            Encryption::ChaCha20Poly1305(token) => {
+                let encrypted_bytes =
+                    encrypt_with_chacha20_poly1305(token, self.encoder_nonce, item)?;
+                self.encoder_nonce += 1;
+                Ok(self.length_delimited.encode(encrypted_bytes, dst)?)
+            }
         }
     }
 }
@@ -19,6 +19,8 @@ pub trait WithLogConfig {
     fn max_log_level(&self) -> &str;
 }
 pub trait WithUserRepositoryConfig {
+    /// The interval to reload all the users, the file system user repository
+    /// only polls when it can not watch the changes of the directory.
     fn refresh_interval_sec(&self) -> u64;
 }
 pub trait WithUsernameConfig {
@@ -30,3 +32,21 @@ pub trait WithFileSystemUserRepoConfig: WithUserRepositoryConfig {
     fn private_key_file_name(&self) -> &str;
     fn user_info_file_name(&self) -> &str;
 }
+pub trait WithSqliteUserRepoConfig: WithUserRepositoryConfig {
+    /// The sqlite database file of the users
+    fn user_repo_database(&self) -> &Path;
+}
+pub trait WithHttpUserRepoConfig: WithUserRepositoryConfig {
+    /// The url to fetch the users from
+    fn user_repo_url(&self) -> &str;
+    /// The private key used together with the public key of each user
+    fn user_repo_private_key_file(&self) -> &Path;
+    /// The file to keep the last users fetched, used when the url is down
+    fn user_repo_snapshot_file(&self) -> &Path;
+    /// The address to receive the push invalidation, the users
+    /// are fetched again once the address is posted
+    fn user_repo_invalidation_address(&self) -> Option<SocketAddr>;
+    /// The secret the push invalidation must carry, the invalidation
+    /// address must be a loopback one when no secret configured
+    fn user_repo_invalidation_secret(&self) -> Option<&str>;
+}
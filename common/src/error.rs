@@ -1,12 +1,33 @@
 use crypto::Error as CryptoError;
-use protocol::UnifiedAddress;
+use protocol::{HandshakeRejection, SetupDestinationFailure, UnifiedAddress};
 use thiserror::Error;
+use std::net::SocketAddr;
 use tracing::metadata::ParseLevelError;
 #[derive(Error, Debug)]
 pub enum Error {
     #[error(transparent)]
     Io(#[from] std::io::Error),
     #[error(transparent)]
+    Toml(#[from] toml::de::Error),
+    #[error(transparent)]
+    TomlSerialize(#[from] toml::ser::Error),
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("Invalid user info: [{0}]")]
+    InvalidUserInfo(String),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Hyper(#[from] hyper::Error),
+    #[error(transparent)]
+    HttpClient(#[from] hyper_util::client::legacy::Error),
+    #[error(transparent)]
+    InvalidUri(#[from] hyper::http::uri::InvalidUri),
+    #[error("Fail to fetch users from [{0}]: {1}")]
+    UserRepoFetch(String, String),
+    #[error("User repository invalidation address is not loopback but no secret: [{0}]")]
+    UserRepoInvalidationUnprotected(SocketAddr),
+    #[error(transparent)]
     ParseLevel(#[from] ParseLevelError),
     #[error(transparent)]
     Crypto(#[from] CryptoError),
@@ -14,10 +35,12 @@ pub enum Error {
     UserNotExist(String),
     #[error("User rsa crypto not exist: [{0}]")]
     UserRsaCryptoNotExist(String),
+    #[error("No active rsa key of user [{0}] match the handshake signature")]
+    UserRsaKeyNotMatch(String),
     #[error("Connection exhausted: [{0}]")]
     ConnectionExhausted(String),
-    #[error("Fail to setup destination: [{0}]")]
-    SetupDestination(UnifiedAddress),
+    #[error("Fail to setup destination: [{0}] because of [{1:?}]")]
+    SetupDestination(UnifiedAddress, SetupDestinationFailure),
     #[error(transparent)]
     Encode(#[from] bincode::error::EncodeError),
     #[error(transparent)]
@@ -26,6 +49,24 @@ pub enum Error {
     ConnectTimeout(u64),
     #[error("Lock error: [{0}]")]
     Lock(String),
+    #[error("Handshake rejected: [{0:?}]")]
+    HandshakeRejected(HandshakeRejection),
+    #[error("Unsupported protocol version of proxy: [{0}]")]
+    UnsupportedProxyVersion(u16),
+    #[error("Multiplexing not supported by the proxy")]
+    MultiplexingNotSupported,
+    #[error("Multiplexed session refused by the proxy: [{0:?}]")]
+    MultiplexingRefused(SetupDestinationFailure),
+    #[error("Multiplexed session closed")]
+    MuxSessionClosed,
+    #[error("Remote send more data than the window on multiplexed stream: [{0}]")]
+    MuxFlowControlViolated(u32),
+    #[error("Remote open the multiplexed stream with the id in use: [{0}]")]
+    MuxStreamIdReused(u32),
+    #[error("Udp relay closed")]
+    UdpRelayClosed,
+    #[error("Unexpected setup destination message from proxy: [{0}]")]
+    UnexpectedSetupDestination(String),
 }
 impl From<Error> for std::io::Error {
     fn from(value: Error) -> Self {
@@ -0,0 +1,137 @@
+use crate::Error;
+use bincode::Encode;
+use crypto::{RsaCrypto, X25519KeyExchange};
+use protocol::{Capabilities, CipherSuite, Encryption, HandshakeRejection};
+use std::sync::Arc;
+const HANDSHAKE_KEY_LENGTH: usize = 32;
+const CLIENT_HANDSHAKE_KEY_INFO: &[u8] = b"ppaass client handshake key";
+const SERVER_HANDSHAKE_KEY_INFO: &[u8] = b"ppaass server handshake key";
+const CLIENT_SIGNATURE_CONTEXT: &[u8] = b"ppaass client handshake signature";
+const SERVER_SIGNATURE_CONTEXT: &[u8] = b"ppaass server handshake signature";
+const SERVER_REJECTION_SIGNATURE_CONTEXT: &[u8] = b"ppaass server rejection signature";
+/// The ephemeral public keys of both side, all the
+/// handshake keys and signatures are bound to it.
+pub struct HandshakeTranscript {
+    pub client_public_key: Vec<u8>,
+    pub server_public_key: Vec<u8>,
+}
+impl HandshakeTranscript {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut result =
+            Vec::with_capacity(self.client_public_key.len() + self.server_public_key.len());
+        result.extend_from_slice(&self.client_public_key);
+        result.extend_from_slice(&self.server_public_key);
+        result
+    }
+    fn signature_payload<T: Encode>(
+        &self,
+        context: &[u8],
+        username: &str,
+        content: &T,
+    ) -> Result<Vec<u8>, Error> {
+        let mut result = context.to_vec();
+        result.extend_from_slice(&self.to_bytes());
+        result.extend_from_slice(username.as_bytes());
+        result.extend(bincode::encode_to_vec(content, bincode::config::standard())?);
+        Ok(result)
+    }
+}
+/// The encryption used to protect the handshake
+/// packets, each direction has its own key.
+pub struct HandshakeEncryption {
+    /// Used to protect the packet from client to server
+    pub client_encryption: Arc<Encryption>,
+    /// Used to protect the packet from server to client
+    pub server_encryption: Arc<Encryption>,
+}
+/// Derive the handshake encryption with the ephemeral key exchange,
+/// the `peer_public_key` is the public key from the other side.
+pub fn derive_handshake_encryption(
+    key_exchange: X25519KeyExchange,
+    peer_public_key: &[u8],
+    transcript: &HandshakeTranscript,
+) -> Result<HandshakeEncryption, Error> {
+    let shared_key = key_exchange.agree(peer_public_key, &transcript.to_bytes())?;
+    let client_token = shared_key.expand(CLIENT_HANDSHAKE_KEY_INFO, HANDSHAKE_KEY_LENGTH)?;
+    let server_token = shared_key.expand(SERVER_HANDSHAKE_KEY_INFO, HANDSHAKE_KEY_LENGTH)?;
+    Ok(HandshakeEncryption {
+        client_encryption: Arc::new(Encryption::AesGcm(client_token)),
+        server_encryption: Arc::new(Encryption::AesGcm(server_token)),
+    })
+}
+/// Sign the client handshake with the agent private key, the
+/// content is everything in the handshake except the signature
+pub fn sign_client_handshake<T: Encode>(
+    transcript: &HandshakeTranscript,
+    username: &str,
+    content: &T,
+    rsa_crypto: &RsaCrypto,
+) -> Result<Vec<u8>, Error> {
+    let payload = transcript.signature_payload(CLIENT_SIGNATURE_CONTEXT, username, content)?;
+    Ok(rsa_crypto.sign(&payload)?)
+}
+/// Verify the client handshake with the agent public key
+pub fn verify_client_handshake<T: Encode>(
+    transcript: &HandshakeTranscript,
+    username: &str,
+    content: &T,
+    signature: &[u8],
+    rsa_crypto: &RsaCrypto,
+) -> Result<(), Error> {
+    let payload = transcript.signature_payload(CLIENT_SIGNATURE_CONTEXT, username, content)?;
+    Ok(rsa_crypto.verify(&payload, signature)?)
+}
+/// Sign the server handshake with the proxy private key, the
+/// content is everything in the handshake except the signature
+pub fn sign_server_handshake<T: Encode>(
+    transcript: &HandshakeTranscript,
+    username: &str,
+    content: &T,
+    rsa_crypto: &RsaCrypto,
+) -> Result<Vec<u8>, Error> {
+    let payload = transcript.signature_payload(SERVER_SIGNATURE_CONTEXT, username, content)?;
+    Ok(rsa_crypto.sign(&payload)?)
+}
+/// Verify the server handshake with the proxy public key
+pub fn verify_server_handshake<T: Encode>(
+    transcript: &HandshakeTranscript,
+    username: &str,
+    content: &T,
+    signature: &[u8],
+    rsa_crypto: &RsaCrypto,
+) -> Result<(), Error> {
+    let payload = transcript.signature_payload(SERVER_SIGNATURE_CONTEXT, username, content)?;
+    Ok(rsa_crypto.verify(&payload, signature)?)
+}
+/// Sign the rejection of client handshake with the proxy private key
+pub fn sign_server_rejection(
+    transcript: &HandshakeTranscript,
+    username: &str,
+    rejection: &HandshakeRejection,
+    rsa_crypto: &RsaCrypto,
+) -> Result<Vec<u8>, Error> {
+    let payload =
+        transcript.signature_payload(SERVER_REJECTION_SIGNATURE_CONTEXT, username, rejection)?;
+    Ok(rsa_crypto.sign(&payload)?)
+}
+/// Verify the rejection of client handshake with the proxy public key
+pub fn verify_server_rejection(
+    transcript: &HandshakeTranscript,
+    username: &str,
+    rejection: &HandshakeRejection,
+    signature: &[u8],
+    rsa_crypto: &RsaCrypto,
+) -> Result<(), Error> {
+    let payload =
+        transcript.signature_payload(SERVER_REJECTION_SIGNATURE_CONTEXT, username, rejection)?;
+    Ok(rsa_crypto.verify(&payload, signature)?)
+}
+/// The capabilities supported by current build
+pub fn supported_capabilities() -> Capabilities {
+    Capabilities {
+        ciphers: vec![CipherSuite::AesGcm, CipherSuite::ChaCha20Poly1305],
+        compression: false,
+        multiplexing: true,
+        udp: true,
+    }
+}
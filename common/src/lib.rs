@@ -1,78 +1,42 @@
 mod codec;
 pub mod config;
 mod error;
+mod handshake;
+pub mod limit;
 mod log;
 pub mod proxy;
+pub mod rule;
 mod runtime;
 mod server;
 pub mod user;
 pub use codec::SecureLengthDelimitedCodec;
 pub use config::WithFileSystemUserRepoConfig;
+pub use config::WithHttpUserRepoConfig;
 pub use config::WithLogConfig;
 pub use config::WithServerConfig;
+pub use config::WithSqliteUserRepoConfig;
 pub use config::WithUserRepositoryConfig;
 pub use config::WithUsernameConfig;
-use crypto::{RsaCrypto, generate_aes_encryption_token, generate_blowfish_encryption_token};
+use crypto::{generate_aes_gcm_encryption_token, generate_chacha20_poly1305_encryption_token};
 pub use error::Error;
+pub use handshake::*;
 pub use log::init_log;
-use protocol::Encryption;
-use rand::random;
+use protocol::{CipherSuite, Encryption};
+use rand::seq::IndexedRandom;
 pub use runtime::build_server_runtime;
 pub use server::ServerGuard;
 pub use server::ServerState;
 pub use server::start_server;
-use std::borrow::Cow;
-use std::sync::Arc;
-use std::sync::LazyLock;
-static HANDSHAKE_ENCRYPTION: LazyLock<Arc<Encryption>> = LazyLock::new(|| {
-    Arc::new(Encryption::Blowfish({
-        b"1212398347384737434783748347387438743742982332672763272320119203".to_vec()
-    }))
-});
-pub fn get_handshake_encryption() -> Arc<Encryption> {
-    Arc::clone(&HANDSHAKE_ENCRYPTION)
-}
-/// Randomly generate a raw encryption
-#[inline(always)]
-pub fn random_generate_encryption() -> Encryption {
-    let random_number = random::<u64>();
-    if random_number % 2 == 0 {
-        Encryption::Aes(generate_aes_encryption_token())
-    } else {
-        Encryption::Blowfish(generate_blowfish_encryption_token())
-    }
-}
-#[inline(always)]
-pub fn rsa_encrypt_encryption<'a>(
-    raw_encryption: &'a Encryption,
-    rsa_crypto: &RsaCrypto,
-) -> Result<Cow<'a, Encryption>, Error> {
-    match raw_encryption {
-        Encryption::Plain => Ok(Cow::Borrowed(raw_encryption)),
-        Encryption::Aes(token) => {
-            let encrypted_token = rsa_crypto.encrypt(token)?;
-            Ok(Cow::Owned(Encryption::Aes(encrypted_token)))
-        }
-        Encryption::Blowfish(token) => {
-            let encrypted_token = rsa_crypto.encrypt(token)?;
-            Ok(Cow::Owned(Encryption::Blowfish(encrypted_token)))
-        }
-    }
-}
+/// Randomly generate a raw encryption among the negotiated cipher
+/// suites, AES-GCM is used when no cipher suite is given.
 #[inline(always)]
-pub fn rsa_decrypt_encryption(
-    encrypted_encryption: Encryption,
-    rsa_crypto: &RsaCrypto,
-) -> Result<Encryption, Error> {
-    match encrypted_encryption {
-        Encryption::Plain => Ok(encrypted_encryption),
-        Encryption::Aes(token) => {
-            let decrypted_token = rsa_crypto.decrypt(&token)?;
-            Ok(Encryption::Aes(decrypted_token))
+pub fn random_generate_encryption(cipher_suites: &[CipherSuite]) -> Encryption {
+    match cipher_suites.choose(&mut rand::rng()) {
+        Some(CipherSuite::ChaCha20Poly1305) => {
+            Encryption::ChaCha20Poly1305(generate_chacha20_poly1305_encryption_token())
         }
-        Encryption::Blowfish(token) => {
-            let decrypted_token = rsa_crypto.decrypt(&token)?;
-            Ok(Encryption::Blowfish(decrypted_token))
+        Some(CipherSuite::AesGcm) | None => {
+            Encryption::AesGcm(generate_aes_gcm_encryption_token())
         }
     }
 }
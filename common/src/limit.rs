@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, MutexGuard};
+/// Count the active connections of each key, such as the user or the client
+/// ip, so that one key can not take all the connections of the server.
+pub struct ConnectionLimiter<K>
+where
+    K: Eq + Hash + Clone,
+{
+    active_connections: Mutex<HashMap<K, usize>>,
+}
+impl<K> Default for ConnectionLimiter<K>
+where
+    K: Eq + Hash + Clone,
+{
+    fn default() -> Self {
+        Self {
+            active_connections: Mutex::new(HashMap::new()),
+        }
+    }
+}
+impl<K> ConnectionLimiter<K>
+where
+    K: Eq + Hash + Clone,
+{
+    fn lock_active_connections(&self) -> MutexGuard<'_, HashMap<K, usize>> {
+        self.active_connections
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    /// Take a permit for the connection of the key, `None` when the key
+    /// reach the max connections already. No limit when max is `None`.
+    /// The connection is counted until the permit dropped.
+    pub fn try_acquire(
+        self: &Arc<Self>,
+        key: K,
+        max_connections: Option<usize>,
+    ) -> Option<ConnectionPermit<K>> {
+        let mut active_connections = self.lock_active_connections();
+        let active = active_connections.entry(key.clone()).or_default();
+        if max_connections.is_some_and(|max_connections| *active >= max_connections) {
+            if *active == 0 {
+                active_connections.remove(&key);
+            }
+            return None;
+        }
+        *active += 1;
+        Some(ConnectionPermit {
+            limiter: self.clone(),
+            key,
+        })
+    }
+    /// The number of active connections of the key
+    pub fn active_connections(&self, key: &K) -> usize {
+        self.lock_active_connections()
+            .get(key)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+/// The connection counted in the limiter
+pub struct ConnectionPermit<K>
+where
+    K: Eq + Hash + Clone,
+{
+    limiter: Arc<ConnectionLimiter<K>>,
+    key: K,
+}
+impl<K> Drop for ConnectionPermit<K>
+where
+    K: Eq + Hash + Clone,
+{
+    fn drop(&mut self) {
+        let mut active_connections = self.limiter.lock_active_connections();
+        if let Some(active) = active_connections.get_mut(&self.key) {
+            *active -= 1;
+            if *active == 0 {
+                active_connections.remove(&self.key);
+            }
+        }
+    }
+}
+#[test]
+fn test() {
+    let limiter = Arc::new(ConnectionLimiter::<&str>::default());
+    let first = limiter.try_acquire("user1", Some(2));
+    let second = limiter.try_acquire("user1", Some(2));
+    assert!(first.is_some() && second.is_some());
+    assert!(limiter.try_acquire("user1", Some(2)).is_none());
+    // Other keys are not affected
+    assert!(limiter.try_acquire("user2", Some(2)).is_some());
+    drop(first);
+    assert_eq!(limiter.active_connections(&"user1"), 1);
+    assert!(limiter.try_acquire("user1", Some(2)).is_some());
+    drop(second);
+    assert_eq!(limiter.active_connections(&"user1"), 0);
+    assert!(limiter.try_acquire("user1", Some(0)).is_none());
+    assert!(limiter.try_acquire("user1", None).is_some());
+}
@@ -0,0 +1,428 @@
+pub mod mux;
+pub mod udp;
+use crate::proxy::mux::MuxSession;
+use crate::proxy::udp::UdpRelay;
+use crate::user::User;
+use crate::{
+    Error, HandshakeTranscript, SecureLengthDelimitedCodec, derive_handshake_encryption,
+    random_generate_encryption, sign_client_handshake, supported_capabilities,
+    verify_server_handshake, verify_server_rejection,
+};
+use chrono::DateTime;
+use bincode::config::Configuration;
+use crypto::X25519KeyExchange;
+use futures_util::{FutureExt, SinkExt, StreamExt};
+use protocol::{
+    Capabilities, ClientHandshake, ClientKeyExchange, ClientSetupDestination, Encryption,
+    HandshakeRejection, PROTOCOL_VERSION, ServerHandshake, ServerKeyExchange, ServerSetupDestination,
+    SetupDestinationFailure, UnifiedAddress,
+};
+use std::io::Error as StdIoError;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::pin;
+use tokio::time::timeout;
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::Framed;
+use tokio_util::io::{SinkWriter, StreamReader};
+use tracing::warn;
+pub type ProxyFramed = Framed<TcpStream, SecureLengthDelimitedCodec>;
+pub type ProxyFramedReaderWriter = SinkWriter<StreamReader<ProxyFramed, BytesMut>>;
+pub enum DestinationType {
+    Tcp,
+}
+pub struct Init;
+/// The proxy is listening for the inbound peer of bind
+pub struct BindListening {
+    proxy_framed: ProxyFramed,
+    peer_addr: UnifiedAddress,
+}
+/// The proxy connection.
+pub struct ProxyConnection<T> {
+    state: T,
+    /// The capabilities negotiated in handshake
+    capabilities: Capabilities,
+}
+impl<T> ProxyConnection<T> {
+    /// The capabilities negotiated with the proxy
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+}
+impl ProxyConnection<Init> {
+    /// Create a new proxy connection, the proxy addresses
+    /// are tried in order until one of them connected.
+    pub async fn new<U>(
+        user_info: Arc<U>,
+        proxy_addresses: &[SocketAddr],
+        connect_timeout: u64,
+    ) -> Result<ProxyConnection<ProxyFramed>, Error>
+    where
+        U: User + Send + Sync + 'static,
+    {
+        let mut proxy_stream = timeout(
+            Duration::from_secs(connect_timeout),
+            TcpStream::connect(proxy_addresses),
+        )
+            .await
+            .map_err(|_| Error::ConnectTimeout(connect_timeout))??;
+        let rsa_crypto = user_info.rsa_crypto().ok_or(Error::UserRsaCryptoNotExist(
+            user_info.username().to_owned(),
+        ))?;
+        let mut handshake_framed = Framed::new(
+            &mut proxy_stream,
+            SecureLengthDelimitedCodec::new(
+                Arc::new(Encryption::Plain),
+                Arc::new(Encryption::Plain),
+            ),
+        );
+        // Exchange the ephemeral keys, the handshake keys are derived from them
+        // so the handshake can not be decrypted even the rsa keys leaked.
+        let key_exchange = X25519KeyExchange::new();
+        let client_public_key = key_exchange.public_key();
+        let client_key_exchange_bytes = bincode::encode_to_vec(
+            ClientKeyExchange {
+                public_key: client_public_key.clone(),
+            },
+            bincode::config::standard(),
+        )?;
+        handshake_framed.send(&client_key_exchange_bytes).await?;
+        let server_key_exchange_bytes =
+            handshake_framed
+                .next()
+                .await
+                .ok_or(Error::ConnectionExhausted(format!(
+                    "Fail to read key exchange message from proxy: {}",
+                    handshake_framed.get_ref().peer_addr()?
+                )))??;
+        let (server_key_exchange, _) =
+            bincode::decode_from_slice::<ServerKeyExchange, Configuration>(
+                &server_key_exchange_bytes,
+                bincode::config::standard(),
+            )?;
+        let transcript = HandshakeTranscript {
+            client_public_key,
+            server_public_key: server_key_exchange.public_key,
+        };
+        let handshake_encryption = derive_handshake_encryption(
+            key_exchange,
+            &transcript.server_public_key,
+            &transcript,
+        )?;
+        *handshake_framed.codec_mut() = SecureLengthDelimitedCodec::new(
+            handshake_encryption.server_encryption,
+            handshake_encryption.client_encryption,
+        );
+        let agent_capabilities = supported_capabilities();
+        let agent_encryption = random_generate_encryption(&agent_capabilities.ciphers);
+        let client_handshake = ClientHandshake {
+            version: PROTOCOL_VERSION,
+            username: user_info.username().to_owned(),
+            signature: sign_client_handshake(
+                &transcript,
+                user_info.username(),
+                &(PROTOCOL_VERSION, &agent_capabilities, &agent_encryption),
+                rsa_crypto,
+            )?,
+            capabilities: agent_capabilities,
+            encryption: agent_encryption.clone(),
+        };
+        let client_handshake_bytes =
+            bincode::encode_to_vec(client_handshake, bincode::config::standard())?;
+        handshake_framed.send(&client_handshake_bytes).await?;
+        let proxy_handshake_bytes =
+            handshake_framed
+                .next()
+                .await
+                .ok_or(Error::ConnectionExhausted(format!(
+                    "Fail to read handshke message from proxy: {}",
+                    handshake_framed.get_ref().peer_addr()?
+                )))??;
+        let (proxy_handshake, _) = bincode::decode_from_slice::<ServerHandshake, Configuration>(
+            &proxy_handshake_bytes,
+            bincode::config::standard(),
+        )?;
+        let (proxy_version, capabilities, proxy_encryption, user_expired_time, proxy_signature) =
+            match proxy_handshake {
+                ServerHandshake::Success {
+                    version,
+                    capabilities,
+                    encryption,
+                    user_expired_time,
+                    signature,
+                } => (version, capabilities, encryption, user_expired_time, signature),
+                ServerHandshake::Reject {
+                    rejection,
+                    signature,
+                } => {
+                    // The proxy can not sign the unsupported version as it does not know
+                    // the user, it is no more harmful than a dropped connection.
+                    if !matches!(rejection, HandshakeRejection::UnsupportedVersion { .. }) {
+                        verify_server_rejection(
+                            &transcript,
+                            user_info.username(),
+                            &rejection,
+                            &signature,
+                            rsa_crypto,
+                        )?;
+                    }
+                    return Err(Error::HandshakeRejected(rejection));
+                }
+            };
+        // The signature proves the proxy owns the private key of this user,
+        // and nobody changed the key exchange or the negotiation in the middle.
+        verify_server_handshake(
+            &transcript,
+            user_info.username(),
+            &(
+                proxy_version,
+                &capabilities,
+                &proxy_encryption,
+                user_expired_time,
+            ),
+            &proxy_signature,
+            rsa_crypto,
+        )?;
+        if proxy_version != PROTOCOL_VERSION {
+            return Err(Error::UnsupportedProxyVersion(proxy_version));
+        }
+        if let Some(user_expired_time) = user_expired_time {
+            warn!(
+                "User [{}] will expire at {}",
+                user_info.username(),
+                DateTime::from_timestamp(user_expired_time, 0).unwrap_or_default()
+            );
+        }
+        let proxy_framed = Framed::new(
+            proxy_stream,
+            SecureLengthDelimitedCodec::new(Arc::new(proxy_encryption), Arc::new(agent_encryption)),
+        );
+        Ok(ProxyConnection {
+            state: proxy_framed,
+            capabilities: capabilities.intersect(&supported_capabilities()),
+        })
+    }
+}
+/// After handshake complete, the proxy connection can do
+/// setup destination
+impl ProxyConnection<ProxyFramed> {
+    /// Check if the connection still usable, the proxy send nothing
+    /// before setup destination, so any readable data or the end of
+    /// stream means the connection is broken.
+    pub fn is_alive(&self) -> bool {
+        if !self.state.read_buffer().is_empty() {
+            return false;
+        }
+        let mut peek_buf = [0u8; 1];
+        self.state
+            .get_ref()
+            .peek(&mut peek_buf)
+            .now_or_never()
+            .is_none()
+    }
+    /// Setup the destination, in this process
+    /// server side will build tcp connection with
+    /// the destination.
+    pub async fn setup_destination(
+        self,
+        destination_addr: UnifiedAddress,
+        destination_type: DestinationType,
+    ) -> Result<ProxyConnection<ProxyFramedReaderWriter>, Error> {
+        let mut proxy_framed = self.state;
+        let setup_destination = match destination_type {
+            DestinationType::Tcp => ClientSetupDestination::Tcp(destination_addr.clone()),
+        };
+        let setup_destination_bytes =
+            bincode::encode_to_vec(setup_destination, bincode::config::standard())?;
+        proxy_framed.send(&setup_destination_bytes).await?;
+        match read_server_setup_destination(&mut proxy_framed, &destination_addr).await? {
+            ServerSetupDestination::Success => Ok(ProxyConnection {
+                state: SinkWriter::new(StreamReader::new(proxy_framed)),
+                capabilities: self.capabilities,
+            }),
+            ServerSetupDestination::Fail(failure) => {
+                Err(Error::SetupDestination(destination_addr, failure))
+            }
+            unexpected => Err(Error::UnexpectedSetupDestination(format!(
+                "{unexpected:?}, destination address: {destination_addr}"
+            ))),
+        }
+    }
+    /// Setup the udp relay, the client address is where the client send
+    /// datagrams from. The datagrams to many destinations can be relayed
+    /// on it in both directions until the relay dropped.
+    pub async fn setup_udp_relay(self, client_addr: UnifiedAddress) -> Result<UdpRelay, Error> {
+        let mut proxy_framed = self.state;
+        let setup_destination_bytes = bincode::encode_to_vec(
+            ClientSetupDestination::Udp(client_addr.clone()),
+            bincode::config::standard(),
+        )?;
+        proxy_framed.send(&setup_destination_bytes).await?;
+        match read_server_setup_destination(&mut proxy_framed, &client_addr).await? {
+            ServerSetupDestination::Success => Ok(UdpRelay::new(proxy_framed)),
+            ServerSetupDestination::Fail(failure) => {
+                Err(Error::SetupDestination(client_addr, failure))
+            }
+            unexpected => Err(Error::UnexpectedSetupDestination(format!(
+                "{unexpected:?}, udp client address: {client_addr}"
+            ))),
+        }
+    }
+    /// Setup the bind destination, the proxy listen for the inbound
+    /// connection from the peer, and the listening address of the
+    /// proxy is returned so that the peer can connect to it.
+    pub async fn setup_bind(
+        self,
+        peer_addr: UnifiedAddress,
+    ) -> Result<(ProxyConnection<BindListening>, SocketAddr), Error> {
+        let mut proxy_framed = self.state;
+        let setup_destination_bytes = bincode::encode_to_vec(
+            ClientSetupDestination::Bind(peer_addr.clone()),
+            bincode::config::standard(),
+        )?;
+        proxy_framed.send(&setup_destination_bytes).await?;
+        match read_server_setup_destination(&mut proxy_framed, &peer_addr).await? {
+            ServerSetupDestination::Bound(bound_addr) => Ok((
+                ProxyConnection {
+                    state: BindListening {
+                        proxy_framed,
+                        peer_addr,
+                    },
+                    capabilities: self.capabilities,
+                },
+                bound_addr,
+            )),
+            ServerSetupDestination::Fail(failure) => {
+                Err(Error::SetupDestination(peer_addr, failure))
+            }
+            unexpected => Err(Error::UnexpectedSetupDestination(format!(
+                "{unexpected:?}, bind peer address: {peer_addr}"
+            ))),
+        }
+    }
+}
+/// The proxy is listening for the peer of bind
+impl ProxyConnection<BindListening> {
+    /// Wait for the peer connect to the listening address of the
+    /// proxy, then the connection can relay data with the peer.
+    pub async fn accept_peer(
+        self,
+    ) -> Result<(ProxyConnection<ProxyFramedReaderWriter>, SocketAddr), Error> {
+        let BindListening {
+            mut proxy_framed,
+            peer_addr,
+        } = self.state;
+        match read_server_setup_destination(&mut proxy_framed, &peer_addr).await? {
+            ServerSetupDestination::PeerConnected(peer_addr) => Ok((
+                ProxyConnection {
+                    state: SinkWriter::new(StreamReader::new(proxy_framed)),
+                    capabilities: self.capabilities,
+                },
+                peer_addr,
+            )),
+            ServerSetupDestination::Fail(failure) => {
+                Err(Error::SetupDestination(peer_addr, failure))
+            }
+            unexpected => Err(Error::UnexpectedSetupDestination(format!(
+                "{unexpected:?}, bind peer address: {peer_addr}"
+            ))),
+        }
+    }
+}
+/// Read the setup destination message from proxy
+async fn read_server_setup_destination(
+    proxy_framed: &mut ProxyFramed,
+    destination_addr: &UnifiedAddress,
+) -> Result<ServerSetupDestination, Error> {
+    let proxy_setup_destination_bytes = proxy_framed
+        .next()
+        .await
+        .ok_or(Error::ConnectionExhausted(format!("Fail to read setup destination connection message from proxy, destination address: {destination_addr:?}")))??;
+    let (proxy_setup_destination, _) =
+        bincode::decode_from_slice::<ServerSetupDestination, Configuration>(
+            &proxy_setup_destination_bytes,
+            bincode::config::standard(),
+        )?;
+    Ok(proxy_setup_destination)
+}
+/// After handshake complete, the proxy connection can
+/// also become a multiplexed session
+impl ProxyConnection<ProxyFramed> {
+    /// Turn the connection into multiplexed session, many
+    /// destinations can be setup on it at the same time.
+    pub async fn into_mux_session(self) -> Result<MuxSession, Error> {
+        if !self.capabilities.multiplexing {
+            return Err(Error::MultiplexingNotSupported);
+        }
+        let mut proxy_framed = self.state;
+        let setup_destination_bytes = bincode::encode_to_vec(
+            ClientSetupDestination::Multiplex,
+            bincode::config::standard(),
+        )?;
+        proxy_framed.send(&setup_destination_bytes).await?;
+        let proxy_setup_destination_bytes =
+            proxy_framed
+                .next()
+                .await
+                .ok_or(Error::ConnectionExhausted(
+                    "Fail to read setup multiplexing message from proxy".to_owned(),
+                ))??;
+        let (proxy_setup_destination, _) =
+            bincode::decode_from_slice::<ServerSetupDestination, Configuration>(
+                &proxy_setup_destination_bytes,
+                bincode::config::standard(),
+            )?;
+        match proxy_setup_destination {
+            ServerSetupDestination::Success => Ok(MuxSession::connect(proxy_framed)),
+            ServerSetupDestination::Fail(SetupDestinationFailure::Other) => {
+                Err(Error::MultiplexingNotSupported)
+            }
+            // The user can not use the proxy, such as expired or out of quota
+            ServerSetupDestination::Fail(failure) => Err(Error::MultiplexingRefused(failure)),
+            unexpected => Err(Error::UnexpectedSetupDestination(format!("{unexpected:?}"))),
+        }
+    }
+}
+/// After setup destinition on proxy connection success,
+/// the proxy connection will become reader & writer,
+/// and this is the reader part.
+impl AsyncRead for ProxyConnection<ProxyFramedReaderWriter> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let proxy_framed = &mut self.get_mut().state;
+        pin!(proxy_framed);
+        proxy_framed.poll_read(cx, buf)
+    }
+}
+/// After setup destinition on proxy connection success,
+/// the proxy connection will become reader & writer,
+/// and this is the writer part.
+impl AsyncWrite for ProxyConnection<ProxyFramedReaderWriter> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, StdIoError>> {
+        let proxy_framed = &mut self.get_mut().state;
+        pin!(proxy_framed);
+        proxy_framed.poll_write(cx, buf)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), StdIoError>> {
+        let proxy_framed = &mut self.get_mut().state;
+        pin!(proxy_framed);
+        proxy_framed.poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), StdIoError>> {
+        let proxy_framed = &mut self.get_mut().state;
+        pin!(proxy_framed);
+        proxy_framed.poll_shutdown(cx)
+    }
+}
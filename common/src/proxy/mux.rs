@@ -0,0 +1,621 @@
+use crate::proxy::DestinationType;
+use crate::{Error, SecureLengthDelimitedCodec};
+use bincode::config::Configuration;
+use futures_util::{Sink, StreamExt};
+use protocol::{
+    ClientSetupDestination, MuxFrame, MuxPayload, ServerSetupDestination, UnifiedAddress,
+};
+use std::collections::{HashMap, VecDeque};
+use std::future::poll_fn;
+use std::io::{Error as StdIoError, ErrorKind};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc::{
+    UnboundedReceiver, UnboundedSender, WeakUnboundedSender, unbounded_channel,
+};
+use tokio::sync::{Semaphore, oneshot};
+use tokio_util::bytes::{Bytes, BytesMut};
+use tokio_util::codec::Framed;
+use tokio_util::sync::PollSemaphore;
+use tracing::{debug, error};
+/// The bytes can be sent on a stream before the receiver consume them
+pub const MUX_STREAM_WINDOW: u32 = 256 * 1024;
+/// The receiver acknowledge the consumed bytes when it reach this size
+const MUX_WINDOW_UPDATE_THRESHOLD: u32 = MUX_STREAM_WINDOW / 4;
+/// The max data size of one frame
+const MUX_MAX_FRAME_DATA: usize = 16 * 1024;
+/// The message from the session handle and streams to the driver
+enum Outbound {
+    /// Send the frame to the remote side
+    Frame(MuxFrame),
+    /// Register a local opened stream then send the open frame
+    Open {
+        stream_id: u32,
+        entry: StreamEntry,
+        setup_destination: ClientSetupDestination,
+    },
+    /// The local stream is dropped
+    Release(u32),
+}
+/// The state of a stream kept by the driver
+struct StreamEntry {
+    /// Deliver the data from remote to the stream, dropped when remote close the stream
+    inbound: Option<UnboundedSender<Vec<u8>>>,
+    /// The send window of the stream
+    send_window: Arc<Semaphore>,
+    /// Mark the remote close the stream normally
+    remote_closed: Arc<AtomicBool>,
+    /// Notify the result of opening the stream
+    open_result: Option<oneshot::Sender<ServerSetupDestination>>,
+    /// The bytes the remote can still send, it grows when the
+    /// local stream acknowledges the consumed bytes.
+    receive_window: u32,
+}
+/// The local side of a stream
+struct StreamHalf {
+    entry: StreamEntry,
+    stream: MuxStream,
+}
+impl StreamHalf {
+    fn new(stream_id: u32, outbound: UnboundedSender<Outbound>) -> Self {
+        let (inbound_sender, inbound_receiver) = unbounded_channel();
+        let send_window = Arc::new(Semaphore::new(MUX_STREAM_WINDOW as usize));
+        let remote_closed = Arc::new(AtomicBool::new(false));
+        Self {
+            entry: StreamEntry {
+                inbound: Some(inbound_sender),
+                send_window: send_window.clone(),
+                remote_closed: remote_closed.clone(),
+                open_result: None,
+                receive_window: MUX_STREAM_WINDOW,
+            },
+            stream: MuxStream {
+                stream_id,
+                outbound,
+                inbound: inbound_receiver,
+                read_buffer: Bytes::new(),
+                send_window: PollSemaphore::new(send_window),
+                remote_closed,
+                consumed: 0,
+                write_closed: false,
+            },
+        }
+    }
+}
+/// The multiplexed session in agent side, many streams can
+/// be opened on one proxy connection.
+pub struct MuxSession {
+    outbound: UnboundedSender<Outbound>,
+    next_stream_id: AtomicU32,
+}
+impl MuxSession {
+    /// Start the session on the connection which complete
+    /// the handshake and multiplex setup.
+    pub fn connect<T>(framed: Framed<T, SecureLengthDelimitedCodec>) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let (outbound, outbound_receiver) = unbounded_channel();
+        tokio::spawn(drive(framed, outbound.downgrade(), outbound_receiver, None));
+        Self {
+            outbound,
+            next_stream_id: AtomicU32::new(1),
+        }
+    }
+    /// The session can not open stream any more
+    pub fn is_closed(&self) -> bool {
+        self.outbound.is_closed()
+    }
+    /// Open a stream and setup the destination of it
+    pub async fn open(
+        &self,
+        destination_addr: UnifiedAddress,
+        destination_type: DestinationType,
+    ) -> Result<MuxStream, Error> {
+        let stream_id = self.next_stream_id.fetch_add(1, Ordering::Relaxed);
+        let setup_destination = match destination_type {
+            DestinationType::Tcp => ClientSetupDestination::Tcp(destination_addr.clone()),
+        };
+        let StreamHalf { mut entry, stream } = StreamHalf::new(stream_id, self.outbound.clone());
+        let (open_result_sender, open_result_receiver) = oneshot::channel();
+        entry.open_result = Some(open_result_sender);
+        self.outbound
+            .send(Outbound::Open {
+                stream_id,
+                entry,
+                setup_destination,
+            })
+            .map_err(|_| Error::MuxSessionClosed)?;
+        match open_result_receiver
+            .await
+            .map_err(|_| Error::MuxSessionClosed)?
+        {
+            ServerSetupDestination::Success => Ok(stream),
+            ServerSetupDestination::Fail(failure) => {
+                Err(Error::SetupDestination(destination_addr, failure))
+            }
+            unexpected => Err(Error::UnexpectedSetupDestination(format!(
+                "{unexpected:?}, destination address: {destination_addr}"
+            ))),
+        }
+    }
+}
+/// The multiplexed session in proxy side, it accepts
+/// the streams opened by agent.
+pub struct MuxListener {
+    accepted: UnboundedReceiver<(ClientSetupDestination, MuxStream)>,
+    /// Keep the session alive while listening
+    _outbound: UnboundedSender<Outbound>,
+}
+impl MuxListener {
+    /// Start the session on the connection which complete
+    /// the handshake and multiplex setup.
+    pub fn accept<T>(framed: Framed<T, SecureLengthDelimitedCodec>) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let (outbound, outbound_receiver) = unbounded_channel();
+        let (accepted_sender, accepted) = unbounded_channel();
+        tokio::spawn(drive(
+            framed,
+            outbound.downgrade(),
+            outbound_receiver,
+            Some(accepted_sender),
+        ));
+        Self {
+            accepted,
+            _outbound: outbound,
+        }
+    }
+    /// Wait for the next stream, the stream must reply the open
+    /// result before relay any data. Return `None` when the
+    /// session closed.
+    pub async fn next(&mut self) -> Option<(ClientSetupDestination, MuxStream)> {
+        self.accepted.recv().await
+    }
+}
+/// Drive the session, read frames from the connection and
+/// dispatch them to streams, send the frames from streams.
+async fn drive<T>(
+    mut framed: Framed<T, SecureLengthDelimitedCodec>,
+    outbound: WeakUnboundedSender<Outbound>,
+    mut outbound_receiver: UnboundedReceiver<Outbound>,
+    accepted_sender: Option<UnboundedSender<(ClientSetupDestination, MuxStream)>>,
+) where
+    T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    // The session handle or the listener and the streams hold the sender, the
+    // driver only keep the weak one to create the accepted streams, so that
+    // the session closes when all of them dropped.
+    let mut streams = HashMap::<u32, StreamEntry>::new();
+    // The frames waiting to be written, the reading must not stop
+    // when the writing blocked, otherwise both side may wait for
+    // each other. The flow control keep the queue size limited.
+    let mut pending_frames = VecDeque::<Vec<u8>>::new();
+    let result: Result<(), Error> = async {
+        loop {
+            let event = poll_fn(|cx| {
+                poll_event(cx, &mut framed, &mut pending_frames, &mut outbound_receiver)
+            })
+            .await?;
+            match event {
+                DriverEvent::Inbound(None) | DriverEvent::Outbound(None) => return Ok(()),
+                DriverEvent::Inbound(Some(inbound_frame)) => {
+                    let (MuxFrame { stream_id, payload }, _) =
+                        bincode::decode_from_slice::<MuxFrame, Configuration>(
+                            &inbound_frame,
+                            bincode::config::standard(),
+                        )?;
+                    match payload {
+                        MuxPayload::Open(setup_destination) => {
+                            let Some(accepted_sender) = &accepted_sender else {
+                                continue;
+                            };
+                            // The frames of the live stream must not go to another one
+                            if streams.contains_key(&stream_id) {
+                                return Err(Error::MuxStreamIdReused(stream_id));
+                            }
+                            let Some(outbound) = outbound.upgrade() else {
+                                return Ok(());
+                            };
+                            let StreamHalf { entry, stream } =
+                                StreamHalf::new(stream_id, outbound);
+                            streams.insert(stream_id, entry);
+                            if accepted_sender.send((setup_destination, stream)).is_err() {
+                                return Ok(());
+                            }
+                        }
+                        MuxPayload::OpenResult(open_result) => {
+                            if let Some(open_result_sender) = streams
+                                .get_mut(&stream_id)
+                                .and_then(|entry| entry.open_result.take())
+                            {
+                                let _ = open_result_sender.send(open_result);
+                            }
+                        }
+                        MuxPayload::Data(data) => {
+                            let Some(entry) = streams.get_mut(&stream_id) else {
+                                // The stream is released, stop the remote sending
+                                pending_frames.push_back(encode_reset(stream_id)?);
+                                continue;
+                            };
+                            // The inbound channel is only bounded by the window
+                            let size = u32::try_from(data.len()).unwrap_or(u32::MAX);
+                            if size > entry.receive_window {
+                                return Err(Error::MuxFlowControlViolated(stream_id));
+                            }
+                            entry.receive_window -= size;
+                            if let Some(inbound) = &entry.inbound {
+                                let _ = inbound.send(data);
+                            }
+                        }
+                        MuxPayload::WindowUpdate(size) => {
+                            if let Some(entry) = streams.get(&stream_id) {
+                                entry.send_window.add_permits(size as usize);
+                            }
+                        }
+                        MuxPayload::Close => match streams.get_mut(&stream_id) {
+                            Some(entry) => {
+                                entry.remote_closed.store(true, Ordering::Release);
+                                entry.inbound = None;
+                            }
+                            None => pending_frames.push_back(encode_reset(stream_id)?),
+                        },
+                        MuxPayload::Reset => {
+                            // Dropping the inbound without the remote closed
+                            // mark fails the pending read with the reset.
+                            if let Some(entry) = streams.remove(&stream_id) {
+                                entry.send_window.close();
+                            }
+                        }
+                    }
+                }
+                DriverEvent::Outbound(Some(outbound_message)) => {
+                    let frame = match outbound_message {
+                        Outbound::Frame(frame) => {
+                            if let MuxFrame {
+                                stream_id,
+                                payload: MuxPayload::WindowUpdate(size),
+                            } = &frame
+                                && let Some(entry) = streams.get_mut(stream_id)
+                            {
+                                entry.receive_window = entry.receive_window.saturating_add(*size);
+                            }
+                            frame
+                        }
+                        Outbound::Open {
+                            stream_id,
+                            entry,
+                            setup_destination,
+                        } => {
+                            streams.insert(stream_id, entry);
+                            MuxFrame {
+                                stream_id,
+                                payload: MuxPayload::Open(setup_destination),
+                            }
+                        }
+                        Outbound::Release(stream_id) => {
+                            streams.remove(&stream_id);
+                            continue;
+                        }
+                    };
+                    pending_frames
+                        .push_back(bincode::encode_to_vec(frame, bincode::config::standard())?);
+                }
+            }
+        }
+    }
+    .await;
+    if let Err(e) = result {
+        error!("Multiplexed session closed because of error: {e:?}");
+    } else {
+        debug!("Multiplexed session closed.");
+    }
+    outbound_receiver.close();
+    // Wake up all the streams, the pending write fail with
+    // the closed window and the pending read see the reset.
+    for entry in streams.values() {
+        entry.send_window.close();
+    }
+}
+/// The frame tells the remote the stream is gone
+fn encode_reset(stream_id: u32) -> Result<Vec<u8>, Error> {
+    Ok(bincode::encode_to_vec(
+        MuxFrame {
+            stream_id,
+            payload: MuxPayload::Reset,
+        },
+        bincode::config::standard(),
+    )?)
+}
+/// The event happen on the session
+enum DriverEvent {
+    /// The frame from remote, `None` means the connection closed
+    Inbound(Option<BytesMut>),
+    /// The message from local, `None` means all the local handles dropped
+    Outbound(Option<Outbound>),
+}
+/// Write the pending frames as much as possible, then wait
+/// for the next frame from either remote or local.
+fn poll_event<T>(
+    cx: &mut Context<'_>,
+    framed: &mut Framed<T, SecureLengthDelimitedCodec>,
+    pending_frames: &mut VecDeque<Vec<u8>>,
+    outbound_receiver: &mut UnboundedReceiver<Outbound>,
+) -> Poll<Result<DriverEvent, Error>>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut written = false;
+    while let Some(frame) = pending_frames.front() {
+        match Pin::new(&mut *framed).poll_ready(cx) {
+            Poll::Ready(result) => result?,
+            Poll::Pending => break,
+        }
+        Pin::new(&mut *framed).start_send(frame.as_slice())?;
+        pending_frames.pop_front();
+        written = true;
+    }
+    if (written || !framed.write_buffer().is_empty())
+        && let Poll::Ready(Err(e)) = Pin::new(&mut *framed).poll_flush(cx)
+    {
+        return Poll::Ready(Err(e));
+    }
+    if let Poll::Ready(outbound_message) = outbound_receiver.poll_recv(cx) {
+        return Poll::Ready(Ok(DriverEvent::Outbound(outbound_message)));
+    }
+    match framed.poll_next_unpin(cx) {
+        Poll::Ready(Some(inbound_frame)) => {
+            Poll::Ready(Ok(DriverEvent::Inbound(Some(inbound_frame?))))
+        }
+        Poll::Ready(None) => Poll::Ready(Ok(DriverEvent::Inbound(None))),
+        Poll::Pending => Poll::Pending,
+    }
+}
+/// The logical stream in multiplexed session
+pub struct MuxStream {
+    stream_id: u32,
+    outbound: UnboundedSender<Outbound>,
+    inbound: UnboundedReceiver<Vec<u8>>,
+    read_buffer: Bytes,
+    send_window: PollSemaphore,
+    remote_closed: Arc<AtomicBool>,
+    /// The bytes consumed but not acknowledged to the remote
+    consumed: u32,
+    write_closed: bool,
+}
+impl MuxStream {
+    /// Reply the open result to the agent, only used in proxy side
+    pub fn reply_open(&self, open_result: ServerSetupDestination) -> Result<(), Error> {
+        self.outbound
+            .send(Outbound::Frame(MuxFrame {
+                stream_id: self.stream_id,
+                payload: MuxPayload::OpenResult(open_result),
+            }))
+            .map_err(|_| Error::MuxSessionClosed)
+    }
+    fn send_frame(&self, payload: MuxPayload) -> Result<(), StdIoError> {
+        self.outbound
+            .send(Outbound::Frame(MuxFrame {
+                stream_id: self.stream_id,
+                payload,
+            }))
+            .map_err(|_| StdIoError::new(ErrorKind::BrokenPipe, Error::MuxSessionClosed))
+    }
+}
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.read_buffer.is_empty() {
+            match ready!(this.inbound.poll_recv(cx)) {
+                Some(data) => this.read_buffer = Bytes::from(data),
+                None if this.remote_closed.load(Ordering::Acquire) => return Poll::Ready(Ok(())),
+                None => {
+                    return Poll::Ready(Err(StdIoError::new(
+                        ErrorKind::ConnectionReset,
+                        Error::MuxSessionClosed,
+                    )));
+                }
+            }
+        }
+        let size = this.read_buffer.len().min(buf.remaining());
+        buf.put_slice(&this.read_buffer.split_to(size));
+        this.consumed += size as u32;
+        if this.consumed >= MUX_WINDOW_UPDATE_THRESHOLD {
+            this.send_frame(MuxPayload::WindowUpdate(this.consumed))?;
+            this.consumed = 0;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, StdIoError>> {
+        let this = self.get_mut();
+        if this.write_closed {
+            return Poll::Ready(Err(ErrorKind::BrokenPipe.into()));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let size = buf.len().min(MUX_MAX_FRAME_DATA);
+        match ready!(this.send_window.poll_acquire_many(cx, size as u32)) {
+            Some(permit) => permit.forget(),
+            None => {
+                return Poll::Ready(Err(StdIoError::new(
+                    ErrorKind::BrokenPipe,
+                    Error::MuxSessionClosed,
+                )));
+            }
+        }
+        this.send_frame(MuxPayload::Data(buf[..size].to_vec()))?;
+        Poll::Ready(Ok(size))
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), StdIoError>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), StdIoError>> {
+        let this = self.get_mut();
+        if !this.write_closed {
+            this.write_closed = true;
+            this.send_frame(MuxPayload::Close)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        // Abort the stream when either direction is still open, otherwise
+        // the remote keeps waiting for the window or the data forever.
+        if !self.write_closed || !self.remote_closed.load(Ordering::Acquire) {
+            let _ = self.send_frame(MuxPayload::Reset);
+        }
+        let _ = self.outbound.send(Outbound::Release(self.stream_id));
+    }
+}
+#[tokio::test]
+async fn test() -> Result<(), Error> {
+    use futures_util::SinkExt;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+    use tokio::time::timeout;
+    let (agent_io, proxy_io) = duplex(64 * 1024);
+    let plain = || {
+        SecureLengthDelimitedCodec::new(
+            Arc::new(protocol::Encryption::Plain),
+            Arc::new(protocol::Encryption::Plain),
+        )
+    };
+    let mux_session = MuxSession::connect(Framed::new(agent_io, plain()));
+    let mut mux_listener = MuxListener::accept(Framed::new(proxy_io, plain()));
+    // The stream to port 80 echoes, the one to port 81 keeps writing
+    // until it fails and reports the error.
+    let (flood_error_sender, mut flood_error_receiver) = unbounded_channel();
+    tokio::spawn(async move {
+        while let Some((setup_destination, mux_stream)) = mux_listener.next().await {
+            mux_stream.reply_open(ServerSetupDestination::Success)?;
+            let flood = matches!(
+                setup_destination,
+                ClientSetupDestination::Tcp(UnifiedAddress::Domain { port: 81, .. })
+            );
+            let flood_error_sender = flood_error_sender.clone();
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = tokio::io::split(mux_stream);
+                if flood {
+                    let flood_error = loop {
+                        if let Err(e) = writer.write_all(&[1u8; 1024]).await {
+                            break e;
+                        }
+                    };
+                    let _ = flood_error_sender.send(flood_error.kind());
+                    return Ok(());
+                }
+                tokio::io::copy(&mut reader, &mut writer).await?;
+                writer.shutdown().await
+            });
+        }
+        Ok::<(), Error>(())
+    });
+    let payload = vec![7u8; MUX_STREAM_WINDOW as usize * 3];
+    let destination_addr = UnifiedAddress::Domain {
+        host: "localhost".to_owned(),
+        port: 80,
+    };
+    let mut streams = Vec::new();
+    for _ in 0..2 {
+        let mux_stream = mux_session
+            .open(destination_addr.clone(), DestinationType::Tcp)
+            .await?;
+        let payload = payload.clone();
+        streams.push(tokio::spawn(async move {
+            let (mut reader, mut writer) = tokio::io::split(mux_stream);
+            let write = async {
+                writer.write_all(&payload).await?;
+                writer.shutdown().await
+            };
+            let mut echo = Vec::new();
+            let (write_result, read_result) = tokio::join!(write, reader.read_to_end(&mut echo));
+            write_result?;
+            read_result?;
+            Ok::<Vec<u8>, std::io::Error>(echo)
+        }));
+    }
+    for stream in streams {
+        assert_eq!(stream.await.expect("stream task panic")?, payload);
+    }
+    // The writer is reset instead of waiting for the window
+    // forever when the other side dropped in the middle.
+    let mut mux_stream = mux_session
+        .open(
+            UnifiedAddress::Domain {
+                host: "localhost".to_owned(),
+                port: 81,
+            },
+            DestinationType::Tcp,
+        )
+        .await?;
+    let mut received = vec![0u8; 1024];
+    mux_stream.read_exact(&mut received).await?;
+    drop(mux_stream);
+    let flood_error = timeout(Duration::from_secs(5), flood_error_receiver.recv())
+        .await
+        .expect("the flood stream is not reset");
+    assert_eq!(flood_error, Some(ErrorKind::BrokenPipe));
+    // The session is dropped when the remote ignores the window
+    let (agent_io, proxy_io) = duplex(64 * 1024);
+    let mut agent_framed = Framed::new(agent_io, plain());
+    let mut mux_listener = MuxListener::accept(Framed::new(proxy_io, plain()));
+    let encode = |payload: MuxPayload| {
+        bincode::encode_to_vec(
+            MuxFrame {
+                stream_id: 1,
+                payload,
+            },
+            bincode::config::standard(),
+        )
+    };
+    agent_framed
+        .send(&encode(MuxPayload::Open(ClientSetupDestination::Tcp(
+            destination_addr.clone(),
+        )))?)
+        .await?;
+    let (_, _unread_mux_stream) = mux_listener.next().await.expect("stream not accepted");
+    for _ in 0..=MUX_STREAM_WINDOW as usize / MUX_MAX_FRAME_DATA {
+        agent_framed
+            .send(&encode(MuxPayload::Data(vec![0u8; MUX_MAX_FRAME_DATA]))?)
+            .await?;
+    }
+    let next_frame = timeout(Duration::from_secs(5), agent_framed.next())
+        .await
+        .expect("the session is not dropped");
+    assert!(next_frame.is_none());
+    // The session is dropped when the remote opens the stream id in use
+    let (agent_io, proxy_io) = duplex(64 * 1024);
+    let mut agent_framed = Framed::new(agent_io, plain());
+    let mut mux_listener = MuxListener::accept(Framed::new(proxy_io, plain()));
+    for _ in 0..2 {
+        agent_framed
+            .send(&encode(MuxPayload::Open(ClientSetupDestination::Tcp(
+                destination_addr.clone(),
+            )))?)
+            .await?;
+    }
+    let (_, mut live_mux_stream) = mux_listener.next().await.expect("stream not accepted");
+    assert!(mux_listener.next().await.is_none());
+    let next_frame = timeout(Duration::from_secs(5), agent_framed.next())
+        .await
+        .expect("the session is not dropped");
+    assert!(next_frame.is_none());
+    assert!(live_mux_stream.read_exact(&mut [0u8; 1]).await.is_err());
+    Ok(())
+}
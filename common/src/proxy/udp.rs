@@ -0,0 +1,245 @@
+use crate::{Error, SecureLengthDelimitedCodec};
+use bincode::config::Configuration;
+use futures_util::{Sink, StreamExt};
+use protocol::{Relay, UnifiedAddress};
+use std::collections::VecDeque;
+use std::future::poll_fn;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{Receiver, Sender, channel};
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::Framed;
+use tracing::{debug, error};
+/// The datagrams can wait in each direction, the new datagrams are
+/// dropped when the queue is full just like the network does.
+const UDP_RELAY_QUEUE_SIZE: usize = 256;
+/// The datagram relayed between agent and proxy
+#[derive(Debug)]
+pub struct UdpDatagram {
+    pub src_addr: UnifiedAddress,
+    pub dst_addr: UnifiedAddress,
+    pub payload: Vec<u8>,
+}
+/// The udp relay on the connection which setup the udp destination,
+/// each frame carries one datagram in both directions.
+pub struct UdpRelay {
+    sender: UdpRelaySender,
+    receiver: UdpRelayReceiver,
+}
+impl UdpRelay {
+    /// Start the relay on the connection, the frames are
+    /// written and read in background.
+    pub fn new<T>(framed: Framed<T, SecureLengthDelimitedCodec>) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let (outbound_sender, outbound_receiver) = channel(UDP_RELAY_QUEUE_SIZE);
+        let (inbound_sender, inbound_receiver) = channel(UDP_RELAY_QUEUE_SIZE);
+        tokio::spawn(drive(framed, outbound_receiver, inbound_sender));
+        Self {
+            sender: UdpRelaySender(outbound_sender),
+            receiver: UdpRelayReceiver(inbound_receiver),
+        }
+    }
+    /// Split the relay so that the datagrams can be sent
+    /// and received in different tasks.
+    pub fn split(self) -> (UdpRelaySender, UdpRelayReceiver) {
+        (self.sender, self.receiver)
+    }
+}
+/// Send datagrams to the remote side of the relay
+#[derive(Clone)]
+pub struct UdpRelaySender(Sender<UdpDatagram>);
+impl UdpRelaySender {
+    /// Queue the datagram, it is dropped when the queue is full.
+    /// Fail when the relay closed.
+    pub fn send(&self, datagram: UdpDatagram) -> Result<(), Error> {
+        match self.0.try_send(datagram) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(datagram)) => {
+                debug!(
+                    "Drop udp datagram to [{}] because the relay queue is full",
+                    datagram.dst_addr
+                );
+                Ok(())
+            }
+            Err(TrySendError::Closed(_)) => Err(Error::UdpRelayClosed),
+        }
+    }
+    pub fn is_closed(&self) -> bool {
+        self.0.is_closed()
+    }
+}
+/// Receive datagrams from the remote side of the relay
+pub struct UdpRelayReceiver(Receiver<UdpDatagram>);
+impl UdpRelayReceiver {
+    /// Wait for the next datagram, `None` when the relay closed
+    pub async fn recv(&mut self) -> Option<UdpDatagram> {
+        self.0.recv().await
+    }
+}
+/// The event happen on the relay
+enum DriverEvent {
+    /// The frame from remote, `None` means the connection closed
+    Inbound(Option<BytesMut>),
+    /// The datagram from local, `None` means the local side dropped
+    Outbound(Option<UdpDatagram>),
+}
+/// Drive the relay, write the local datagrams as frames and
+/// deliver the frames from remote as datagrams.
+async fn drive<T>(
+    mut framed: Framed<T, SecureLengthDelimitedCodec>,
+    mut outbound_receiver: Receiver<UdpDatagram>,
+    inbound_sender: Sender<UdpDatagram>,
+) where
+    T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    // The reading must not stop when the writing blocked,
+    // otherwise both side may wait for each other.
+    let mut pending_frames = VecDeque::<Vec<u8>>::new();
+    let result: Result<(), Error> = async {
+        loop {
+            let event = poll_fn(|cx| {
+                poll_event(cx, &mut framed, &mut pending_frames, &mut outbound_receiver)
+            })
+            .await?;
+            match event {
+                DriverEvent::Inbound(None) | DriverEvent::Outbound(None) => return Ok(()),
+                DriverEvent::Inbound(Some(inbound_frame)) => {
+                    let (relay, _) = bincode::decode_from_slice::<Relay, Configuration>(
+                        &inbound_frame,
+                        bincode::config::standard(),
+                    )?;
+                    let Relay::Udp {
+                        src_addr,
+                        dst_addr,
+                        payload,
+                    } = relay
+                    else {
+                        debug!("Ignore the tcp relay frame in udp relay");
+                        continue;
+                    };
+                    match inbound_sender.try_send(UdpDatagram {
+                        src_addr,
+                        dst_addr,
+                        payload,
+                    }) {
+                        Ok(()) => {}
+                        Err(TrySendError::Full(datagram)) => debug!(
+                            "Drop udp datagram from [{}] because the relay queue is full",
+                            datagram.src_addr
+                        ),
+                        Err(TrySendError::Closed(_)) => return Ok(()),
+                    }
+                }
+                DriverEvent::Outbound(Some(UdpDatagram {
+                    src_addr,
+                    dst_addr,
+                    payload,
+                })) => {
+                    pending_frames.push_back(bincode::encode_to_vec(
+                        Relay::Udp {
+                            src_addr,
+                            dst_addr,
+                            payload,
+                        },
+                        bincode::config::standard(),
+                    )?);
+                }
+            }
+        }
+    }
+    .await;
+    match result {
+        Ok(()) => debug!("Udp relay closed."),
+        Err(e) => error!("Udp relay closed because of error: {e:?}"),
+    }
+}
+/// Write the pending frames as much as possible, then wait
+/// for the next frame from either remote or local.
+fn poll_event<T>(
+    cx: &mut Context<'_>,
+    framed: &mut Framed<T, SecureLengthDelimitedCodec>,
+    pending_frames: &mut VecDeque<Vec<u8>>,
+    outbound_receiver: &mut Receiver<UdpDatagram>,
+) -> Poll<Result<DriverEvent, Error>>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut written = false;
+    while let Some(frame) = pending_frames.front() {
+        match Pin::new(&mut *framed).poll_ready(cx) {
+            Poll::Ready(result) => result?,
+            Poll::Pending => break,
+        }
+        Pin::new(&mut *framed).start_send(frame.as_slice())?;
+        pending_frames.pop_front();
+        written = true;
+    }
+    if (written || !framed.write_buffer().is_empty())
+        && let Poll::Ready(Err(e)) = Pin::new(&mut *framed).poll_flush(cx)
+    {
+        return Poll::Ready(Err(e));
+    }
+    // Stop taking datagrams when the writing can not catch up,
+    // so that the new datagrams are dropped by the sender.
+    if pending_frames.len() < UDP_RELAY_QUEUE_SIZE
+        && let Poll::Ready(datagram) = outbound_receiver.poll_recv(cx)
+    {
+        return Poll::Ready(Ok(DriverEvent::Outbound(datagram)));
+    }
+    match framed.poll_next_unpin(cx) {
+        Poll::Ready(Some(inbound_frame)) => {
+            Poll::Ready(Ok(DriverEvent::Inbound(Some(inbound_frame?))))
+        }
+        Poll::Ready(None) => Poll::Ready(Ok(DriverEvent::Inbound(None))),
+        Poll::Pending => Poll::Pending,
+    }
+}
+#[tokio::test]
+async fn test() -> Result<(), Error> {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use tokio::io::duplex;
+    let (agent_io, proxy_io) = duplex(64 * 1024);
+    let plain = || {
+        SecureLengthDelimitedCodec::new(
+            Arc::new(protocol::Encryption::Plain),
+            Arc::new(protocol::Encryption::Plain),
+        )
+    };
+    let (agent_sender, mut agent_receiver) = UdpRelay::new(Framed::new(agent_io, plain())).split();
+    let (proxy_sender, mut proxy_receiver) = UdpRelay::new(Framed::new(proxy_io, plain())).split();
+    // Echo the datagrams back with the addresses swapped
+    tokio::spawn(async move {
+        while let Some(datagram) = proxy_receiver.recv().await {
+            proxy_sender.send(UdpDatagram {
+                src_addr: datagram.dst_addr,
+                dst_addr: datagram.src_addr,
+                payload: datagram.payload,
+            })?;
+        }
+        Ok::<(), Error>(())
+    });
+    let client_addr: UnifiedAddress = "127.0.0.1:5353".parse::<SocketAddr>().unwrap().into();
+    for index in 0..10u8 {
+        let destination_addr = UnifiedAddress::Domain {
+            host: format!("destination{index}.com"),
+            port: 53,
+        };
+        agent_sender.send(UdpDatagram {
+            src_addr: client_addr.clone(),
+            dst_addr: destination_addr.clone(),
+            payload: vec![index; 512],
+        })?;
+        let datagram = agent_receiver.recv().await.ok_or(Error::UdpRelayClosed)?;
+        assert_eq!(datagram.src_addr, destination_addr);
+        assert_eq!(datagram.dst_addr, client_addr);
+        assert_eq!(datagram.payload, vec![index; 512]);
+    }
+    drop(agent_sender);
+    assert!(agent_receiver.recv().await.is_none());
+    Ok(())
+}
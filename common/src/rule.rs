@@ -0,0 +1,84 @@
+use ipnet::IpNet;
+use protocol::UnifiedAddress;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+/// The condition to match a destination address, used by
+/// the rules which decide how to handle the destination.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressMatcher {
+    /// Match the domain and all its sub domains
+    DomainSuffix(String),
+    /// Match the domain which contains the keyword
+    DomainKeyword(String),
+    /// Match the domain exactly
+    Domain(String),
+    /// Match the ip address in the network, the domain
+    /// is not resolved to match this condition
+    IpCidr(IpNet),
+    /// Match the port
+    Port(u16),
+    /// Match the port in the range, both ends included
+    PortRange(u16, u16),
+}
+impl AddressMatcher {
+    /// Check if the address match the condition
+    pub fn matches(&self, address: &UnifiedAddress) -> bool {
+        let (host, port) = match address {
+            UnifiedAddress::Domain { host, port } => (
+                match host.parse::<IpAddr>() {
+                    Ok(ip) => MatchHost::Ip(ip),
+                    Err(_) => MatchHost::Domain(normalize_domain(host)),
+                },
+                *port,
+            ),
+            UnifiedAddress::SocketAddress(socket_addr) => {
+                (MatchHost::Ip(socket_addr.ip()), socket_addr.port())
+            }
+        };
+        match (self, host) {
+            (AddressMatcher::DomainSuffix(suffix), MatchHost::Domain(domain)) => {
+                let suffix = normalize_domain(suffix);
+                domain == suffix || domain.ends_with(&format!(".{suffix}"))
+            }
+            (AddressMatcher::DomainKeyword(keyword), MatchHost::Domain(domain)) => {
+                domain.contains(&keyword.to_lowercase())
+            }
+            (AddressMatcher::Domain(expected), MatchHost::Domain(domain)) => {
+                domain == normalize_domain(expected)
+            }
+            (AddressMatcher::IpCidr(network), MatchHost::Ip(ip)) => network.contains(&ip),
+            (AddressMatcher::Port(expected), _) => port == *expected,
+            (AddressMatcher::PortRange(start, end), _) => (*start..=*end).contains(&port),
+            _ => false,
+        }
+    }
+}
+enum MatchHost {
+    Domain(String),
+    Ip(IpAddr),
+}
+fn normalize_domain(domain: &str) -> String {
+    domain.trim_end_matches('.').to_lowercase()
+}
+#[test]
+fn test() {
+    let domain = |host: &str| UnifiedAddress::Domain {
+        host: host.to_owned(),
+        port: 443,
+    };
+    let suffix = AddressMatcher::DomainSuffix("example.com".to_owned());
+    assert!(suffix.matches(&domain("example.com")));
+    assert!(suffix.matches(&domain("www.Example.com.")));
+    assert!(!suffix.matches(&domain("badexample.com")));
+    assert!(AddressMatcher::DomainKeyword("ample".to_owned()).matches(&domain("www.example.com")));
+    assert!(!AddressMatcher::Domain("example.com".to_owned()).matches(&domain("www.example.com")));
+    let cidr = AddressMatcher::IpCidr("192.168.0.0/16".parse().expect("invalid cidr"));
+    assert!(cidr.matches(&domain("192.168.1.1")));
+    assert!(cidr.matches(&UnifiedAddress::SocketAddress(
+        "192.168.2.3:80".parse().expect("invalid address")
+    )));
+    assert!(!cidr.matches(&domain("example.com")));
+    assert!(AddressMatcher::PortRange(400, 500).matches(&domain("example.com")));
+    assert!(!AddressMatcher::Port(80).matches(&domain("example.com")));
+}
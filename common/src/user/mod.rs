@@ -6,6 +6,53 @@ use std::sync::Arc;
 use chrono::{DateTime, Utc};
 use crypto::RsaCrypto;
 use std::net::SocketAddr;
+/// The id of the rsa key loaded as the rsa crypto of the user
+pub const PRIMARY_USER_RSA_KEY_ID: &str = "primary";
+/// The rsa key of the user with its validity window, several keys can be
+/// active at the same time so that the keys can be rotated without downtime.
+#[derive(Debug, Clone)]
+pub struct UserRsaKey {
+    id: String,
+    not_before: Option<DateTime<Utc>>,
+    not_after: Option<DateTime<Utc>>,
+    rsa_crypto: RsaCrypto,
+}
+impl UserRsaKey {
+    pub fn new(
+        id: String,
+        not_before: Option<DateTime<Utc>>,
+        not_after: Option<DateTime<Utc>>,
+        rsa_crypto: RsaCrypto,
+    ) -> Self {
+        Self {
+            id,
+            not_before,
+            not_after,
+            rsa_crypto,
+        }
+    }
+    /// The primary key without validity window
+    pub fn primary(rsa_crypto: RsaCrypto) -> Self {
+        Self::new(PRIMARY_USER_RSA_KEY_ID.to_owned(), None, None, rsa_crypto)
+    }
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+    pub fn not_before(&self) -> Option<&DateTime<Utc>> {
+        self.not_before.as_ref()
+    }
+    pub fn not_after(&self) -> Option<&DateTime<Utc>> {
+        self.not_after.as_ref()
+    }
+    pub fn rsa_crypto(&self) -> &RsaCrypto {
+        &self.rsa_crypto
+    }
+    /// If the key can be used at the time
+    pub fn is_active(&self, time: &DateTime<Utc>) -> bool {
+        self.not_before.is_none_or(|not_before| not_before <= *time)
+            && self.not_after.is_none_or(|not_after| *time < not_after)
+    }
+}
 /// The base user
 pub trait User {
     /// The username
@@ -14,6 +61,13 @@ pub trait User {
     fn rsa_crypto(&self) -> Option<&RsaCrypto>;
     /// Attach the rsa crypto to user
     fn set_rsa_crypto(&mut self, rsa_crypto: RsaCrypto);
+    /// Get all the rsa keys of the user, include the inactive ones
+    fn rsa_keys(&self) -> &[UserRsaKey] {
+        &[]
+    }
+    /// Attach the rsa keys to user, the user only use the
+    /// rsa crypto ignores them.
+    fn set_rsa_keys(&mut self, _rsa_keys: Vec<UserRsaKey>) {}
 }
 /// The user with expired time
 pub trait UserWithExpiredTime: User {
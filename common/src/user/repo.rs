@@ -1,16 +1,36 @@
 use crate::Error;
 use crate::config::WithFileSystemUserRepoConfig;
+pub use http::HttpUserRepository;
+pub use sqlite::{SqliteUserRepository, import_file_system_user_repo};
 use crate::user::UserRepository;
-use crate::user::User;
+use crate::user::{PRIMARY_USER_RSA_KEY_ID, User, UserRsaKey};
+use chrono::{DateTime, Utc};
 use crypto::RsaCrypto;
+use serde::Deserialize;
 use serde::de::DeserializeOwned;
-use std::collections::HashMap;
+use notify::event::{AccessKind, AccessMode};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::ErrorKind;
 use std::marker::PhantomData;
 use std::ops::Deref;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
 use std::time::Duration;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
 use tokio::time::sleep;
-use tracing::error;
+use tracing::{error, info, warn};
+mod http;
+mod sqlite;
+/// How long to collect the notifications before reloading the changed users
+const USER_REPO_CHANGE_DEBOUNCE: Duration = Duration::from_millis(500);
+/// The directory in the user directory holds the rotated rsa keys, each
+/// sub directory is one key named by the key id and has the same key
+/// files as the user directory.
+pub const USER_RSA_KEYS_DIRECTORY_NAME: &str = "keys";
+/// The optional file in the key directory holds the validity window
+pub const USER_RSA_KEY_INFO_FILE_NAME: &str = "key_info.toml";
 #[derive(Debug)]
 pub struct FileSystemUserRepository<U, C>
 where
@@ -20,90 +40,376 @@ where
     storage: Arc<RwLock<HashMap<String, Arc<U>>>>,
     _config_mark: PhantomData<C>,
 }
+/// The user loaded from the user directory last time
+struct LoadedUserDirectory<U> {
+    user: Arc<U>,
+    /// The hash of the user files, to find the changed users
+    /// and skip parsing the unchanged ones.
+    fingerprint: u64,
+}
+impl<U> Clone for LoadedUserDirectory<U> {
+    fn clone(&self) -> Self {
+        Self {
+            user: self.user.clone(),
+            fingerprint: self.fingerprint,
+        }
+    }
+}
+/// The validity window of the rsa key
+#[derive(Deserialize, Default)]
+struct UserRsaKeyInfo {
+    not_before: Option<DateTime<Utc>>,
+    not_after: Option<DateTime<Utc>>,
+}
+/// The content of the files in the key directory
+#[derive(Hash)]
+struct UserRsaKeyFiles {
+    id: String,
+    public_key: String,
+    private_key: String,
+    key_info: Option<String>,
+}
+impl UserRsaKeyFiles {
+    fn read<C: WithFileSystemUserRepoConfig>(
+        config: &C,
+        id: String,
+        key_dir_path: &Path,
+    ) -> Result<Self, Error> {
+        let key_info =
+            match std::fs::read_to_string(key_dir_path.join(USER_RSA_KEY_INFO_FILE_NAME)) {
+                Ok(key_info) => Some(key_info),
+                Err(e) if e.kind() == ErrorKind::NotFound => None,
+                Err(e) => return Err(e.into()),
+            };
+        Ok(Self {
+            id,
+            public_key: std::fs::read_to_string(key_dir_path.join(config.public_key_file_name()))?,
+            private_key: std::fs::read_to_string(
+                key_dir_path.join(config.private_key_file_name()),
+            )?,
+            key_info,
+        })
+    }
+    /// Read the primary key in the user directory and
+    /// the rotated keys in the keys directory.
+    fn read_all<C: WithFileSystemUserRepoConfig>(
+        config: &C,
+        user_dir_path: &Path,
+    ) -> Result<Vec<Self>, Error> {
+        let mut all_key_files = vec![Self::read(
+            config,
+            PRIMARY_USER_RSA_KEY_ID.to_owned(),
+            user_dir_path,
+        )?];
+        let keys_dir_path = user_dir_path.join(USER_RSA_KEYS_DIRECTORY_NAME);
+        if !keys_dir_path.is_dir() {
+            return Ok(all_key_files);
+        }
+        let mut key_dir_paths = list_user_directories(&keys_dir_path)?;
+        key_dir_paths.sort();
+        for key_dir_path in key_dir_paths {
+            let Some(id) = key_dir_path.file_name().and_then(|id| id.to_str()) else {
+                continue;
+            };
+            all_key_files.push(Self::read(config, id.to_owned(), &key_dir_path)?);
+        }
+        Ok(all_key_files)
+    }
+    fn parse(&self) -> Result<UserRsaKey, Error> {
+        let key_info = self
+            .key_info
+            .as_deref()
+            .map(toml::from_str::<UserRsaKeyInfo>)
+            .transpose()?
+            .unwrap_or_default();
+        Ok(UserRsaKey::new(
+            self.id.clone(),
+            key_info.not_before,
+            key_info.not_after,
+            RsaCrypto::new(self.public_key.as_bytes(), self.private_key.as_bytes())?,
+        ))
+    }
+}
+/// The difference of the users between two reloads
+#[derive(Debug, Default, PartialEq)]
+struct UserRepositoryDiff {
+    added: Vec<String>,
+    removed: Vec<String>,
+    changed: Vec<String>,
+}
+impl UserRepositoryDiff {
+    fn new(previous: &HashMap<String, u64>, current: &HashMap<String, u64>) -> Self {
+        let mut diff = Self::default();
+        for (username, fingerprint) in current {
+            match previous.get(username) {
+                None => diff.added.push(username.clone()),
+                Some(previous_fingerprint) if previous_fingerprint != fingerprint => {
+                    diff.changed.push(username.clone())
+                }
+                Some(_) => {}
+            }
+        }
+        diff.removed = previous
+            .keys()
+            .filter(|username| !current.contains_key(*username))
+            .cloned()
+            .collect();
+        diff.added.sort();
+        diff.removed.sort();
+        diff.changed.sort();
+        diff
+    }
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
 impl<U, C> FileSystemUserRepository<U, C>
 where
     U: User + Send + Sync + DeserializeOwned + 'static,
     C: WithFileSystemUserRepoConfig + Send + Sync + 'static,
 {
-    fn fill_storage(
+    /// Load the user from the user directory, the user files are parsed
+    /// again only when they changed after the previous load.
+    fn load_user(
+        config: &C,
+        user_dir_path: &Path,
+        previous: Option<&LoadedUserDirectory<U>>,
+    ) -> Result<LoadedUserDirectory<U>, Error> {
+        let all_key_files = UserRsaKeyFiles::read_all(config, user_dir_path)?;
+        let user_info_file_content =
+            std::fs::read_to_string(user_dir_path.join(config.user_info_file_name()))?;
+        let mut hasher = DefaultHasher::new();
+        (&all_key_files, &user_info_file_content).hash(&mut hasher);
+        let fingerprint = hasher.finish();
+        if let Some(previous) = previous
+            && previous.fingerprint == fingerprint
+        {
+            return Ok(previous.clone());
+        }
+        let user_rsa_keys = all_key_files
+            .iter()
+            .map(UserRsaKeyFiles::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut user_info = toml::from_str::<U>(&user_info_file_content)?;
+        // The primary key is always the first one
+        user_info.set_rsa_crypto(user_rsa_keys[0].rsa_crypto().clone());
+        user_info.set_rsa_keys(user_rsa_keys);
+        Ok(LoadedUserDirectory {
+            user: Arc::new(user_info),
+            fingerprint,
+        })
+    }
+    /// Reload the user directories into a fresh storage and swap it in. All
+    /// the user directories are reloaded when `changed_directories` is `None`,
+    /// so the users deleted from the directory are dropped. The user fail to
+    /// reload keeps the one loaded last time, so a half-written file never
+    /// replace a good user.
+    fn reload_storage(
         config: &C,
-        storage: Arc<RwLock<HashMap<String, Arc<U>>>>,
+        user_repo_directory_path: &Path,
+        storage: &RwLock<HashMap<String, Arc<U>>>,
+        loaded_directories: &mut HashMap<PathBuf, LoadedUserDirectory<U>>,
+        changed_directories: Option<HashSet<PathBuf>>,
     ) -> Result<(), Error> {
-        let user_repo_directory_path = config.user_repo_directory();
-        let mut user_repo_directory = std::fs::read_dir(user_repo_directory_path)?;
-        while let Some(Ok(sub_entry)) = user_repo_directory.next() {
-            let file_type = match sub_entry.file_type() {
-                Ok(file_type) => file_type,
-                Err(e) => {
-                    error!(
-                        "Fail to read sub entry from user user directory [{user_repo_directory_path:?}] because of error: {e:?}"
-                    );
-                    continue;
-                }
-            };
-            if !file_type.is_dir() {
-                continue;
-            }
-            let file_name = sub_entry.file_name();
-            let file_name = match file_name.to_str() {
-                Some(file_name) => file_name,
-                None => {
-                    continue;
-                }
-            };
-            if file_name.starts_with("\\.") {
+        let (mut current_directories, changed_directories) = match changed_directories {
+            None => (
+                HashMap::new(),
+                list_user_directories(user_repo_directory_path)?,
+            ),
+            Some(changed_directories) => (
+                loaded_directories.clone(),
+                changed_directories.into_iter().collect(),
+            ),
+        };
+        for user_dir_path in changed_directories {
+            if !user_dir_path.is_dir() {
+                current_directories.remove(&user_dir_path);
                 continue;
             }
-            let user_dir_path = sub_entry.path();
-            let public_key_file_path = user_dir_path.join(config.public_key_file_name());
-            let public_key_file = match std::fs::File::open(public_key_file_path) {
-                Ok(public_key_file) => public_key_file,
-                Err(e) => {
-                    error!("Fail to read public key file: {e:?}");
-                    continue;
+            let previous = loaded_directories.get(&user_dir_path);
+            match Self::load_user(config, &user_dir_path, previous) {
+                Ok(loaded_directory) => {
+                    current_directories.insert(user_dir_path, loaded_directory);
                 }
-            };
-            let private_key_file_path = user_dir_path.join(config.private_key_file_name());
-            let private_key_file = match std::fs::File::open(private_key_file_path) {
-                Ok(private_key_file) => private_key_file,
                 Err(e) => {
-                    error!("Fail to read private key file: {e:?}");
-                    continue;
+                    error!("Fail to load user from directory [{user_dir_path:?}]: {e:?}");
+                    if let Some(previous) = previous {
+                        warn!(
+                            "Keep the last loaded user [{}] from directory [{user_dir_path:?}]",
+                            previous.user.username()
+                        );
+                        current_directories.insert(user_dir_path, previous.clone());
+                    }
                 }
-            };
-            let user_rsa_crypto = match RsaCrypto::new(public_key_file, private_key_file) {
-                Ok(user_rsa_crypto) => user_rsa_crypto,
-                Err(e) => {
-                    error!("Fail to create user rsa crypto: {e:?}");
-                    continue;
-                }
-            };
-            let user_info_file_path = user_dir_path.join(config.user_info_file_name());
-            let user_info_file_content = match std::fs::read_to_string(&user_info_file_path) {
-                Ok(content) => content,
+            }
+        }
+        let fingerprints = |directories: &HashMap<PathBuf, LoadedUserDirectory<U>>| {
+            directories
+                .values()
+                .map(|loaded_directory| {
+                    (
+                        loaded_directory.user.username().to_owned(),
+                        loaded_directory.fingerprint,
+                    )
+                })
+                .collect::<HashMap<_, _>>()
+        };
+        let diff = UserRepositoryDiff::new(
+            &fingerprints(loaded_directories),
+            &fingerprints(&current_directories),
+        );
+        let current_storage = current_directories
+            .values()
+            .map(|loaded_directory| {
+                (
+                    loaded_directory.user.username().to_owned(),
+                    loaded_directory.user.clone(),
+                )
+            })
+            .collect();
+        *storage.write().map_err(|e| {
+            Error::Lock(format!(
+                "Fail to lock user repository because of error: {e:?}"
+            ))
+        })? = current_storage;
+        *loaded_directories = current_directories;
+        if !diff.is_empty() {
+            info!(
+                "User repository reloaded, added: {:?}, removed: {:?}, changed: {:?}",
+                diff.added, diff.removed, diff.changed
+            );
+        }
+        Ok(())
+    }
+    /// Watch the user repository directory and send the changed user
+    /// directories, `None` means all the user directories need a reload.
+    fn watch_user_directories(
+        user_repo_directory_path: &Path,
+        changed_sender: UnboundedSender<Option<PathBuf>>,
+    ) -> Result<RecommendedWatcher, notify::Error> {
+        let watched_directory_path = user_repo_directory_path.to_owned();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+            let changed_directories = match event {
+                Ok(event) if event.need_rescan() => vec![None],
+                Ok(Event {
+                    kind: EventKind::Access(AccessKind::Close(AccessMode::Write)),
+                    paths,
+                    ..
+                }) => paths
+                    .iter()
+                    .filter_map(|path| user_directory_of(&watched_directory_path, path))
+                    .collect(),
+                Ok(Event {
+                    kind: EventKind::Access(_),
+                    ..
+                }) => vec![],
+                Ok(Event { paths, .. }) => paths
+                    .iter()
+                    .filter_map(|path| user_directory_of(&watched_directory_path, path))
+                    .collect(),
                 Err(e) => {
-                    error!("Fail to read user info file content: {e:?}");
-                    continue;
+                    error!("Fail to watch user repository directory: {e:?}");
+                    vec![None]
                 }
             };
-            let mut user_info = match toml::from_str::<U>(&user_info_file_content) {
-                Ok(user_info) => user_info,
-                Err(e) => {
-                    error!("Fail to deserialize the user info: {e:?}");
-                    continue;
+            for changed_directory in changed_directories {
+                let _ = changed_sender.send(changed_directory);
+            }
+        })?;
+        watcher.watch(user_repo_directory_path, RecursiveMode::Recursive)?;
+        Ok(watcher)
+    }
+    /// Reload the changed user directories once the watcher notified. The
+    /// notifications are collected for a while, as one user change usually
+    /// touch several files.
+    async fn reload_on_change(
+        config: &C,
+        user_repo_directory_path: &Path,
+        storage: &RwLock<HashMap<String, Arc<U>>>,
+        loaded_directories: &mut HashMap<PathBuf, LoadedUserDirectory<U>>,
+        mut changed_receiver: UnboundedReceiver<Option<PathBuf>>,
+    ) {
+        while let Some(changed_directory) = changed_receiver.recv().await {
+            sleep(USER_REPO_CHANGE_DEBOUNCE).await;
+            let mut changed_directories = Some(HashSet::new());
+            let mut collect = |changed_directory: Option<PathBuf>| match changed_directory {
+                None => changed_directories = None,
+                Some(changed_directory) => {
+                    if let Some(changed_directories) = &mut changed_directories {
+                        changed_directories.insert(changed_directory);
+                    }
                 }
             };
-            user_info.set_rsa_crypto(user_rsa_crypto);
-            let mut storage = storage.write().map_err(|e| {
-                Error::Lock(format!(
-                    "Fail to lock user repository because of error: {e:?}"
-                ))
-            })?;
-            storage.insert(user_info.username().to_owned(), Arc::new(user_info));
+            collect(changed_directory);
+            while let Ok(changed_directory) = changed_receiver.try_recv() {
+                collect(changed_directory);
+            }
+            if let Err(e) = Self::reload_storage(
+                config,
+                user_repo_directory_path,
+                storage,
+                loaded_directories,
+                changed_directories,
+            ) {
+                error!("Failed to reload user repository storage: {}", e);
+            }
         }
-        Ok(())
     }
 }
+/// The user directory contains the changed path, `Some(None)` when the user
+/// repository directory itself changed. The change inside the hidden directory,
+/// such as the one the user is written into before renamed, is skipped as `None`.
+fn user_directory_of(
+    user_repo_directory_path: &Path,
+    changed_path: &Path,
+) -> Option<Option<PathBuf>> {
+    let Some(user_dir_name) = changed_path
+        .strip_prefix(user_repo_directory_path)
+        .ok()
+        .and_then(|relative_path| relative_path.components().next())
+    else {
+        return Some(None);
+    };
+    let user_directory = user_repo_directory_path.join(user_dir_name);
+    if is_hidden(&user_directory) {
+        return None;
+    }
+    Some(Some(user_directory))
+}
+/// List the user directories under the user repository directory
+fn list_user_directories(user_repo_directory_path: &Path) -> Result<Vec<PathBuf>, Error> {
+    let user_repo_directory = std::fs::read_dir(user_repo_directory_path)?;
+    let mut user_directories = Vec::new();
+    for sub_entry in user_repo_directory {
+        let sub_entry = match sub_entry {
+            Ok(sub_entry) => sub_entry,
+            Err(e) => {
+                error!(
+                    "Fail to read sub entry from user directory [{user_repo_directory_path:?}] because of error: {e:?}"
+                );
+                continue;
+            }
+        };
+        let file_type = match sub_entry.file_type() {
+            Ok(file_type) => file_type,
+            Err(e) => {
+                error!(
+                    "Fail to read sub entry from user user directory [{user_repo_directory_path:?}] because of error: {e:?}"
+                );
+                continue;
+            }
+        };
+        if file_type.is_dir() && !is_hidden(&sub_entry.path()) {
+            user_directories.push(sub_entry.path());
+        }
+    }
+    Ok(user_directories)
+}
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|file_name| file_name.to_str())
+        .is_none_or(|file_name| file_name.starts_with('.'))
+}
 impl<U, C> UserRepository for FileSystemUserRepository<U, C>
 where
     U: User + Send + Sync + DeserializeOwned + 'static,
@@ -116,16 +422,57 @@ where
         T: Deref<Target = Self::UserRepoConfigType> + Send + Sync + 'static,
     {
         let storage = Arc::new(RwLock::new(HashMap::new()));
-        if let Err(e) = Self::fill_storage(&config, storage.clone()) {
+        // The watcher reports the absolute paths, so the user
+        // directories are tracked with the absolute paths too.
+        let user_repo_directory_path = config
+            .user_repo_directory()
+            .canonicalize()
+            .unwrap_or_else(|_| config.user_repo_directory().to_owned());
+        let mut loaded_directories = HashMap::new();
+        if let Err(e) = Self::reload_storage(
+            &config,
+            &user_repo_directory_path,
+            &storage,
+            &mut loaded_directories,
+            None,
+        ) {
             error!("Failed to fill user repository storage: {}", e);
         };
+        let (changed_sender, changed_receiver) = unbounded_channel();
+        let watcher = match Self::watch_user_directories(&user_repo_directory_path, changed_sender)
+        {
+            Ok(watcher) => Some((watcher, changed_receiver)),
+            Err(e) => {
+                warn!(
+                    "Fail to watch user repository directory [{user_repo_directory_path:?}], fallback to polling: {e:?}"
+                );
+                None
+            }
+        };
         let storage_clone = storage.clone();
         tokio::spawn(async move {
+            if let Some((_watcher, changed_receiver)) = watcher {
+                Self::reload_on_change(
+                    &config,
+                    &user_repo_directory_path,
+                    &storage_clone,
+                    &mut loaded_directories,
+                    changed_receiver,
+                )
+                .await;
+                warn!("User repository watcher stopped, fallback to polling");
+            }
             loop {
-                if let Err(e) = Self::fill_storage(&config, storage_clone.clone()) {
+                sleep(Duration::from_secs(config.refresh_interval_sec())).await;
+                if let Err(e) = Self::reload_storage(
+                    &config,
+                    &user_repo_directory_path,
+                    &storage_clone,
+                    &mut loaded_directories,
+                    None,
+                ) {
                     error!("Failed to fill user repository storage: {}", e);
                 };
-                sleep(Duration::from_secs(config.refresh_interval_sec())).await;
             }
         });
         Ok(Self {
@@ -165,3 +512,45 @@ where
         lock.insert(user.username().to_owned(), Arc::new(user));
     }
 }
+#[test]
+fn test() {
+    let previous = HashMap::from([
+        ("user1".to_owned(), 1),
+        ("user2".to_owned(), 2),
+        ("user3".to_owned(), 3),
+    ]);
+    let current = HashMap::from([
+        ("user1".to_owned(), 1),
+        ("user2".to_owned(), 20),
+        ("user4".to_owned(), 4),
+    ]);
+    let diff = UserRepositoryDiff::new(&previous, &current);
+    assert_eq!(diff.added, vec!["user4".to_owned()]);
+    assert_eq!(diff.removed, vec!["user3".to_owned()]);
+    assert_eq!(diff.changed, vec!["user2".to_owned()]);
+    assert!(UserRepositoryDiff::new(&current, &current).is_empty());
+    let user_repo_directory_path = Path::new("/ppaass/user");
+    assert_eq!(
+        user_directory_of(
+            user_repo_directory_path,
+            Path::new("/ppaass/user/user1/user_info.toml")
+        ),
+        Some(Some(PathBuf::from("/ppaass/user/user1")))
+    );
+    assert_eq!(
+        user_directory_of(user_repo_directory_path, user_repo_directory_path),
+        Some(None)
+    );
+    // The user written by the admin is not loaded before renamed
+    assert_eq!(
+        user_directory_of(
+            user_repo_directory_path,
+            Path::new("/ppaass/user/.user1.tmp/user_info.toml")
+        ),
+        None
+    );
+    assert_eq!(
+        user_directory_of(user_repo_directory_path, Path::new("/ppaass/user/.user1.tmp")),
+        None
+    );
+}
@@ -0,0 +1,658 @@
+use super::UserRepositoryDiff;
+use crate::Error;
+use crate::config::WithHttpUserRepoConfig;
+use crate::user::{User, UserRepository, UserRsaKey};
+use chrono::{DateTime, Utc};
+use crypto::RsaCrypto;
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Bytes;
+use hyper::header::{ETAG, HeaderValue, IF_NONE_MATCH};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode, Uri};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use subtle::ConstantTimeEq;
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::ErrorKind;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::Notify;
+use tokio::time::{sleep, timeout};
+use tracing::{debug, error, info, warn};
+/// The timeout of fetching the users from the url
+const USER_REPO_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// The header carries the secret of the push invalidation
+const INVALIDATION_SECRET_HEADER: &str = "x-invalidation-secret";
+type HttpClient = Client<HttpsConnector<HttpConnector>, Empty<Bytes>>;
+/// The users responded by the url, each user is the user info together
+/// with the `public_key_pem` and the optional rotated `keys` of the user.
+#[derive(Deserialize)]
+struct UserSnapshot {
+    users: Vec<Value>,
+}
+/// The rotated key of the user, it shares the private key of the
+/// repository with the primary key.
+#[derive(Deserialize, Hash)]
+struct UserRsaKeyEntry {
+    id: String,
+    public_key_pem: String,
+    #[serde(default)]
+    not_before: Option<DateTime<Utc>>,
+    #[serde(default)]
+    not_after: Option<DateTime<Utc>>,
+}
+/// The user loaded from the snapshot last time
+struct LoadedUser<U> {
+    user: Arc<U>,
+    fingerprint: u64,
+    key_fingerprint: u64,
+    /// The parsed keys with the primary one first, parsing the keys is much
+    /// slower than the user info so they are parsed again only when changed.
+    rsa_keys: Vec<UserRsaKey>,
+}
+/// The user repository fetched from the http endpoint, so that many
+/// proxies share the same users. The users are fetched periodically
+/// and once the push invalidation received, the `ETag` of the response
+/// is used to skip the users not modified. The last good users are
+/// saved into the snapshot file and used when the endpoint is down.
+#[derive(Debug)]
+pub struct HttpUserRepository<U, C>
+where
+    U: User + Send + Sync + DeserializeOwned + 'static,
+    C: WithHttpUserRepoConfig + Send + Sync + 'static,
+{
+    storage: Arc<RwLock<HashMap<String, Arc<U>>>>,
+    _config_mark: PhantomData<C>,
+}
+impl<U, C> HttpUserRepository<U, C>
+where
+    U: User + Send + Sync + DeserializeOwned + 'static,
+    C: WithHttpUserRepoConfig + Send + Sync + 'static,
+{
+    /// Create the user from the user info in the snapshot, the keys
+    /// are parsed only when they changed after the previous load.
+    fn load_user(
+        mut user_value: Value,
+        private_key_pem: &str,
+        fingerprint: u64,
+        previous: Option<&LoadedUser<U>>,
+    ) -> Result<LoadedUser<U>, Error> {
+        let Some(user_object) = user_value.as_object_mut() else {
+            return Err(Error::InvalidUserInfo("the user is not an object".to_owned()));
+        };
+        let public_key_pem = match user_object.remove("public_key_pem") {
+            Some(Value::String(public_key_pem)) => public_key_pem,
+            _ => {
+                return Err(Error::InvalidUserInfo(
+                    "the public key is missing".to_owned(),
+                ));
+            }
+        };
+        let rotated_keys = match user_object.remove("keys") {
+            Some(keys) => serde_json::from_value::<Vec<UserRsaKeyEntry>>(keys)?,
+            None => Vec::new(),
+        };
+        let mut hasher = DefaultHasher::new();
+        (&public_key_pem, private_key_pem, &rotated_keys).hash(&mut hasher);
+        let key_fingerprint = hasher.finish();
+        let rsa_keys = match previous {
+            Some(previous) if previous.key_fingerprint == key_fingerprint => {
+                previous.rsa_keys.clone()
+            }
+            _ => {
+                let mut rsa_keys = vec![UserRsaKey::primary(RsaCrypto::new(
+                    public_key_pem.as_bytes(),
+                    private_key_pem.as_bytes(),
+                )?)];
+                for rotated_key in rotated_keys {
+                    rsa_keys.push(UserRsaKey::new(
+                        rotated_key.id,
+                        rotated_key.not_before,
+                        rotated_key.not_after,
+                        RsaCrypto::new(
+                            rotated_key.public_key_pem.as_bytes(),
+                            private_key_pem.as_bytes(),
+                        )?,
+                    ));
+                }
+                rsa_keys
+            }
+        };
+        let mut user_info = serde_json::from_value::<U>(user_value)?;
+        user_info.set_rsa_crypto(rsa_keys[0].rsa_crypto().clone());
+        user_info.set_rsa_keys(rsa_keys.clone());
+        Ok(LoadedUser {
+            user: Arc::new(user_info),
+            fingerprint,
+            key_fingerprint,
+            rsa_keys,
+        })
+    }
+    /// Load the users in the snapshot and swap them in, the user
+    /// fail to load keeps the one loaded last time.
+    fn apply_snapshot(
+        config: &C,
+        storage: &RwLock<HashMap<String, Arc<U>>>,
+        loaded_users: &mut HashMap<String, LoadedUser<U>>,
+        snapshot: &[u8],
+    ) -> Result<(), Error> {
+        let snapshot = serde_json::from_slice::<UserSnapshot>(snapshot)?;
+        let private_key_pem = std::fs::read_to_string(config.user_repo_private_key_file())?;
+        let fingerprints = |users: &HashMap<String, LoadedUser<U>>| {
+            users
+                .iter()
+                .map(|(username, loaded_user)| (username.clone(), loaded_user.fingerprint))
+                .collect::<HashMap<_, _>>()
+        };
+        let previous_fingerprints = fingerprints(loaded_users);
+        let mut current_users = HashMap::new();
+        for user_value in snapshot.users {
+            let Some(username) = user_value
+                .get("username")
+                .and_then(Value::as_str)
+                .map(str::to_owned)
+            else {
+                error!("Fail to load user without username: {user_value}");
+                continue;
+            };
+            let mut hasher = DefaultHasher::new();
+            (user_value.to_string(), &private_key_pem).hash(&mut hasher);
+            let fingerprint = hasher.finish();
+            let previous = match loaded_users.remove(&username) {
+                Some(previous) if previous.fingerprint == fingerprint => {
+                    current_users.insert(username, previous);
+                    continue;
+                }
+                previous => previous,
+            };
+            match Self::load_user(user_value, &private_key_pem, fingerprint, previous.as_ref()) {
+                Ok(loaded_user) => {
+                    current_users.insert(username, loaded_user);
+                }
+                Err(e) => {
+                    error!("Fail to load user [{username}] from snapshot: {e:?}");
+                    if let Some(previous) = previous {
+                        warn!("Keep the last loaded user [{username}]");
+                        current_users.insert(username, previous);
+                    }
+                }
+            }
+        }
+        let diff = UserRepositoryDiff::new(&previous_fingerprints, &fingerprints(&current_users));
+        let current_storage = current_users
+            .iter()
+            .map(|(username, loaded_user)| (username.clone(), loaded_user.user.clone()))
+            .collect();
+        *storage.write().map_err(|e| {
+            Error::Lock(format!(
+                "Fail to lock user repository because of error: {e:?}"
+            ))
+        })? = current_storage;
+        *loaded_users = current_users;
+        if !diff.is_empty() {
+            info!(
+                "User repository reloaded, added: {:?}, removed: {:?}, changed: {:?}",
+                diff.added, diff.removed, diff.changed
+            );
+        }
+        Ok(())
+    }
+    /// Fetch the users and apply them, the last good users are kept when
+    /// the fetching fails, and nothing changes when the users not modified.
+    async fn refresh(
+        config: &C,
+        client: &HttpClient,
+        uri: &Uri,
+        etag: &mut Option<HeaderValue>,
+        storage: &RwLock<HashMap<String, Arc<U>>>,
+        loaded_users: &mut HashMap<String, LoadedUser<U>>,
+    ) {
+        let (snapshot, snapshot_etag) = match fetch_users(client, uri, etag.as_ref()).await {
+            Ok(Some(fetched)) => fetched,
+            Ok(None) => {
+                debug!("Users from [{uri}] not modified");
+                return;
+            }
+            Err(e) => {
+                error!("Fail to fetch users from [{uri}], keep the last good users: {e:?}");
+                return;
+            }
+        };
+        if let Err(e) = Self::apply_snapshot(config, storage, loaded_users, &snapshot) {
+            error!("Fail to load users fetched from [{uri}], keep the last good users: {e:?}");
+            return;
+        }
+        *etag = snapshot_etag;
+        if let Err(e) = save_snapshot(config.user_repo_snapshot_file(), &snapshot) {
+            error!(
+                "Fail to save user snapshot to [{:?}]: {e:?}",
+                config.user_repo_snapshot_file()
+            );
+        }
+    }
+}
+impl<U, C> UserRepository for HttpUserRepository<U, C>
+where
+    U: User + Send + Sync + DeserializeOwned + 'static,
+    C: WithHttpUserRepoConfig + Send + Sync + 'static,
+{
+    type UserInfoType = U;
+    type UserRepoConfigType = C;
+    fn new<T>(config: T) -> Result<Self, Error>
+    where
+        T: Deref<Target = Self::UserRepoConfigType> + Send + Sync + 'static,
+    {
+        let uri = config.user_repo_url().parse::<Uri>()?;
+        let storage = Arc::new(RwLock::new(HashMap::new()));
+        let mut loaded_users = HashMap::new();
+        // Serve with the last good users until the first fetching done
+        match std::fs::read(config.user_repo_snapshot_file()) {
+            Ok(snapshot) => {
+                if let Err(e) =
+                    Self::apply_snapshot(&config, &storage, &mut loaded_users, &snapshot)
+                {
+                    error!("Fail to load user snapshot: {e:?}");
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => error!("Fail to read user snapshot: {e:?}"),
+        }
+        let invalidation = Arc::new(Notify::new());
+        if let Some(invalidation_address) = config.user_repo_invalidation_address() {
+            // Anyone can reach the address make the users fetched again and again
+            let invalidation_secret = config.user_repo_invalidation_secret().map(Arc::<str>::from);
+            if invalidation_secret.is_none() && !invalidation_address.ip().is_loopback() {
+                return Err(Error::UserRepoInvalidationUnprotected(invalidation_address));
+            }
+            let invalidation_listener = std::net::TcpListener::bind(invalidation_address)?;
+            invalidation_listener.set_nonblocking(true)?;
+            let invalidation_listener = TcpListener::from_std(invalidation_listener)?;
+            tokio::spawn(listen_invalidation(
+                invalidation_listener,
+                invalidation_secret,
+                invalidation.clone(),
+            ));
+        }
+        let client = Client::builder(TokioExecutor::new()).build(
+            HttpsConnectorBuilder::new()
+                .with_webpki_roots()
+                .https_or_http()
+                .enable_http1()
+                .build(),
+        );
+        let storage_clone = storage.clone();
+        tokio::spawn(async move {
+            let mut etag = None;
+            loop {
+                Self::refresh(
+                    &config,
+                    &client,
+                    &uri,
+                    &mut etag,
+                    &storage_clone,
+                    &mut loaded_users,
+                )
+                .await;
+                tokio::select! {
+                    _ = sleep(Duration::from_secs(config.refresh_interval_sec())) => {}
+                    _ = invalidation.notified() => {
+                        debug!("User repository invalidated, fetch users from [{uri}]");
+                    }
+                }
+            }
+        });
+        Ok(Self {
+            storage,
+            _config_mark: Default::default(),
+        })
+    }
+    fn find_user(&self, username: &str) -> Option<Arc<Self::UserInfoType>> {
+        let lock = match self.storage.read() {
+            Ok(guard) => guard,
+            Err(e) => {
+                error!("Fail to lock user storage: {e:?}");
+                return None;
+            }
+        };
+        let user_info = lock.get(username)?;
+        Some(user_info.clone())
+    }
+    fn list_users(&self) -> Vec<Arc<Self::UserInfoType>> {
+        let lock = match self.storage.read() {
+            Ok(guard) => guard,
+            Err(e) => {
+                error!("Fail to lock user storage: {e:?}");
+                return vec![];
+            }
+        };
+        lock.values().cloned().collect()
+    }
+    /// The user is kept in memory only, it is replaced by
+    /// the endpoint once the users modified.
+    fn save_user(&self, user: Self::UserInfoType) {
+        let mut lock = match self.storage.write() {
+            Ok(guard) => guard,
+            Err(e) => {
+                error!("Fail to lock user storage: {e:?}");
+                return;
+            }
+        };
+        lock.insert(user.username().to_owned(), Arc::new(user));
+    }
+}
+/// Fetch the users with the `ETag` of the last good users, return
+/// `None` when the users not modified, otherwise the users together
+/// with the new `ETag`.
+async fn fetch_users(
+    client: &HttpClient,
+    uri: &Uri,
+    etag: Option<&HeaderValue>,
+) -> Result<Option<(Bytes, Option<HeaderValue>)>, Error> {
+    let mut request = Request::get(uri.clone());
+    if let Some(etag) = etag {
+        request = request.header(IF_NONE_MATCH, etag);
+    }
+    let request = request
+        .body(Empty::new())
+        .map_err(|e| Error::UserRepoFetch(uri.to_string(), e.to_string()))?;
+    let response = timeout(USER_REPO_FETCH_TIMEOUT, client.request(request))
+        .await
+        .map_err(|_| Error::UserRepoFetch(uri.to_string(), "timeout".to_owned()))??;
+    match response.status() {
+        StatusCode::NOT_MODIFIED => Ok(None),
+        StatusCode::OK => {
+            let etag = response.headers().get(ETAG).cloned();
+            let snapshot = response.into_body().collect().await?.to_bytes();
+            Ok(Some((snapshot, etag)))
+        }
+        status => Err(Error::UserRepoFetch(uri.to_string(), status.to_string())),
+    }
+}
+/// Save the snapshot into a temporary file and rename it to the
+/// snapshot file, so that the snapshot is never left half written.
+fn save_snapshot(snapshot_file: &Path, snapshot: &[u8]) -> std::io::Result<()> {
+    let mut temp_snapshot_file = snapshot_file.as_os_str().to_owned();
+    temp_snapshot_file.push(".tmp");
+    std::fs::write(&temp_snapshot_file, snapshot)?;
+    std::fs::rename(&temp_snapshot_file, snapshot_file)
+}
+/// Accept the push invalidation, the `POST` request carrying the secret
+/// when configured make the users fetched again immediately.
+async fn listen_invalidation(
+    invalidation_listener: TcpListener,
+    invalidation_secret: Option<Arc<str>>,
+    invalidation: Arc<Notify>,
+) {
+    loop {
+        let (invalidation_stream, invalidation_addr) = match invalidation_listener.accept().await
+        {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Fail to accept user repository invalidation: {e:?}");
+                continue;
+            }
+        };
+        let invalidation = invalidation.clone();
+        let invalidation_secret = invalidation_secret.clone();
+        tokio::spawn(async move {
+            let invalidate = service_fn(move |request: Request<hyper::body::Incoming>| {
+                let invalidation = invalidation.clone();
+                // The secret is compared in constant time so the timing leaks nothing
+                let authorized = invalidation_secret.as_deref().is_none_or(|secret| {
+                    request
+                        .headers()
+                        .get(INVALIDATION_SECRET_HEADER)
+                        .is_some_and(|header| {
+                            bool::from(header.as_bytes().ct_eq(secret.as_bytes()))
+                        })
+                });
+                async move {
+                    let status = if request.method() != Method::POST {
+                        StatusCode::METHOD_NOT_ALLOWED
+                    } else if !authorized {
+                        warn!("Refuse unauthorized user repository invalidation from [{invalidation_addr}]");
+                        StatusCode::UNAUTHORIZED
+                    } else {
+                        debug!("Receive user repository invalidation from [{invalidation_addr}]");
+                        invalidation.notify_one();
+                        StatusCode::ACCEPTED
+                    };
+                    Response::builder().status(status).body(Empty::<Bytes>::new())
+                }
+            });
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(invalidation_stream), invalidate)
+                .await
+            {
+                error!("Fail to serve user repository invalidation: {e:?}");
+            }
+        });
+    }
+}
+#[tokio::test]
+async fn test() {
+    use crate::config::WithUserRepositoryConfig;
+    use crypto::RsaCrypto;
+    use http_body_util::Full;
+    use serde::Serialize;
+    use std::net::SocketAddr;
+    use std::path::{Path, PathBuf};
+    use std::sync::Mutex;
+    use std::time::Instant;
+    #[derive(Serialize, Deserialize, Debug)]
+    struct TestUser {
+        username: String,
+        #[serde(skip)]
+        rsa_crypto: Option<RsaCrypto>,
+        #[serde(skip)]
+        rsa_keys: Vec<UserRsaKey>,
+    }
+    impl User for TestUser {
+        fn username(&self) -> &str {
+            &self.username
+        }
+        fn rsa_crypto(&self) -> Option<&RsaCrypto> {
+            self.rsa_crypto.as_ref()
+        }
+        fn set_rsa_crypto(&mut self, rsa_crypto: RsaCrypto) {
+            self.rsa_crypto = Some(rsa_crypto)
+        }
+        fn rsa_keys(&self) -> &[UserRsaKey] {
+            &self.rsa_keys
+        }
+        fn set_rsa_keys(&mut self, rsa_keys: Vec<UserRsaKey>) {
+            self.rsa_keys = rsa_keys
+        }
+    }
+    struct TestConfig {
+        user_repo_url: String,
+        user_repo_private_key_file: PathBuf,
+        user_repo_snapshot_file: PathBuf,
+        user_repo_invalidation_address: Option<SocketAddr>,
+        user_repo_invalidation_secret: Option<String>,
+    }
+    impl WithUserRepositoryConfig for TestConfig {
+        fn refresh_interval_sec(&self) -> u64 {
+            3600
+        }
+    }
+    impl WithHttpUserRepoConfig for TestConfig {
+        fn user_repo_url(&self) -> &str {
+            &self.user_repo_url
+        }
+        fn user_repo_private_key_file(&self) -> &Path {
+            &self.user_repo_private_key_file
+        }
+        fn user_repo_snapshot_file(&self) -> &Path {
+            &self.user_repo_snapshot_file
+        }
+        fn user_repo_invalidation_address(&self) -> Option<SocketAddr> {
+            self.user_repo_invalidation_address
+        }
+        fn user_repo_invalidation_secret(&self) -> Option<&str> {
+            self.user_repo_invalidation_secret.as_deref()
+        }
+    }
+    async fn wait_until(condition: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !condition() {
+            assert!(start.elapsed() < Duration::from_secs(10), "wait timeout");
+            sleep(Duration::from_millis(20)).await;
+        }
+    }
+    let public_key_pem = include_str!("../../../../resources/proxy/user/user1/AgentPublicKey.pem");
+    let test_directory = std::env::temp_dir().join(format!(
+        "ppaass-http-user-repo-{}",
+        std::process::id()
+    ));
+    std::fs::create_dir_all(&test_directory).unwrap();
+    let private_key_file = test_directory.join("ProxyPrivateKey.pem");
+    std::fs::write(
+        &private_key_file,
+        include_str!("../../../../resources/proxy/user/user1/ProxyPrivateKey.pem"),
+    )
+    .unwrap();
+    let snapshot_file = test_directory.join("users.json");
+    // The mock endpoint serves the users with the version as etag,
+    // and it is down when no version.
+    let users = Arc::new(Mutex::new((Some(1), vec!["user1"])));
+    let requests = Arc::new(Mutex::new((0, 0)));
+    let mock_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mock_addr = mock_listener.local_addr().unwrap();
+    {
+        let users = users.clone();
+        let requests = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mock_stream, _) = mock_listener.accept().await.unwrap();
+                let users = users.clone();
+                let requests = requests.clone();
+                tokio::spawn(async move {
+                    let serve = service_fn(move |request: Request<hyper::body::Incoming>| {
+                        let (version, usernames) = users.lock().unwrap().clone();
+                        let etag = format!("\"{}\"", version.unwrap_or_default());
+                        let mut requests = requests.lock().unwrap();
+                        requests.0 += 1;
+                        let response = if version.is_none() {
+                            Response::builder()
+                                .status(StatusCode::SERVICE_UNAVAILABLE)
+                                .body(Full::new(Bytes::new()))
+                        } else if request
+                            .headers()
+                            .get(IF_NONE_MATCH)
+                            .is_some_and(|if_none_match| if_none_match == etag.as_str())
+                        {
+                            requests.1 += 1;
+                            Response::builder()
+                                .status(StatusCode::NOT_MODIFIED)
+                                .body(Full::new(Bytes::new()))
+                        } else {
+                            let users = usernames
+                                .iter()
+                                .map(|username| {
+                                    serde_json::json!({
+                                        "username": username,
+                                        "public_key_pem": public_key_pem,
+                                        "keys": [{
+                                            "id": "next",
+                                            "public_key_pem": public_key_pem,
+                                            "not_before": "2030-01-01T00:00:00Z",
+                                        }],
+                                    })
+                                })
+                                .collect::<Vec<_>>();
+                            Response::builder().header(ETAG, etag).body(Full::new(Bytes::from(
+                                serde_json::json!({ "users": users }).to_string(),
+                            )))
+                        };
+                        async move { response }
+                    });
+                    let _ = http1::Builder::new()
+                        .serve_connection(TokioIo::new(mock_stream), serve)
+                        .await;
+                });
+            }
+        });
+    }
+    let invalidation_address = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let config = |user_repo_invalidation_address, user_repo_invalidation_secret: Option<&str>| {
+        Arc::new(TestConfig {
+            user_repo_url: format!("http://{mock_addr}/users"),
+            user_repo_private_key_file: private_key_file.clone(),
+            user_repo_snapshot_file: snapshot_file.clone(),
+            user_repo_invalidation_address,
+            user_repo_invalidation_secret: user_repo_invalidation_secret.map(str::to_owned),
+        })
+    };
+    // The invalidation address reachable by others must be protected by secret
+    let public_address = SocketAddr::from(([0, 0, 0, 0], 0));
+    assert!(matches!(
+        HttpUserRepository::<TestUser, TestConfig>::new(config(Some(public_address), None)),
+        Err(Error::UserRepoInvalidationUnprotected(_))
+    ));
+    let user_repo = HttpUserRepository::<TestUser, TestConfig>::new(config(
+        Some(invalidation_address),
+        Some("test-secret"),
+    ))
+    .unwrap();
+    wait_until(|| user_repo.find_user("user1").is_some()).await;
+    let post_invalidation = |secret: Option<&'static str>| async move {
+        let client = Client::builder(TokioExecutor::new()).build_http::<Empty<Bytes>>();
+        let mut request = Request::post(format!("http://{invalidation_address}/"));
+        if let Some(secret) = secret {
+            request = request.header(INVALIDATION_SECRET_HEADER, secret);
+        }
+        client
+            .request(request.body(Empty::new()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    };
+    let invalidate = || async {
+        assert_eq!(post_invalidation(Some("test-secret")).await, StatusCode::ACCEPTED);
+    };
+    // The invalidation without the right secret is refused
+    assert_eq!(post_invalidation(None).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(post_invalidation(Some("wrong")).await, StatusCode::UNAUTHORIZED);
+    // The pushed invalidation fetch the modified users
+    *users.lock().unwrap() = (Some(2), vec!["user2"]);
+    invalidate().await;
+    wait_until(|| user_repo.find_user("user2").is_some()).await;
+    assert!(user_repo.find_user("user1").is_none());
+    // The users not modified are skipped by etag
+    invalidate().await;
+    wait_until(|| requests.lock().unwrap().1 == 1).await;
+    assert_eq!(user_repo.list_users().len(), 1);
+    // The last good users are kept when the endpoint is down
+    users.lock().unwrap().0 = None;
+    let requests_before_down = requests.lock().unwrap().0;
+    invalidate().await;
+    wait_until(|| requests.lock().unwrap().0 > requests_before_down).await;
+    sleep(Duration::from_millis(50)).await;
+    assert!(user_repo.find_user("user2").is_some());
+    // The new repository start from the snapshot when the endpoint is down
+    let restarted_user_repo =
+        HttpUserRepository::<TestUser, TestConfig>::new(config(None, None)).unwrap();
+    let restarted_user = restarted_user_repo.find_user("user2").unwrap();
+    assert!(restarted_user.rsa_crypto().is_some());
+    // The rotated key is loaded after the primary key with its validity
+    let rsa_keys = restarted_user.rsa_keys();
+    assert_eq!(rsa_keys.len(), 2);
+    assert_eq!(rsa_keys[1].id(), "next");
+    assert!(!rsa_keys[1].is_active(&Utc::now()));
+    std::fs::remove_dir_all(&test_directory).unwrap();
+}
@@ -0,0 +1,615 @@
+use super::{UserRepositoryDiff, UserRsaKeyFiles, list_user_directories};
+use crate::Error;
+use crate::config::{WithFileSystemUserRepoConfig, WithSqliteUserRepoConfig};
+use crate::user::{PRIMARY_USER_RSA_KEY_ID, User, UserRepository, UserRsaKey};
+use chrono::{DateTime, Utc};
+use crypto::RsaCrypto;
+use rusqlite::types::Type;
+use rusqlite::{Connection, Row, params};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::Duration;
+use tokio::time::sleep;
+use toml::{Table, Value};
+use tracing::{error, info, warn};
+/// The fields of the user info have own column, the other
+/// fields are kept in the `user_info` column as toml.
+const CREATE_USERS_TABLE: &str = "CREATE TABLE IF NOT EXISTS users (
+    username TEXT PRIMARY KEY NOT NULL,
+    public_key_pem TEXT NOT NULL,
+    private_key_pem TEXT NOT NULL,
+    expired_time TEXT,
+    upload_rate_limit INTEGER,
+    download_rate_limit INTEGER,
+    monthly_traffic_quota INTEGER,
+    max_sessions INTEGER,
+    proxy_servers TEXT NOT NULL DEFAULT '',
+    user_info TEXT NOT NULL DEFAULT ''
+)";
+const SELECT_USERS: &str = "SELECT username, public_key_pem, private_key_pem, expired_time,
+    upload_rate_limit, download_rate_limit, monthly_traffic_quota, max_sessions,
+    proxy_servers, user_info FROM users";
+const UPSERT_USER: &str = "INSERT INTO users (username, public_key_pem, private_key_pem,
+    expired_time, upload_rate_limit, download_rate_limit, monthly_traffic_quota, max_sessions,
+    proxy_servers, user_info) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+    ON CONFLICT(username) DO UPDATE SET public_key_pem = excluded.public_key_pem,
+    private_key_pem = excluded.private_key_pem, expired_time = excluded.expired_time,
+    upload_rate_limit = excluded.upload_rate_limit,
+    download_rate_limit = excluded.download_rate_limit,
+    monthly_traffic_quota = excluded.monthly_traffic_quota,
+    max_sessions = excluded.max_sessions, proxy_servers = excluded.proxy_servers,
+    user_info = excluded.user_info";
+/// The rotated keys of the users, the primary key stays in the users table
+const CREATE_USER_RSA_KEYS_TABLE: &str = "CREATE TABLE IF NOT EXISTS user_rsa_keys (
+    username TEXT NOT NULL,
+    id TEXT NOT NULL,
+    public_key_pem TEXT NOT NULL,
+    private_key_pem TEXT NOT NULL,
+    not_before TEXT,
+    not_after TEXT,
+    PRIMARY KEY (username, id)
+)";
+const SELECT_USER_RSA_KEYS: &str = "SELECT username, id, public_key_pem, private_key_pem,
+    not_before, not_after FROM user_rsa_keys ORDER BY username, id";
+const DELETE_USER_RSA_KEYS: &str = "DELETE FROM user_rsa_keys WHERE username = ?1";
+const INSERT_USER_RSA_KEY: &str = "INSERT INTO user_rsa_keys (username, id, public_key_pem,
+    private_key_pem, not_before, not_after) VALUES (?1, ?2, ?3, ?4, ?5, ?6)";
+/// The integer columns of the limits of the user
+const LIMIT_COLUMNS: [&str; 4] = [
+    "upload_rate_limit",
+    "download_rate_limit",
+    "monthly_traffic_quota",
+    "max_sessions",
+];
+/// The rotated key stored in one row of the keys table, the
+/// validity window is kept in RFC 3339 format.
+#[derive(Hash, Debug, PartialEq)]
+struct UserRsaKeyRecord {
+    id: String,
+    public_key_pem: String,
+    private_key_pem: String,
+    not_before: Option<String>,
+    not_after: Option<String>,
+}
+impl UserRsaKeyRecord {
+    /// Read the key together with the username it belongs to
+    fn read(row: &Row) -> Result<(String, Self), rusqlite::Error> {
+        Ok((
+            row.get(0)?,
+            Self {
+                id: row.get(1)?,
+                public_key_pem: row.get(2)?,
+                private_key_pem: row.get(3)?,
+                not_before: row.get(4)?,
+                not_after: row.get(5)?,
+            },
+        ))
+    }
+    fn of(rsa_key: &UserRsaKey) -> Result<Self, Error> {
+        Ok(Self {
+            id: rsa_key.id().to_owned(),
+            public_key_pem: rsa_key.rsa_crypto().public_key_pem()?,
+            private_key_pem: rsa_key.rsa_crypto().private_key_pem()?,
+            not_before: rsa_key.not_before().map(DateTime::to_rfc3339),
+            not_after: rsa_key.not_after().map(DateTime::to_rfc3339),
+        })
+    }
+    fn parse(&self) -> Result<UserRsaKey, Error> {
+        let parse_time = |time: &Option<String>| {
+            time.as_deref()
+                .map(|time| {
+                    DateTime::parse_from_rfc3339(time).map(|time| time.with_timezone(&Utc))
+                })
+                .transpose()
+                .map_err(|e| {
+                    Error::InvalidUserInfo(format!("invalid validity of key [{}]: {e}", self.id))
+                })
+        };
+        Ok(UserRsaKey::new(
+            self.id.clone(),
+            parse_time(&self.not_before)?,
+            parse_time(&self.not_after)?,
+            RsaCrypto::new(self.public_key_pem.as_bytes(), self.private_key_pem.as_bytes())?,
+        ))
+    }
+}
+/// The user stored in one row of the users table
+struct UserRecord {
+    public_key_pem: String,
+    private_key_pem: String,
+    /// The whole user info, include the fields have own column
+    user_info: Table,
+    /// The rotated keys stored in the keys table
+    rotated_keys: Vec<UserRsaKeyRecord>,
+}
+impl UserRecord {
+    fn username(&self) -> Option<&str> {
+        self.user_info.get("username")?.as_str()
+    }
+    /// The hash of the keys, to reuse the parsed rsa keys
+    fn key_fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        (&self.public_key_pem, &self.private_key_pem, &self.rotated_keys).hash(&mut hasher);
+        hasher.finish()
+    }
+    /// The hash of the whole record, to find the changed users
+    fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        (self.key_fingerprint(), self.user_info.to_string()).hash(&mut hasher);
+        hasher.finish()
+    }
+    fn read(row: &Row) -> Result<Self, rusqlite::Error> {
+        let user_info_content: String = row.get(9)?;
+        let mut user_info = toml::from_str::<Table>(&user_info_content)
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(9, Type::Text, Box::new(e)))?;
+        user_info.insert("username".to_owned(), Value::String(row.get(0)?));
+        if let Some(expired_time) = row.get::<_, Option<String>>(3)? {
+            user_info.insert("expired_time".to_owned(), Value::String(expired_time));
+        }
+        for (index, column) in LIMIT_COLUMNS.iter().enumerate() {
+            if let Some(limit) = row.get::<_, Option<i64>>(index + 4)? {
+                user_info.insert(column.to_string(), Value::Integer(limit));
+            }
+        }
+        let proxy_servers: String = row.get(8)?;
+        user_info.insert(
+            "proxy_servers".to_owned(),
+            Value::Array(
+                proxy_servers
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|proxy_server| !proxy_server.is_empty())
+                    .map(|proxy_server| Value::String(proxy_server.to_owned()))
+                    .collect(),
+            ),
+        );
+        Ok(Self {
+            public_key_pem: row.get(1)?,
+            private_key_pem: row.get(2)?,
+            user_info,
+            rotated_keys: Vec::new(),
+        })
+    }
+    /// Insert the record, or replace the one with same username,
+    /// the rotated keys of the user are replaced together.
+    fn save(&self, connection: &Connection) -> Result<(), Error> {
+        let mut user_info = self.user_info.clone();
+        let username = match user_info.remove("username") {
+            Some(Value::String(username)) => username,
+            _ => {
+                return Err(Error::InvalidUserInfo(
+                    "the username is missing".to_owned(),
+                ));
+            }
+        };
+        let expired_time = match user_info.remove("expired_time") {
+            Some(Value::String(expired_time)) => Some(expired_time),
+            Some(Value::Datetime(expired_time)) => Some(expired_time.to_string()),
+            _ => None,
+        };
+        let [upload_rate_limit, download_rate_limit, monthly_traffic_quota, max_sessions] =
+            LIMIT_COLUMNS.map(|column| {
+                user_info
+                    .remove(column)
+                    .and_then(|limit| limit.as_integer())
+            });
+        let proxy_servers = match user_info.remove("proxy_servers") {
+            Some(Value::Array(proxy_servers)) => proxy_servers
+                .iter()
+                .filter_map(Value::as_str)
+                .collect::<Vec<_>>()
+                .join(","),
+            _ => String::new(),
+        };
+        connection.execute(
+            UPSERT_USER,
+            params![
+                username,
+                self.public_key_pem,
+                self.private_key_pem,
+                expired_time,
+                upload_rate_limit,
+                download_rate_limit,
+                monthly_traffic_quota,
+                max_sessions,
+                proxy_servers,
+                toml::to_string(&user_info)?,
+            ],
+        )?;
+        connection.execute(DELETE_USER_RSA_KEYS, params![username])?;
+        for rotated_key in &self.rotated_keys {
+            connection.execute(
+                INSERT_USER_RSA_KEY,
+                params![
+                    username,
+                    rotated_key.id,
+                    rotated_key.public_key_pem,
+                    rotated_key.private_key_pem,
+                    rotated_key.not_before,
+                    rotated_key.not_after,
+                ],
+            )?;
+        }
+        Ok(())
+    }
+}
+/// The user loaded from the database last time
+struct LoadedUser<U> {
+    user: Arc<U>,
+    fingerprint: u64,
+    key_fingerprint: u64,
+    /// The parsed keys with the primary one first, parsing the keys is much
+    /// slower than the user info so they are parsed again only when changed.
+    rsa_keys: Vec<UserRsaKey>,
+}
+/// The user repository stored in sqlite database, the users are
+/// cached in memory and reloaded from the database periodically.
+#[derive(Debug)]
+pub struct SqliteUserRepository<U, C>
+where
+    U: User + Send + Sync + Serialize + DeserializeOwned + 'static,
+    C: WithSqliteUserRepoConfig + Send + Sync + 'static,
+{
+    connection: Arc<Mutex<Connection>>,
+    storage: Arc<RwLock<HashMap<String, Arc<U>>>>,
+    _config_mark: PhantomData<C>,
+}
+impl<U, C> SqliteUserRepository<U, C>
+where
+    U: User + Send + Sync + Serialize + DeserializeOwned + 'static,
+    C: WithSqliteUserRepoConfig + Send + Sync + 'static,
+{
+    fn lock_connection(connection: &Mutex<Connection>) -> MutexGuard<'_, Connection> {
+        connection
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    /// Create the user from the record, the keys are parsed
+    /// only when they changed after the previous load.
+    fn load_user(
+        record: UserRecord,
+        previous: Option<&LoadedUser<U>>,
+    ) -> Result<LoadedUser<U>, Error> {
+        let fingerprint = record.fingerprint();
+        let key_fingerprint = record.key_fingerprint();
+        let rsa_keys = match previous {
+            Some(previous) if previous.key_fingerprint == key_fingerprint => {
+                previous.rsa_keys.clone()
+            }
+            _ => {
+                let mut rsa_keys = vec![UserRsaKey::primary(RsaCrypto::new(
+                    record.public_key_pem.as_bytes(),
+                    record.private_key_pem.as_bytes(),
+                )?)];
+                for rotated_key in &record.rotated_keys {
+                    rsa_keys.push(rotated_key.parse()?);
+                }
+                rsa_keys
+            }
+        };
+        let mut user_info: U = Value::Table(record.user_info)
+            .try_into()
+            .map_err(|e: toml::de::Error| Error::InvalidUserInfo(e.to_string()))?;
+        user_info.set_rsa_crypto(rsa_keys[0].rsa_crypto().clone());
+        user_info.set_rsa_keys(rsa_keys.clone());
+        Ok(LoadedUser {
+            user: Arc::new(user_info),
+            fingerprint,
+            key_fingerprint,
+            rsa_keys,
+        })
+    }
+    /// Reload all the users from the database and swap them in, the
+    /// user fail to load keeps the one loaded last time.
+    fn reload_storage(
+        connection: &Mutex<Connection>,
+        storage: &RwLock<HashMap<String, Arc<U>>>,
+        loaded_users: &mut HashMap<String, LoadedUser<U>>,
+    ) -> Result<(), Error> {
+        let (records, mut rotated_keys) = {
+            let connection = Self::lock_connection(connection);
+            let records = connection
+                .prepare(SELECT_USERS)?
+                .query_map([], UserRecord::read)?
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut rotated_keys = HashMap::<String, Vec<UserRsaKeyRecord>>::new();
+            for rotated_key in connection
+                .prepare(SELECT_USER_RSA_KEYS)?
+                .query_map([], UserRsaKeyRecord::read)?
+            {
+                let (username, rotated_key) = rotated_key?;
+                rotated_keys.entry(username).or_default().push(rotated_key);
+            }
+            (records, rotated_keys)
+        };
+        let fingerprints = |users: &HashMap<String, LoadedUser<U>>| {
+            users
+                .iter()
+                .map(|(username, loaded_user)| (username.clone(), loaded_user.fingerprint))
+                .collect::<HashMap<_, _>>()
+        };
+        let previous_fingerprints = fingerprints(loaded_users);
+        let mut current_users = HashMap::new();
+        for mut record in records {
+            let Some(username) = record.username().map(str::to_owned) else {
+                continue;
+            };
+            record.rotated_keys = rotated_keys.remove(&username).unwrap_or_default();
+            let previous = loaded_users.remove(&username);
+            let previous = match previous {
+                Some(previous) if previous.fingerprint == record.fingerprint() => {
+                    current_users.insert(username, previous);
+                    continue;
+                }
+                previous => previous,
+            };
+            match Self::load_user(record, previous.as_ref()) {
+                Ok(loaded_user) => {
+                    current_users.insert(username, loaded_user);
+                }
+                Err(e) => {
+                    error!("Fail to load user [{username}] from database: {e:?}");
+                    if let Some(previous) = previous {
+                        warn!("Keep the last loaded user [{username}]");
+                        current_users.insert(username, previous);
+                    }
+                }
+            }
+        }
+        let diff = UserRepositoryDiff::new(&previous_fingerprints, &fingerprints(&current_users));
+        let current_storage = current_users
+            .iter()
+            .map(|(username, loaded_user)| (username.clone(), loaded_user.user.clone()))
+            .collect();
+        *storage.write().map_err(|e| {
+            Error::Lock(format!(
+                "Fail to lock user repository because of error: {e:?}"
+            ))
+        })? = current_storage;
+        *loaded_users = current_users;
+        if !diff.is_empty() {
+            info!(
+                "User repository reloaded, added: {:?}, removed: {:?}, changed: {:?}",
+                diff.added, diff.removed, diff.changed
+            );
+        }
+        Ok(())
+    }
+}
+impl<U, C> UserRepository for SqliteUserRepository<U, C>
+where
+    U: User + Send + Sync + Serialize + DeserializeOwned + 'static,
+    C: WithSqliteUserRepoConfig + Send + Sync + 'static,
+{
+    type UserInfoType = U;
+    type UserRepoConfigType = C;
+    fn new<T>(config: T) -> Result<Self, Error>
+    where
+        T: Deref<Target = Self::UserRepoConfigType> + Send + Sync + 'static,
+    {
+        let connection = open_database(&*config)?;
+        let connection = Arc::new(Mutex::new(connection));
+        let storage = Arc::new(RwLock::new(HashMap::new()));
+        let mut loaded_users = HashMap::new();
+        if let Err(e) = Self::reload_storage(&connection, &storage, &mut loaded_users) {
+            error!("Failed to fill user repository storage: {}", e);
+        };
+        let connection_clone = connection.clone();
+        let storage_clone = storage.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(Duration::from_secs(config.refresh_interval_sec())).await;
+                if let Err(e) =
+                    Self::reload_storage(&connection_clone, &storage_clone, &mut loaded_users)
+                {
+                    error!("Failed to fill user repository storage: {}", e);
+                };
+            }
+        });
+        Ok(Self {
+            connection,
+            storage,
+            _config_mark: Default::default(),
+        })
+    }
+    fn find_user(&self, username: &str) -> Option<Arc<Self::UserInfoType>> {
+        let lock = match self.storage.read() {
+            Ok(guard) => guard,
+            Err(e) => {
+                error!("Fail to lock user storage: {e:?}");
+                return None;
+            }
+        };
+        let user_info = lock.get(username)?;
+        Some(user_info.clone())
+    }
+    fn list_users(&self) -> Vec<Arc<Self::UserInfoType>> {
+        let lock = match self.storage.read() {
+            Ok(guard) => guard,
+            Err(e) => {
+                error!("Fail to lock user storage: {e:?}");
+                return vec![];
+            }
+        };
+        lock.values().cloned().collect()
+    }
+    fn save_user(&self, user: Self::UserInfoType) {
+        let record = match user_record_of(&user) {
+            Ok(record) => record,
+            Err(e) => {
+                error!("Fail to save user [{}]: {e:?}", user.username());
+                return;
+            }
+        };
+        if let Err(e) = record.save(&Self::lock_connection(&self.connection)) {
+            error!("Fail to save user [{}]: {e:?}", user.username());
+            return;
+        }
+        let mut lock = match self.storage.write() {
+            Ok(guard) => guard,
+            Err(e) => {
+                error!("Fail to lock user storage: {e:?}");
+                return;
+            }
+        };
+        lock.insert(user.username().to_owned(), Arc::new(user));
+    }
+}
+fn user_record_of<U>(user: &U) -> Result<UserRecord, Error>
+where
+    U: User + Serialize,
+{
+    let rsa_crypto = user
+        .rsa_crypto()
+        .ok_or(Error::UserRsaCryptoNotExist(user.username().to_owned()))?;
+    Ok(UserRecord {
+        public_key_pem: rsa_crypto.public_key_pem()?,
+        private_key_pem: rsa_crypto.private_key_pem()?,
+        user_info: Table::try_from(user)?,
+        rotated_keys: user
+            .rsa_keys()
+            .iter()
+            .filter(|rsa_key| rsa_key.id() != PRIMARY_USER_RSA_KEY_ID)
+            .map(UserRsaKeyRecord::of)
+            .collect::<Result<_, _>>()?,
+    })
+}
+/// Open the database and create the tables when not exist
+fn open_database<C>(config: &C) -> Result<Connection, Error>
+where
+    C: WithSqliteUserRepoConfig + ?Sized,
+{
+    let database_path = config.user_repo_database();
+    if let Some(database_directory) = database_path.parent() {
+        std::fs::create_dir_all(database_directory)?;
+    }
+    let connection = Connection::open(database_path)?;
+    connection.execute(CREATE_USERS_TABLE, [])?;
+    connection.execute(CREATE_USER_RSA_KEYS_TABLE, [])?;
+    Ok(connection)
+}
+/// Import the users of the file system user repository into the sqlite
+/// user repository, the users already in the database are replaced. The
+/// rotated keys in the keys directory are imported with their validity.
+/// Return the usernames imported.
+pub fn import_file_system_user_repo<C>(config: &C) -> Result<Vec<String>, Error>
+where
+    C: WithFileSystemUserRepoConfig + WithSqliteUserRepoConfig,
+{
+    let mut connection = open_database(config)?;
+    let transaction = connection.transaction()?;
+    let mut imported_usernames = Vec::new();
+    for user_dir_path in list_user_directories(config.user_repo_directory())? {
+        let read_record = || -> Result<UserRecord, Error> {
+            let mut all_key_files = UserRsaKeyFiles::read_all(config, &user_dir_path)?.into_iter();
+            let primary_key_files = all_key_files.next().ok_or(Error::InvalidUserInfo(
+                "the primary key is missing".to_owned(),
+            ))?;
+            // Make sure only the valid keys are imported
+            primary_key_files.parse()?;
+            let rotated_keys = all_key_files
+                .map(|key_files| {
+                    let rsa_key = key_files.parse()?;
+                    Ok(UserRsaKeyRecord {
+                        id: key_files.id,
+                        public_key_pem: key_files.public_key,
+                        private_key_pem: key_files.private_key,
+                        not_before: rsa_key.not_before().map(DateTime::to_rfc3339),
+                        not_after: rsa_key.not_after().map(DateTime::to_rfc3339),
+                    })
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+            let user_info = toml::from_str::<Table>(&std::fs::read_to_string(
+                user_dir_path.join(config.user_info_file_name()),
+            )?)?;
+            Ok(UserRecord {
+                public_key_pem: primary_key_files.public_key,
+                private_key_pem: primary_key_files.private_key,
+                user_info,
+                rotated_keys,
+            })
+        };
+        let record = match read_record() {
+            Ok(record) => record,
+            Err(e) => {
+                error!("Fail to import user from directory [{user_dir_path:?}]: {e:?}");
+                continue;
+            }
+        };
+        record.save(&transaction)?;
+        if let Some(username) = record.username() {
+            imported_usernames.push(username.to_owned());
+        }
+    }
+    transaction.commit()?;
+    imported_usernames.sort();
+    Ok(imported_usernames)
+}
+#[test]
+fn test() {
+    let connection = Connection::open_in_memory().unwrap();
+    connection.execute(CREATE_USERS_TABLE, []).unwrap();
+    connection.execute(CREATE_USER_RSA_KEYS_TABLE, []).unwrap();
+    let user_info = toml::from_str::<Table>(
+        r#"
+        username = "user1"
+        expired_time = "2030-01-01T00:00:00Z"
+        monthly_traffic_quota = 1073741824
+        proxy_servers = ["10.0.0.1:80", "10.0.0.2:80"]
+        [destination_acl]
+        allow = [{ ip_cidr = "10.1.0.0/16" }]
+        "#,
+    )
+    .unwrap();
+    let record = UserRecord {
+        public_key_pem: "public key".to_owned(),
+        private_key_pem: "private key".to_owned(),
+        user_info,
+        rotated_keys: vec![UserRsaKeyRecord {
+            id: "next".to_owned(),
+            public_key_pem: "next public key".to_owned(),
+            private_key_pem: "next private key".to_owned(),
+            not_before: Some("2030-01-01T00:00:00+00:00".to_owned()),
+            not_after: None,
+        }],
+    };
+    record.save(&connection).unwrap();
+    // Save again replace the existing one
+    record.save(&connection).unwrap();
+    let monthly_traffic_quota: i64 = connection
+        .query_row(
+            "SELECT monthly_traffic_quota FROM users WHERE username = 'user1'",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(monthly_traffic_quota, 1073741824);
+    let mut loaded_records = connection
+        .prepare(SELECT_USERS)
+        .unwrap()
+        .query_map([], UserRecord::read)
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(loaded_records.len(), 1);
+    assert_eq!(loaded_records[0].user_info, record.user_info);
+    // Save again replace the rotated keys instead of adding them
+    let (usernames, rotated_keys): (Vec<_>, Vec<_>) = connection
+        .prepare(SELECT_USER_RSA_KEYS)
+        .unwrap()
+        .query_map([], UserRsaKeyRecord::read)
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap()
+        .into_iter()
+        .unzip();
+    assert_eq!(usernames, ["user1"]);
+    assert_eq!(rotated_keys, record.rotated_keys);
+    loaded_records[0].rotated_keys = rotated_keys;
+    assert_eq!(loaded_records[0].fingerprint(), record.fingerprint());
+}
@@ -0,0 +1,55 @@
+use crate::error::Error;
+use crate::{counter_nonce, random_n_bytes};
+use aes_gcm::aead::Aead;
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use bytes::Bytes;
+/// Generate the encryption token for AES-GCM
+/// The 32 bytes is the key, the nonce is the
+/// frame counter so it is not part of the token
+#[inline(always)]
+pub fn generate_aes_gcm_encryption_token() -> Vec<u8> {
+    random_n_bytes::<32>()
+}
+/// Encrypt the target bytes with AES-GCM, the nonce
+/// must never be reused with the same token
+#[inline(always)]
+pub fn encrypt_with_aes_gcm(
+    encryption_token: &[u8],
+    nonce: u64,
+    target: &[u8],
+) -> Result<Bytes, Error> {
+    let cipher = Aes256Gcm::new_from_slice(encryption_token)?;
+    let result = cipher
+        .encrypt(Nonce::from_slice(&counter_nonce(nonce)), target)
+        .map_err(|_| Error::AeadEncrypt)?;
+    Ok(result.into())
+}
+/// Decrypt the target bytes with AES-GCM, fail when the
+/// bytes are tampered or the nonce is not the expected one
+#[inline(always)]
+pub fn decrypt_with_aes_gcm(
+    encryption_token: &[u8],
+    nonce: u64,
+    target: &[u8],
+) -> Result<Bytes, Error> {
+    let cipher = Aes256Gcm::new_from_slice(encryption_token)?;
+    let result = cipher
+        .decrypt(Nonce::from_slice(&counter_nonce(nonce)), target)
+        .map_err(|_| Error::AeadDecrypt(nonce))?;
+    Ok(result.into())
+}
+#[test]
+fn test() -> Result<(), Error> {
+    let encryption_token = generate_aes_gcm_encryption_token();
+    let target = "hello world! this is my plaintext.".as_bytes().to_vec();
+    let encrypt_result = encrypt_with_aes_gcm(&encryption_token, 0, &target)?;
+    let decrypted_result = decrypt_with_aes_gcm(&encryption_token, 0, &encrypt_result)?;
+    assert_eq!(target, decrypted_result.to_vec());
+    // The frame out of order can not be decrypted
+    assert!(decrypt_with_aes_gcm(&encryption_token, 1, &encrypt_result).is_err());
+    // The tampered frame can not be decrypted
+    let mut tampered_result = encrypt_result.to_vec();
+    tampered_result[0] ^= 1;
+    assert!(decrypt_with_aes_gcm(&encryption_token, 0, &tampered_result).is_err());
+    Ok(())
+}
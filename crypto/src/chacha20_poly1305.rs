@@ -0,0 +1,50 @@
+use crate::error::Error;
+use crate::{counter_nonce, random_n_bytes};
+use bytes::Bytes;
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce};
+/// Generate the encryption token for ChaCha20-Poly1305
+/// The 32 bytes is the key, the nonce is the
+/// frame counter so it is not part of the token
+#[inline(always)]
+pub fn generate_chacha20_poly1305_encryption_token() -> Vec<u8> {
+    random_n_bytes::<32>()
+}
+/// Encrypt the target bytes with ChaCha20-Poly1305, the nonce
+/// must never be reused with the same token
+#[inline(always)]
+pub fn encrypt_with_chacha20_poly1305(
+    encryption_token: &[u8],
+    nonce: u64,
+    target: &[u8],
+) -> Result<Bytes, Error> {
+    let cipher = ChaCha20Poly1305::new_from_slice(encryption_token)?;
+    let result = cipher
+        .encrypt(Nonce::from_slice(&counter_nonce(nonce)), target)
+        .map_err(|_| Error::AeadEncrypt)?;
+    Ok(result.into())
+}
+/// Decrypt the target bytes with ChaCha20-Poly1305, fail when the
+/// bytes are tampered or the nonce is not the expected one
+#[inline(always)]
+pub fn decrypt_with_chacha20_poly1305(
+    encryption_token: &[u8],
+    nonce: u64,
+    target: &[u8],
+) -> Result<Bytes, Error> {
+    let cipher = ChaCha20Poly1305::new_from_slice(encryption_token)?;
+    let result = cipher
+        .decrypt(Nonce::from_slice(&counter_nonce(nonce)), target)
+        .map_err(|_| Error::AeadDecrypt(nonce))?;
+    Ok(result.into())
+}
+#[test]
+fn test() -> Result<(), Error> {
+    let encryption_token = generate_chacha20_poly1305_encryption_token();
+    let target = "hello world! this is my plaintext.".as_bytes().to_vec();
+    let encrypt_result = encrypt_with_chacha20_poly1305(&encryption_token, 7, &target)?;
+    let decrypted_result = decrypt_with_chacha20_poly1305(&encryption_token, 7, &encrypt_result)?;
+    assert_eq!(target, decrypted_result.to_vec());
+    assert!(decrypt_with_chacha20_poly1305(&encryption_token, 8, &encrypt_result).is_err());
+    Ok(())
+}
@@ -14,4 +14,19 @@ pub enum Error {
     Pkcs8(#[from] pkcs8::Error),
     #[error(transparent)]
     Io(#[from] std::io::Error),
+    #[error("Invalid key exchange public key length: {0}")]
+    InvalidPublicKey(usize),
+    #[error("The key exchange is not contributory")]
+    NonContributoryKeyExchange,
+    #[error("Fail to encrypt frame with AEAD cipher")]
+    AeadEncrypt,
+    #[error("Fail to decrypt frame [{0}], the frame is tampered or out of order")]
+    AeadDecrypt(u64),
+    #[error("Fail to expand key material: {0}")]
+    KeyExpand(hkdf::InvalidLength),
+}
+impl From<hkdf::InvalidLength> for Error {
+    fn from(value: hkdf::InvalidLength) -> Self {
+        Error::KeyExpand(value)
+    }
 }
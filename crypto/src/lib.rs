@@ -1,14 +1,28 @@
 mod aes;
+mod aes_gcm;
 mod blowfish;
+mod chacha20_poly1305;
 mod error;
 mod rsa;
+mod x25519;
 pub use aes::*;
+pub use aes_gcm::*;
 pub use blowfish::*;
+pub use chacha20_poly1305::*;
 pub use error::Error;
 use rand::random;
 pub use rsa::*;
+pub use x25519::*;
 #[inline(always)]
 fn random_n_bytes<const N: usize>() -> Vec<u8> {
     let random_n_bytes = random::<[u8; N]>();
     random_n_bytes.to_vec()
 }
+/// Build the 96 bits AEAD nonce from the frame counter,
+/// the counter is put in the last 8 bytes with big endian.
+#[inline(always)]
+fn counter_nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
@@ -4,10 +4,11 @@ pub use rsa::pkcs8::EncodePublicKey;
 pub use rsa::pkcs8::LineEnding;
 pub use rsa::rand_core::OsRng;
 use rsa::{
-    Pkcs1v15Encrypt,
+    Pkcs1v15Encrypt, Pkcs1v15Sign,
     pkcs8::{DecodePrivateKey, DecodePublicKey},
 };
 pub use rsa::{RsaPrivateKey, RsaPublicKey};
+use sha2::{Digest, Sha256};
 use std::fmt::Debug;
 use std::io::Read;
 pub const DEFAULT_AGENT_PRIVATE_KEY_PATH: &str = "AgentPrivateKey.pem";
@@ -15,7 +16,7 @@ pub const DEFAULT_AGENT_PUBLIC_KEY_PATH: &str = "AgentPublicKey.pem";
 pub const DEFAULT_PROXY_PRIVATE_KEY_PATH: &str = "ProxyPrivateKey.pem";
 pub const DEFAULT_PROXY_PUBLIC_KEY_PATH: &str = "ProxyPublicKey.pem";
 /// The util to do RSA encryption and decryption.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct RsaCrypto {
     /// The private used to do decryption
     private_key: RsaPrivateKey,
@@ -39,6 +40,14 @@ impl RsaCrypto {
             private_key,
         })
     }
+    /// The public key in PEM format
+    pub fn public_key_pem(&self) -> Result<String, Error> {
+        Ok(self.public_key.to_public_key_pem(LineEnding::LF)?)
+    }
+    /// The private key in PKCS#8 PEM format
+    pub fn private_key_pem(&self) -> Result<String, Error> {
+        Ok(self.private_key.to_pkcs8_pem(LineEnding::LF)?.to_string())
+    }
     /// Encrypt the target bytes with RSA public key
     pub fn encrypt(&self, target: &[u8]) -> Result<Vec<u8>, Error> {
         let result = self
@@ -51,4 +60,19 @@ impl RsaCrypto {
         let result = self.private_key.decrypt(Pkcs1v15Encrypt, target.as_ref())?;
         Ok(result)
     }
+    /// Sign the SHA-256 digest of target bytes with RSA private key
+    pub fn sign(&self, target: &[u8]) -> Result<Vec<u8>, Error> {
+        let digest = Sha256::digest(target);
+        let result = self
+            .private_key
+            .sign(Pkcs1v15Sign::new::<Sha256>(), &digest)?;
+        Ok(result)
+    }
+    /// Verify the signature of target bytes with RSA public key
+    pub fn verify(&self, target: &[u8], signature: &[u8]) -> Result<(), Error> {
+        let digest = Sha256::digest(target);
+        self.public_key
+            .verify(Pkcs1v15Sign::new::<Sha256>(), &digest, signature)?;
+        Ok(())
+    }
 }
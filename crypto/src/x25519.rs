@@ -0,0 +1,77 @@
+use crate::error::Error;
+use hkdf::Hkdf;
+use rsa::rand_core::OsRng;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret};
+/// The length of the X25519 public key
+pub const X25519_PUBLIC_KEY_LENGTH: usize = 32;
+/// The ephemeral X25519 key pair, the secret part
+/// is consumed by the key agreement so it can only be
+/// used for one handshake.
+pub struct X25519KeyExchange {
+    secret: EphemeralSecret,
+    public_key: PublicKey,
+}
+impl X25519KeyExchange {
+    /// Generate a new ephemeral key pair
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public_key = PublicKey::from(&secret);
+        Self { secret, public_key }
+    }
+    /// The public key which should be sent to the peer
+    pub fn public_key(&self) -> Vec<u8> {
+        self.public_key.as_bytes().to_vec()
+    }
+    /// Do the key agreement with the peer public key, the salt
+    /// is used to bind the derived keys to the handshake transcript.
+    pub fn agree(self, peer_public_key: &[u8], salt: &[u8]) -> Result<X25519SharedKey, Error> {
+        let peer_public_key: [u8; X25519_PUBLIC_KEY_LENGTH] = peer_public_key
+            .try_into()
+            .map_err(|_| Error::InvalidPublicKey(peer_public_key.len()))?;
+        let shared_secret: SharedSecret =
+            self.secret.diffie_hellman(&PublicKey::from(peer_public_key));
+        if !shared_secret.was_contributory() {
+            return Err(Error::NonContributoryKeyExchange);
+        }
+        Ok(X25519SharedKey {
+            hkdf: Hkdf::<Sha256>::new(Some(salt), shared_secret.as_bytes()),
+        })
+    }
+}
+impl Default for X25519KeyExchange {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+/// The shared key after X25519 key agreement
+pub struct X25519SharedKey {
+    hkdf: Hkdf<Sha256>,
+}
+impl X25519SharedKey {
+    /// Expand the shared key into key material with HKDF-SHA256,
+    /// different info generate independent keys.
+    pub fn expand(&self, info: &[u8], length: usize) -> Result<Vec<u8>, Error> {
+        let mut key_material = vec![0u8; length];
+        self.hkdf.expand(info, &mut key_material)?;
+        Ok(key_material)
+    }
+}
+#[test]
+fn test() -> Result<(), Error> {
+    let client_key_exchange = X25519KeyExchange::new();
+    let server_key_exchange = X25519KeyExchange::new();
+    let client_public_key = client_key_exchange.public_key();
+    let server_public_key = server_key_exchange.public_key();
+    let client_shared_key = client_key_exchange.agree(&server_public_key, b"salt")?;
+    let server_shared_key = server_key_exchange.agree(&client_public_key, b"salt")?;
+    assert_eq!(
+        client_shared_key.expand(b"client", 48)?,
+        server_shared_key.expand(b"client", 48)?
+    );
+    assert_ne!(
+        client_shared_key.expand(b"client", 48)?,
+        client_shared_key.expand(b"server", 48)?
+    );
+    Ok(())
+}
@@ -1,29 +1,164 @@
 use crate::address::UnifiedAddress;
 use bincode::{Decode, Encode};
+use std::net::SocketAddr;
+/// The version of the protocol, it must be changed
+/// when the layout of any packet is changed.
+pub const PROTOCOL_VERSION: u16 = 3;
 #[derive(Debug, Encode, Decode, Clone)]
 pub enum Encryption {
     Plain,
     Aes(Vec<u8>),
     Blowfish(Vec<u8>),
+    /// AES-256-GCM, the nonce is the frame counter
+    AesGcm(Vec<u8>),
+    /// ChaCha20-Poly1305, the nonce is the frame counter
+    ChaCha20Poly1305(Vec<u8>),
 }
+impl Encryption {
+    /// The cipher suite of the encryption, the
+    /// legacy ciphers are not negotiable
+    pub fn cipher_suite(&self) -> Option<CipherSuite> {
+        match self {
+            Encryption::AesGcm(_) => Some(CipherSuite::AesGcm),
+            Encryption::ChaCha20Poly1305(_) => Some(CipherSuite::ChaCha20Poly1305),
+            Encryption::Plain | Encryption::Aes(_) | Encryption::Blowfish(_) => None,
+        }
+    }
+}
+/// The cipher suite can be negotiated in handshake
+#[derive(Debug, Encode, Decode, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CipherSuite {
+    AesGcm,
+    ChaCha20Poly1305,
+}
+/// The capabilities of one side, the intersection of
+/// both side is the capabilities used by the connection.
+#[derive(Debug, Encode, Decode, Clone, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The supported cipher suites, ordered by preference
+    pub ciphers: Vec<CipherSuite>,
+    /// Support compress the relay data
+    pub compression: bool,
+    /// Support multiple streams in one connection
+    pub multiplexing: bool,
+    /// Support relay udp packets
+    pub udp: bool,
+}
+impl Capabilities {
+    /// The intersection of two capability sets, the
+    /// cipher order of current capability set is kept.
+    pub fn intersect(&self, other: &Capabilities) -> Capabilities {
+        Capabilities {
+            ciphers: self
+                .ciphers
+                .iter()
+                .filter(|cipher| other.ciphers.contains(cipher))
+                .copied()
+                .collect(),
+            compression: self.compression && other.compression,
+            multiplexing: self.multiplexing && other.multiplexing,
+            udp: self.udp && other.udp,
+        }
+    }
+}
+/// The first packet sent by client, it carries the
+/// ephemeral public key used to derive the handshake keys.
+#[derive(Debug, Encode, Decode)]
+pub struct ClientKeyExchange {
+    pub public_key: Vec<u8>,
+}
+/// The server reply of the key exchange, it carries the
+/// ephemeral public key of server.
+#[derive(Debug, Encode, Decode)]
+pub struct ServerKeyExchange {
+    pub public_key: Vec<u8>,
+}
+/// The client handshake is encrypted with the handshake keys,
+/// the signature is created by the agent private key.
+/// The version must be the first field so the server can
+/// read it even the rest of the layout is changed.
 #[derive(Debug, Encode, Decode)]
 pub struct ClientHandshake {
+    pub version: u16,
+    pub capabilities: Capabilities,
     pub username: String,
     pub encryption: Encryption,
+    pub signature: Vec<u8>,
 }
+/// The server handshake is encrypted with the handshake keys,
+/// the signature is created by the proxy private key.
 #[derive(Debug, Encode, Decode)]
-pub struct ServerHandshake {
-    pub encryption: Encryption,
+pub enum ServerHandshake {
+    Success {
+        version: u16,
+        /// The negotiated capabilities
+        capabilities: Capabilities,
+        encryption: Encryption,
+        /// The unix timestamp when the user expire, only sent when
+        /// the user will expire in the grace period of the proxy.
+        user_expired_time: Option<i64>,
+        signature: Vec<u8>,
+    },
+    /// The rejection is the first field, so the agent of
+    /// older version can still read the unsupported version.
+    Reject {
+        rejection: HandshakeRejection,
+        /// Created by the proxy private key, it is empty for the unsupported
+        /// version as the user is unknown before the handshake is decoded.
+        signature: Vec<u8>,
+    },
+}
+/// The reason why server reject the client handshake
+#[derive(Debug, Encode, Decode, Clone)]
+pub enum HandshakeRejection {
+    UnsupportedVersion { supported_version: u16 },
+    NoCommonCipher,
+    /// The user expired at the unix timestamp
+    UserExpired { expired_time: i64 },
+    /// The user reach the max concurrent sessions
+    TooManySessions { max_sessions: u32 },
 }
 #[derive(Debug, Encode, Decode)]
 pub enum ClientSetupDestination {
     Tcp(UnifiedAddress),
     Udp(UnifiedAddress),
+    /// Turn the connection into a multiplexed session, each
+    /// stream in the session setup its own destination.
+    Multiplex,
+    /// Listen on the proxy side for the inbound connection from
+    /// the peer, the address is where the peer expected to come from.
+    Bind(UnifiedAddress),
 }
 #[derive(Debug, Encode, Decode)]
 pub enum ServerSetupDestination {
     Success,
-    Fail,
+    Fail(SetupDestinationFailure),
+    /// The proxy is listening on the address for the peer of bind
+    Bound(SocketAddr),
+    /// The peer of bind connected from the address, the relay begin
+    PeerConnected(SocketAddr),
+}
+/// The reason why server fail to setup the destination
+#[derive(Debug, Encode, Decode, Clone, Copy, PartialEq, Eq)]
+pub enum SetupDestinationFailure {
+    /// The destination domain can not be resolved
+    DnsResolution,
+    /// The destination refused the connection
+    ConnectionRefused,
+    /// Connect to the destination timeout
+    Timeout,
+    /// The destination network or host is unreachable
+    Unreachable,
+    /// The destination is not allowed by the access control
+    AclDenied,
+    /// The user is expired
+    UserExpired,
+    /// The user used up the traffic quota
+    QuotaExceeded,
+    /// The multiplexed session reach the max concurrent streams
+    TooManyStreams,
+    /// Other failures
+    Other,
 }
 #[derive(Debug, Encode, Decode)]
 pub enum Relay {
@@ -34,3 +169,24 @@ pub enum Relay {
         payload: Vec<u8>,
     },
 }
+/// The frame of multiplexed session
+#[derive(Debug, Encode, Decode)]
+pub struct MuxFrame {
+    pub stream_id: u32,
+    pub payload: MuxPayload,
+}
+#[derive(Debug, Encode, Decode)]
+pub enum MuxPayload {
+    /// Open a new stream with the destination
+    Open(ClientSetupDestination),
+    /// The result of opening the stream
+    OpenResult(ServerSetupDestination),
+    /// The data of the stream
+    Data(Vec<u8>),
+    /// The receiver consumed the data, the sender can send more bytes
+    WindowUpdate(u32),
+    /// The sender will not send more data on the stream
+    Close,
+    /// The sender dropped the stream, the receiver stops both directions
+    Reset,
+}
@@ -0,0 +1,194 @@
+use crate::config::get_config;
+use crate::error::Error;
+use crate::user::{ProxyUser, get_user_repo};
+use common::rule::AddressMatcher;
+use common::user::UserRepository;
+use protocol::{Error as ProtocolError, UnifiedAddress};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::lookup_host;
+use tracing::warn;
+/// The access control of the destinations, the deny rules win over the
+/// allow rules, and only the destinations match the allow rules are
+/// permitted when any allow rule configured.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(crate) struct DestinationAcl {
+    #[serde(default)]
+    allow: Vec<AddressMatcher>,
+    #[serde(default)]
+    deny: Vec<AddressMatcher>,
+}
+impl DestinationAcl {
+    /// The rule match the requested address or the address resolved from it
+    fn any_matches(
+        rules: &[AddressMatcher],
+        destination_address: &UnifiedAddress,
+        resolved_address: &UnifiedAddress,
+    ) -> bool {
+        rules
+            .iter()
+            .any(|rule| rule.matches(destination_address) || rule.matches(resolved_address))
+    }
+    fn permits(
+        &self,
+        destination_address: &UnifiedAddress,
+        resolved_address: &UnifiedAddress,
+    ) -> bool {
+        if Self::any_matches(&self.deny, destination_address, resolved_address) {
+            return false;
+        }
+        self.allow.is_empty()
+            || Self::any_matches(&self.allow, destination_address, resolved_address)
+    }
+    /// The private address is only reachable when the allow
+    /// rules contain the network of it explicitly.
+    fn allows_private(&self, resolved_address: &UnifiedAddress) -> bool {
+        self.allow
+            .iter()
+            .any(|rule| matches!(rule, AddressMatcher::IpCidr(_)) && rule.matches(resolved_address))
+    }
+}
+/// The address can not be reached from the public internet, include
+/// the loopback, link local (cloud metadata), private and shared ranges.
+fn is_private_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_private_ipv4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_private_ipv4(ip),
+            None => is_private_ipv6(ip),
+        },
+    }
+}
+fn is_private_ipv4(ip: Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        // 0.0.0.0/8 means this host
+        || octets[0] == 0
+        // 100.64.0.0/10 is shared by the carrier grade NAT
+        || (octets[0] == 100 && (octets[1] & 0xc0) == 64)
+}
+fn is_private_ipv6(ip: Ipv6Addr) -> bool {
+    let first_segment = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // fc00::/7 is the unique local range
+        || (first_segment & 0xfe00) == 0xfc00
+        // fe80::/10 is the link local range
+        || (first_segment & 0xffc0) == 0xfe80
+}
+/// Resolve the destination and keep the addresses permitted by the global
+/// and the user access control. The check happens on the resolved addresses,
+/// so that the domain resolved to a private address is still denied.
+pub(crate) async fn resolve_permitted_destination(
+    username: &str,
+    destination_address: &UnifiedAddress,
+) -> Result<Vec<SocketAddr>, Error> {
+    let resolved_addresses: Vec<SocketAddr> = match destination_address {
+        UnifiedAddress::Domain { host, port } => lookup_host((host.as_str(), *port))
+            .await
+            .map_err(ProtocolError::Io)?
+            .collect(),
+        UnifiedAddress::SocketAddress(socket_addr) => vec![*socket_addr],
+    };
+    let user = get_user_repo().find_user(username);
+    let acls = [
+        Some(get_config().destination_acl()),
+        user.as_deref().map(ProxyUser::destination_acl),
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>();
+    let permitted_addresses = resolved_addresses
+        .into_iter()
+        .filter(|resolved_addr| {
+            let resolved_address = UnifiedAddress::SocketAddress(*resolved_addr);
+            acls.iter()
+                .all(|acl| acl.permits(destination_address, &resolved_address))
+                && (!is_private_ip(resolved_addr.ip())
+                    || acls.iter().any(|acl| acl.allows_private(&resolved_address)))
+        })
+        .collect::<Vec<_>>();
+    if permitted_addresses.is_empty() {
+        warn!("Destination [{destination_address}] of user [{username}] denied by access control");
+        return Err(Error::DestinationDenied(destination_address.to_string()));
+    }
+    Ok(permitted_addresses)
+}
+/// Check the destination exit from the forward hop, it is resolved by the
+/// hop so the global and the user access control only match the requested
+/// address, the private addresses are left to the access control of the hop.
+pub(crate) fn check_forwarded_destination(
+    username: &str,
+    destination_address: &UnifiedAddress,
+) -> Result<(), Error> {
+    let user = get_user_repo().find_user(username);
+    let permitted = get_config()
+        .destination_acl()
+        .permits(destination_address, destination_address)
+        && user.as_deref().is_none_or(|user| {
+            user.destination_acl()
+                .permits(destination_address, destination_address)
+        });
+    if !permitted {
+        warn!(
+            "Forwarded destination [{destination_address}] of user [{username}] denied by access control"
+        );
+        return Err(Error::DestinationDenied(destination_address.to_string()));
+    }
+    Ok(())
+}
+#[test]
+fn test() {
+    let acl: DestinationAcl = toml::from_str(
+        r#"
+        allow = [{ ip_cidr = "10.1.0.0/16" }, { domain_suffix = "example.com" }]
+        deny = [{ port = 25 }]
+        "#,
+    )
+    .unwrap();
+    let resolved = |addr: &str| UnifiedAddress::SocketAddress(addr.parse().unwrap());
+    let domain = |host: &str, port| UnifiedAddress::Domain {
+        host: host.to_owned(),
+        port,
+    };
+    assert!(acl.permits(
+        &domain("www.example.com", 443),
+        &resolved("93.184.216.34:443")
+    ));
+    assert!(!acl.permits(
+        &domain("www.example.com", 25),
+        &resolved("93.184.216.34:25")
+    ));
+    assert!(!acl.permits(&domain("other.com", 443), &resolved("93.184.216.34:443")));
+    // The forwarded destination is only matched by the requested address
+    let forwarded = resolved("10.1.2.3:443");
+    assert!(acl.permits(&forwarded, &forwarded));
+    let forwarded = domain("other.com", 443);
+    assert!(!acl.permits(&forwarded, &forwarded));
+    // The domain allowed by name still can not reach the private address
+    assert!(!acl.allows_private(&resolved("127.0.0.1:443")));
+    assert!(acl.allows_private(&resolved("10.1.2.3:443")));
+    assert!(!acl.allows_private(&resolved("10.2.2.3:443")));
+    for private_ip in [
+        "127.0.0.1",
+        "169.254.169.254",
+        "10.0.0.1",
+        "172.16.0.1",
+        "192.168.1.1",
+        "100.64.0.1",
+        "0.0.0.0",
+        "::1",
+        "fd00::1",
+        "fe80::1",
+        "::ffff:127.0.0.1",
+    ] {
+        assert!(is_private_ip(private_ip.parse().unwrap()), "{private_ip}");
+    }
+    assert!(!is_private_ip("93.184.216.34".parse().unwrap()));
+    assert!(!is_private_ip("2606:2800:220:1::".parse().unwrap()));
+}
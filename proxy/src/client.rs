@@ -1,8 +1,6 @@
 use common::SecureLengthDelimitedCodec;
-use protocol::Encryption;
 use std::io::Error;
 use std::pin::Pin;
-use std::sync::Arc;
 use std::task::{Context, Poll};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::TcpStream;
@@ -10,20 +8,13 @@ use tokio::pin;
 use tokio_util::bytes::BytesMut;
 use tokio_util::codec::Framed;
 use tokio_util::io::{SinkWriter, StreamReader};
+/// The framed client connection after handshake
+pub type ClientFramed = Framed<TcpStream, SecureLengthDelimitedCodec>;
 pub struct ClientTcpRelayEndpoint {
-    client_read_write:
-        SinkWriter<StreamReader<Framed<TcpStream, SecureLengthDelimitedCodec>, BytesMut>>,
+    client_read_write: SinkWriter<StreamReader<ClientFramed, BytesMut>>,
 }
 impl ClientTcpRelayEndpoint {
-    pub fn new(
-        client_stream: TcpStream,
-        client_encryption: Arc<Encryption>,
-        server_encryption: Arc<Encryption>,
-    ) -> Self {
-        let client_framed = Framed::new(
-            client_stream,
-            SecureLengthDelimitedCodec::new(client_encryption, server_encryption),
-        );
+    pub fn new(client_framed: ClientFramed) -> Self {
         Self {
             client_read_write: SinkWriter::new(StreamReader::new(client_framed)),
         }
@@ -26,4 +26,11 @@ pub(crate) struct CommandArgs {
     /// The interval to refresh the user repository
     #[arg(short = 'i', long)]
     pub user_repo_refresh_interval: Option<u64>,
+    /// The sqlite database of the user repository
+    #[arg(short = 'd', long)]
+    pub user_repo_database: Option<PathBuf>,
+    /// Import the users in the user repository directory
+    /// into the sqlite database, then exit
+    #[arg(long)]
+    pub import_user_repo: bool,
 }
@@ -1,8 +1,10 @@
+use crate::acl::DestinationAcl;
 use crate::command::CommandArgs;
+use crate::forward::{ForwardRouting, check_forward_hops};
 use clap::Parser;
 use common_macro::{
-    FileSystemUserRepoConfig, LogConfig, ServerConfig,  UserRepositoryConfig,
-    UsernameConfig,
+    FileSystemUserRepoConfig, HttpUserRepoConfig, LogConfig, ServerConfig,
+    SqliteUserRepoConfig, UserRepositoryConfig, UsernameConfig,
 };
 use core::panic;
 use serde::{Deserialize, Serialize};
@@ -33,6 +35,12 @@ pub fn get_config() -> &'static Config {
         let mut config = toml::from_str::<Config>(&config_content)
             .expect("Fail to initialize proxy configuration");
         config.merge_command_args(command_line);
+        // The single forward configuration is the first hop
+        if let Some(forward) = config.forward.take() {
+            config.forward_hops.insert(0, forward);
+        }
+        check_forward_hops(&config.forward_hops)
+            .unwrap_or_else(|e| panic!("Invalid proxy forward hops: {e}"));
         config
     })
 }
@@ -46,6 +54,9 @@ pub fn get_config() -> &'static Config {
     FileSystemUserRepoConfig,
 )]
 pub(crate) struct ForwardConfig {
+    /// The name used by forward rules to choose this hop
+    #[serde(default = "default_forward_hop_name")]
+    name: String,
     #[serde(default = "default_forward_proxy_connect_timeout")]
     proxy_connect_timeout: u64,
     #[serde(default = "default_forward_user_info_file_name")]
@@ -61,6 +72,9 @@ pub(crate) struct ForwardConfig {
     username: String,
 }
 impl ForwardConfig {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
     pub fn proxy_connect_timeout(&self) -> u64 {
         self.proxy_connect_timeout
     }
@@ -73,6 +87,8 @@ impl ForwardConfig {
     LogConfig,
     UserRepositoryConfig,
     FileSystemUserRepoConfig,
+    SqliteUserRepoConfig,
+    HttpUserRepoConfig,
 )]
 pub(crate) struct Config {
     #[serde(default = "default_listening_address")]
@@ -97,14 +113,109 @@ pub(crate) struct Config {
     user_info_public_key_file_name: String,
     #[serde(default = "default_user_info_private_key_file_name")]
     user_info_private_key_file_name: String,
+    /// Where the proxy users are stored
+    #[serde(default)]
+    user_repo_type: UserRepositoryType,
+    /// The sqlite database of the proxy users, used
+    /// when the user repository type is `sqlite`
+    #[serde(default = "default_user_repo_database")]
+    user_repo_database: PathBuf,
+    /// The url to fetch the proxy users, used when
+    /// the user repository type is `http`
+    #[serde(default)]
+    user_repo_url: String,
+    /// The proxy private key of the users fetched from the url
+    #[serde(default = "default_user_repo_private_key_file")]
+    user_repo_private_key_file: PathBuf,
+    /// The last good users fetched from the url
+    #[serde(default = "default_user_repo_snapshot_file")]
+    user_repo_snapshot_file: PathBuf,
+    /// The address to receive the push invalidation of the users
+    #[serde(default)]
+    user_repo_invalidation_address: Option<SocketAddr>,
+    /// The secret in the `X-Invalidation-Secret` header of the push
+    /// invalidation, required unless the address is a loopback one
+    #[serde(default)]
+    user_repo_invalidation_secret: Option<String>,
+    /// Import the users in the user repository directory
+    /// into the sqlite database instead of starting the server
+    #[serde(skip)]
+    import_user_repo: bool,
     #[serde(default = "default_destination_connect_timeout")]
     destination_connect_timeout: u64,
+    /// The seconds to wait for the peer of bind connecting to proxy
+    #[serde(default = "default_bind_accept_timeout")]
+    bind_accept_timeout: u64,
+    /// The seconds to close the udp relay when no datagram in both directions
+    #[serde(default = "default_udp_idle_timeout")]
+    udp_idle_timeout: u64,
+    /// The seconds before the user expire to warn the user in
+    /// handshake, the sessions are closed when the user expired.
+    #[serde(default = "default_user_expiry_grace_period")]
+    user_expiry_grace_period: i64,
+    /// The directory to save the monthly traffic usage of the users
+    #[serde(default = "default_traffic_usage_directory")]
+    traffic_usage_directory: PathBuf,
+    /// The seconds between saving the traffic usage
+    #[serde(default = "default_traffic_usage_save_interval")]
+    traffic_usage_save_interval: u64,
+    /// The max concurrent streams in one multiplexed session
+    #[serde(default = "default_mux_max_streams")]
+    mux_max_streams: usize,
+    /// Same as the first forward hop, kept for the
+    /// configuration with only one forward proxy
+    #[serde(default)]
     forward: Option<ForwardConfig>,
+    /// The next hop proxies can be used by forward rules
+    #[serde(default)]
+    forward_hops: Vec<ForwardConfig>,
+    #[serde(default)]
+    forward_routing: ForwardRouting,
+    /// The access control of the destinations for all users,
+    /// the private addresses are denied unless allowed here.
+    #[serde(default)]
+    destination_acl: DestinationAcl,
+}
+/// The storage of the proxy users
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum UserRepositoryType {
+    /// Each user is a directory of the user info and keys
+    #[default]
+    FileSystem,
+    /// The users are rows of the sqlite database
+    Sqlite,
+    /// The users are fetched from the http endpoint
+    Http,
 }
 impl Config {
+    pub fn user_repo_type(&self) -> UserRepositoryType {
+        self.user_repo_type
+    }
+    pub fn import_user_repo(&self) -> bool {
+        self.import_user_repo
+    }
     pub fn destination_connect_timeout(&self) -> u64 {
         self.destination_connect_timeout
     }
+    pub fn bind_accept_timeout(&self) -> u64 {
+        self.bind_accept_timeout
+    }
+    pub fn udp_idle_timeout(&self) -> u64 {
+        self.udp_idle_timeout
+    }
+    pub fn user_expiry_grace_period(&self) -> i64 {
+        self.user_expiry_grace_period
+    }
+    pub fn traffic_usage_directory(&self) -> &Path {
+        &self.traffic_usage_directory
+    }
+    pub fn traffic_usage_save_interval(&self) -> u64 {
+        self.traffic_usage_save_interval
+    }
+    pub fn mux_max_streams(&self) -> usize {
+        self.mux_max_streams
+    }
     pub fn merge_command_args(&mut self, command: CommandArgs) {
         if let Some(listening_address) = command.listening_address {
             self.listening_address = listening_address;
@@ -124,9 +235,23 @@ impl Config {
         if let Some(user_repo_refresh_interval) = command.user_repo_refresh_interval {
             self.user_repo_refresh_interval = user_repo_refresh_interval;
         }
+        if let Some(user_repo_database) = command.user_repo_database {
+            self.user_repo_database = user_repo_database;
+        }
+        self.import_user_repo = command.import_user_repo;
+    }
+    pub fn forward_hops(&self) -> &[ForwardConfig] {
+        &self.forward_hops
     }
-    pub fn forward(&self) -> Option<&ForwardConfig> {
-        self.forward.as_ref()
+    /// Find the forward hop by name
+    pub fn forward_hop(&self, name: &str) -> Option<&ForwardConfig> {
+        self.forward_hops.iter().find(|hop| hop.name == name)
+    }
+    pub fn forward_routing(&self) -> &ForwardRouting {
+        &self.forward_routing
+    }
+    pub fn destination_acl(&self) -> &DestinationAcl {
+        &self.destination_acl
     }
 }
 fn default_listening_address() -> SocketAddr {
@@ -150,6 +275,17 @@ fn default_user_repo_directory() -> PathBuf {
 fn default_user_repo_refresh_interval() -> u64 {
     10
 }
+fn default_user_repo_database() -> PathBuf {
+    PathBuf::from_str("./resources/proxy/user.db").expect("Wrong user repository database")
+}
+fn default_user_repo_private_key_file() -> PathBuf {
+    PathBuf::from_str("./resources/proxy/ProxyPrivateKey.pem")
+        .expect("Wrong user repository private key file")
+}
+fn default_user_repo_snapshot_file() -> PathBuf {
+    PathBuf::from_str("./resources/proxy/user_snapshot.json")
+        .expect("Wrong user repository snapshot file")
+}
 fn default_user_info_file_name() -> String {
     "user_info.toml".to_string()
 }
@@ -159,6 +295,9 @@ fn default_user_info_public_key_file_name() -> String {
 fn default_user_info_private_key_file_name() -> String {
     "ProxyPublicKey.pem".to_string()
 }
+fn default_forward_hop_name() -> String {
+    "default".to_string()
+}
 fn default_forward_user_repo_directory() -> PathBuf {
     PathBuf::from_str("./resources/proxy/forward_user")
         .expect("Wrong forward user repository directory")
@@ -181,6 +320,24 @@ fn default_forward_proxy_connect_timeout() -> u64 {
 fn default_destination_connect_timeout() -> u64 {
     10
 }
+fn default_bind_accept_timeout() -> u64 {
+    60
+}
+fn default_udp_idle_timeout() -> u64 {
+    120
+}
+fn default_user_expiry_grace_period() -> i64 {
+    7 * 24 * 3600
+}
+fn default_traffic_usage_directory() -> PathBuf {
+    PathBuf::from_str("./resources/proxy/usage").expect("Wrong traffic usage directory")
+}
+fn default_traffic_usage_save_interval() -> u64 {
+    60
+}
+fn default_mux_max_streams() -> usize {
+    256
+}
 fn default_client_max_connections() -> usize {
     1024
 }
@@ -0,0 +1,97 @@
+use crate::destination::tcp::TcpDestEndpoint;
+use crate::error::Error;
+use protocol::{Error as ProtocolError, UnifiedAddress};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::net::{TcpListener, lookup_host};
+use tokio::time::timeout;
+use tracing::{debug, warn};
+/// The listener of bind, the peer connect to it
+/// become the destination of the client.
+pub struct BindDestListener {
+    tcp_listener: TcpListener,
+}
+impl BindDestListener {
+    /// Listen on a random port of the ip
+    pub async fn bind(ip: IpAddr) -> Result<Self, Error> {
+        let tcp_listener = TcpListener::bind(SocketAddr::new(ip, 0)).await?;
+        Ok(Self { tcp_listener })
+    }
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.tcp_listener.local_addr()?)
+    }
+    /// Wait for the peer connect, the connections not from the expected
+    /// peer are dropped. Any peer is accepted when the expected peer
+    /// address is unspecified.
+    pub async fn accept(
+        self,
+        peer_addr: UnifiedAddress,
+        accept_timeout: u64,
+    ) -> Result<TcpDestEndpoint, Error> {
+        let expected_peer_ips = match peer_addr {
+            UnifiedAddress::SocketAddress(socket_addr) if socket_addr.ip().is_unspecified() => None,
+            UnifiedAddress::SocketAddress(socket_addr) => Some(vec![socket_addr.ip()]),
+            UnifiedAddress::Domain { host, port } => Some(
+                lookup_host((host.as_str(), port))
+                    .await
+                    .map_err(ProtocolError::Io)?
+                    .map(|socket_addr| socket_addr.ip())
+                    .collect::<Vec<_>>(),
+            ),
+        };
+        let accept = async {
+            loop {
+                let (tcp_stream, inbound_peer_addr) = self.tcp_listener.accept().await?;
+                match &expected_peer_ips {
+                    Some(expected_peer_ips)
+                        if !expected_peer_ips.contains(&inbound_peer_addr.ip()) =>
+                    {
+                        warn!(
+                            "Drop the unexpected peer [{inbound_peer_addr}] of bind, expected: {expected_peer_ips:?}"
+                        );
+                    }
+                    _ => {
+                        debug!("Peer [{inbound_peer_addr}] of bind connected");
+                        return Ok::<_, Error>(TcpDestEndpoint::from_accepted(
+                            tcp_stream,
+                            inbound_peer_addr,
+                        ));
+                    }
+                }
+            }
+        };
+        timeout(Duration::from_secs(accept_timeout), accept)
+            .await
+            .map_err(|_| Error::BindAcceptTimeout(accept_timeout))?
+    }
+}
+#[tokio::test]
+async fn test() {
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpStream;
+    let localhost = IpAddr::from([127, 0, 0, 1]);
+    // The unexpected peer is dropped, then the accept timeout
+    let bind_listener = BindDestListener::bind(localhost).await.unwrap();
+    let listening_addr = bind_listener.local_addr().unwrap();
+    let expected_peer_addr = UnifiedAddress::from(SocketAddr::from(([10, 0, 0, 1], 0)));
+    let accept = tokio::spawn(bind_listener.accept(expected_peer_addr, 1));
+    let mut unexpected_peer = TcpStream::connect(listening_addr).await.unwrap();
+    assert!(matches!(
+        unexpected_peer.read(&mut [0u8; 1]).await,
+        Ok(0) | Err(_)
+    ));
+    assert!(matches!(
+        accept.await.unwrap(),
+        Err(Error::BindAcceptTimeout(1))
+    ));
+    // The peer expected by the domain is accepted
+    let bind_listener = BindDestListener::bind(localhost).await.unwrap();
+    let listening_addr = bind_listener.local_addr().unwrap();
+    let expected_peer_addr = UnifiedAddress::Domain {
+        host: "localhost".to_owned(),
+        port: 0,
+    };
+    let accept = tokio::spawn(bind_listener.accept(expected_peer_addr, 5));
+    let _expected_peer = TcpStream::connect(listening_addr).await.unwrap();
+    assert!(accept.await.unwrap().is_ok());
+}
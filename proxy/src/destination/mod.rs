@@ -1,7 +1,6 @@
 use crate::destination::tcp::TcpDestEndpoint;
-use crate::destination::udp::UdpDestEndpoint;
 use common::proxy::{ProxyConnection, ProxyFramedReaderWriter};
-use protocol::UnifiedAddress;
+pub(crate) mod bind;
 pub(crate) mod tcp;
 pub(crate) mod udp;
 
@@ -13,10 +12,4 @@ pub enum Destination {
     /// The forward destination, the agent data will forward
     /// to the remote proxy through current proxy node.
     Forward(Box<ProxyConnection<ProxyFramedReaderWriter>>),
-    /// The UDP destination
-    #[allow(unused)]
-    Udp {
-        dst_udp_endpoint: UdpDestEndpoint,
-        dst_addr: UnifiedAddress,
-    },
 }
@@ -1,6 +1,5 @@
 use crate::error::Error;
 use common::Error as CommonError;
-use protocol::UnifiedAddress;
 use std::io::Error as StdIoError;
 use std::net::SocketAddr;
 use std::pin::Pin;
@@ -15,14 +14,12 @@ pub struct TcpDestEndpoint {
     dst_addr: SocketAddr,
 }
 impl TcpDestEndpoint {
-    pub async fn connect(
-        unified_dst_addr: UnifiedAddress,
-        connect_timeout: u64,
-    ) -> Result<Self, Error> {
-        let dst_addrs: Vec<SocketAddr> = unified_dst_addr.try_into()?;
+    /// Connect to the destination with the addresses
+    /// resolved and permitted already.
+    pub async fn connect(dst_addrs: &[SocketAddr], connect_timeout: u64) -> Result<Self, Error> {
         let tcp_stream = timeout(
             Duration::from_secs(connect_timeout),
-            TcpStream::connect(&dst_addrs[..]),
+            TcpStream::connect(dst_addrs),
         )
         .await
         .map_err(|_| CommonError::ConnectTimeout(connect_timeout))??;
@@ -32,6 +29,13 @@ impl TcpDestEndpoint {
             tcp_stream,
         })
     }
+    /// The peer connected to proxy is the destination of bind
+    pub fn from_accepted(tcp_stream: TcpStream, dst_addr: SocketAddr) -> Self {
+        Self {
+            tcp_stream,
+            dst_addr,
+        }
+    }
     pub fn dst_addr(&self) -> SocketAddr {
         self.dst_addr
     }
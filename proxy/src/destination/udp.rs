@@ -1,6 +1,8 @@
 use crate::error::Error;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::net::UdpSocket;
+/// The udp socket to send the client datagrams to destinations,
+/// one socket is used by all the destinations of a client.
 pub struct UdpDestEndpoint {
     udp_socket: UdpSocket,
 }
@@ -12,14 +14,15 @@ impl UdpDestEndpoint {
         Ok(Self { udp_socket })
     }
 
-    pub async fn replay_to<A: ToSocketAddrs>(
-        &self,
-        dst_addr: A,
-        buf: &[u8],
-    ) -> Result<Vec<u8>, Error> {
+    /// Send the datagram to the destination address
+    /// resolved and permitted already.
+    pub async fn send_to(&self, buf: &[u8], dst_addr: SocketAddr) -> Result<(), Error> {
         self.udp_socket.send_to(buf, dst_addr).await?;
-        let mut dst_udp_data = [0u8; 65536];
-        self.udp_socket.recv(&mut dst_udp_data).await?;
-        Ok(dst_udp_data.to_vec())
+        Ok(())
+    }
+
+    /// Receive the datagram from any destination
+    pub async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), Error> {
+        Ok(self.udp_socket.recv_from(buf).await?)
     }
 }
@@ -8,7 +8,27 @@ pub enum Error {
     #[error(transparent)]
     Toml(#[from] toml::de::Error),
     #[error(transparent)]
+    TomlSerialize(#[from] toml::ser::Error),
+    #[error(transparent)]
     Common(#[from] CommonError),
     #[error(transparent)]
     Protocol(#[from] ProtocolError),
+    #[error("Wait for the peer of bind timeout in {0} seconds.")]
+    BindAcceptTimeout(u64),
+    #[error("Destination denied by access control: [{0}]")]
+    DestinationDenied(String),
+    #[error("User used up the monthly traffic quota: [{0}]")]
+    QuotaExceeded(String),
+    #[error("User expired: [{0}]")]
+    UserExpired(String),
+    #[error("Username can not be used in the file name: [{0}]")]
+    InvalidUsernameForFile(String),
+    #[error("Forward hop not exist: [{0}]")]
+    ForwardHopNotExist(String),
+    #[error("Forward hop name is duplicated: [{0}]")]
+    ForwardHopNameDuplicated(String),
+    #[error("Forward hop name is reserved for the local egress: [{0}]")]
+    ForwardHopNameReserved(String),
+    #[error("{0} is not supported in multiplexed session")]
+    NotSupportedInMultiplexedSession(&'static str),
 }
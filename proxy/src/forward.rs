@@ -0,0 +1,169 @@
+use crate::config::{Config, ForwardConfig, get_config};
+use crate::error::Error;
+use common::rule::AddressMatcher;
+use protocol::UnifiedAddress;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use tracing::debug;
+/// The egress to exit from current proxy
+const LOCAL_EGRESS: &str = "local";
+/// The rule to choose the egress of destination
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct ForwardRule {
+    #[serde(flatten)]
+    matcher: AddressMatcher,
+    /// The name of the forward hop, or `local`
+    /// to exit from current proxy
+    egress: String,
+}
+/// The forward rules, the rules are matched in order and
+/// the first matched one decide the egress of destination.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(crate) struct ForwardRouting {
+    #[serde(default)]
+    rules: Vec<ForwardRule>,
+    /// The egress when no rule matched, the first forward
+    /// hop is used by default, or `local` when no hop.
+    #[serde(default)]
+    default_egress: Option<String>,
+}
+impl ForwardRouting {
+    /// The name of the egress, `None` means the default one
+    fn egress(&self, destination_address: &UnifiedAddress) -> Option<&str> {
+        self.rules
+            .iter()
+            .find(|rule| rule.matcher.matches(destination_address))
+            .map(|rule| rule.egress.as_str())
+            .or(self.default_egress.as_deref())
+    }
+}
+/// Where the destination is connected from
+pub(crate) enum Egress {
+    /// Connect the destination from current proxy
+    Local,
+    /// Connect the destination through the forward hop
+    Hop(&'static ForwardConfig),
+}
+/// The forward hops are chosen by name, so the names must be
+/// unique and can not be the name of the local egress.
+pub(crate) fn check_forward_hops(forward_hops: &[ForwardConfig]) -> Result<(), Error> {
+    let mut names = HashSet::new();
+    for forward_hop in forward_hops {
+        if forward_hop.name() == LOCAL_EGRESS {
+            return Err(Error::ForwardHopNameReserved(forward_hop.name().to_owned()));
+        }
+        if !names.insert(forward_hop.name()) {
+            return Err(Error::ForwardHopNameDuplicated(forward_hop.name().to_owned()));
+        }
+    }
+    Ok(())
+}
+/// Decide the egress of the destination with the forward rules
+pub(crate) fn select_egress(destination_address: &UnifiedAddress) -> Result<Egress, Error> {
+    select_egress_of(get_config(), destination_address)
+}
+fn select_egress_of(
+    config: &'static Config,
+    destination_address: &UnifiedAddress,
+) -> Result<Egress, Error> {
+    let forward_hop = match config.forward_routing().egress(destination_address) {
+        Some(LOCAL_EGRESS) => None,
+        Some(name) => Some(
+            config
+                .forward_hop(name)
+                .ok_or(Error::ForwardHopNotExist(name.to_owned()))?,
+        ),
+        None => config.forward_hops().first(),
+    };
+    match forward_hop {
+        None => {
+            debug!("Destination [{destination_address}] exit from local");
+            Ok(Egress::Local)
+        }
+        Some(forward_hop) => {
+            debug!(
+                "Destination [{destination_address}] forward to hop: {}",
+                forward_hop.name()
+            );
+            Ok(Egress::Hop(forward_hop))
+        }
+    }
+}
+#[test]
+fn test() {
+    let load_config = |content: &str| -> &'static Config {
+        Box::leak(Box::new(toml::from_str::<Config>(content).unwrap()))
+    };
+    let domain = |host: &str, port| UnifiedAddress::Domain {
+        host: host.to_owned(),
+        port,
+    };
+    let hop_name = |egress: Result<Egress, Error>| match egress.unwrap() {
+        Egress::Local => LOCAL_EGRESS,
+        Egress::Hop(forward_hop) => forward_hop.name(),
+    };
+    let config = load_config(
+        r#"
+        [[forward_hops]]
+        name = "hop1"
+        username = "user1"
+        [[forward_hops]]
+        name = "hop2"
+        username = "user1"
+        [forward_routing]
+        default_egress = "local"
+        rules = [
+            { domain_suffix = "example.com", egress = "hop2" },
+            { domain_suffix = "a.example.com", egress = "hop1" },
+            { port = 25, egress = "missing" },
+        ]
+        "#,
+    );
+    check_forward_hops(config.forward_hops()).unwrap();
+    // The first matched rule decide the egress
+    let routing = config.forward_routing();
+    assert_eq!(routing.egress(&domain("www.a.example.com", 443)), Some("hop2"));
+    assert_eq!(routing.egress(&domain("other.com", 443)), Some(LOCAL_EGRESS));
+    assert_eq!(hop_name(select_egress_of(config, &domain("a.example.com", 443))), "hop2");
+    assert_eq!(hop_name(select_egress_of(config, &domain("other.com", 443))), LOCAL_EGRESS);
+    assert!(matches!(
+        select_egress_of(config, &domain("other.com", 25)),
+        Err(Error::ForwardHopNotExist(name)) if name == "missing"
+    ));
+    // The first hop is used without the default egress, local without any hop
+    let config = load_config(
+        r#"
+        [[forward_hops]]
+        name = "hop1"
+        username = "user1"
+        "#,
+    );
+    assert_eq!(config.forward_routing().egress(&domain("other.com", 443)), None);
+    assert_eq!(hop_name(select_egress_of(config, &domain("other.com", 443))), "hop1");
+    let config = load_config("");
+    assert_eq!(hop_name(select_egress_of(config, &domain("other.com", 443))), LOCAL_EGRESS);
+    // The hop names are unique and not the local egress
+    let config = load_config(
+        r#"
+        [[forward_hops]]
+        username = "user1"
+        [[forward_hops]]
+        username = "user2"
+        "#,
+    );
+    assert!(matches!(
+        check_forward_hops(config.forward_hops()),
+        Err(Error::ForwardHopNameDuplicated(name)) if name == "default"
+    ));
+    let config = load_config(
+        r#"
+        [[forward_hops]]
+        name = "local"
+        username = "user1"
+        "#,
+    );
+    assert!(matches!(
+        check_forward_hops(config.forward_hops()),
+        Err(Error::ForwardHopNameReserved(_))
+    ));
+}
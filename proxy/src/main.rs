@@ -1,13 +1,19 @@
 use crate::config::get_config;
 use crate::error::Error;
-use common::{ServerState, build_server_runtime, init_log, start_server};
+use common::user::repo::import_file_system_user_repo;
+use common::{
+    ServerState, WithSqliteUserRepoConfig, build_server_runtime, init_log, start_server,
+};
 use tokio::signal;
 use tracing::{debug, error, info};
+mod acl;
 pub(crate) mod client;
 mod command;
 mod config;
 pub(crate) mod destination;
 mod error;
+mod forward;
+mod traffic;
 mod tunnel;
 mod user;
 
@@ -20,15 +26,28 @@ async fn handle_agent_connection(server_state: ServerState) -> Result<(), Error>
 /// Start the proxy server
 fn main() -> Result<(), Error> {
     let _log_guard = init_log(get_config())?;
+    if get_config().import_user_repo() {
+        let imported_usernames = import_file_system_user_repo(get_config())?;
+        println!(
+            "Imported {} users into {:?}: {imported_usernames:?}",
+            imported_usernames.len(),
+            get_config().user_repo_database()
+        );
+        return Ok(());
+    }
     let server_runtime = build_server_runtime(get_config())?;
     server_runtime.block_on(async move {
+        // Load the users before the first agent connected
+        user::get_user_repo();
         let server_guard = start_server(get_config(), handle_agent_connection);
+        tokio::spawn(traffic::persist_traffic_usages());
         if let Err(e) = signal::ctrl_c().await {
             error!("Error happen when listening stop signal: {}", e);
             return;
         }
         info!("Receive stop signal, going to stop server.");
         server_guard.stop_signal.cancel();
+        traffic::save_traffic_usages();
     });
     Ok(())
 }
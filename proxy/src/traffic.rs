@@ -0,0 +1,360 @@
+use crate::config::get_config;
+use crate::error::Error;
+use crate::user::ProxyUser;
+use chrono::Utc;
+use common::user::User;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::task::{Context, Poll, ready};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Sleep, sleep};
+use tracing::{debug, error, warn};
+static USER_TRAFFICS: OnceLock<Mutex<HashMap<String, Arc<UserTraffic>>>> = OnceLock::new();
+/// The direction of the traffic, from the view of the client
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum TrafficDirection {
+    /// From the client to the destination
+    Upload,
+    /// From the destination to the client
+    Download,
+}
+/// The bytes transferred by the user in the month
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct TrafficUsage {
+    /// The month of the usage in `YYYY-MM`, the usage
+    /// is reset when the month changed.
+    month: String,
+    uploaded: u64,
+    downloaded: u64,
+}
+impl TrafficUsage {
+    fn total(&self) -> u64 {
+        self.uploaded.saturating_add(self.downloaded)
+    }
+}
+/// The token bucket to limit the bytes per second, the burst
+/// is one second of the rate. The bucket can go into debt so
+/// that the transfer never split, the next one wait for it.
+struct TokenBucket {
+    rate: Option<u64>,
+    tokens: f64,
+    last_refill: Instant,
+}
+impl TokenBucket {
+    fn new(rate: Option<u64>) -> Self {
+        Self {
+            rate,
+            tokens: rate.unwrap_or_default() as f64,
+            last_refill: Instant::now(),
+        }
+    }
+    /// Take the tokens of the bytes, return how long to
+    /// wait before the next transfer.
+    fn consume(&mut self, bytes: u64) -> Duration {
+        let Some(rate) = self.rate.filter(|rate| *rate > 0) else {
+            return Duration::ZERO;
+        };
+        let rate = rate as f64;
+        let now = Instant::now();
+        self.tokens =
+            (self.tokens + now.duration_since(self.last_refill).as_secs_f64() * rate).min(rate);
+        self.last_refill = now;
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+struct TrafficState {
+    upload_bucket: TokenBucket,
+    download_bucket: TokenBucket,
+    monthly_traffic_quota: Option<u64>,
+    usage: TrafficUsage,
+    /// The usage changed after last saved
+    dirty: bool,
+}
+impl TrafficState {
+    /// Start the usage of the new month
+    fn roll_month(&mut self) {
+        let month = Utc::now().format("%Y-%m").to_string();
+        if self.usage.month != month {
+            self.usage = TrafficUsage {
+                month,
+                ..Default::default()
+            };
+            self.dirty = true;
+        }
+    }
+    fn quota_exceeded(&self) -> bool {
+        self.monthly_traffic_quota
+            .is_some_and(|quota| self.usage.total() >= quota)
+    }
+}
+/// The traffic of the user shared by all its connections
+pub(crate) struct UserTraffic {
+    username: String,
+    state: Mutex<TrafficState>,
+}
+impl UserTraffic {
+    fn lock_state(&self) -> MutexGuard<'_, TrafficState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    /// Fail when the user used up the monthly quota
+    pub fn check_quota(&self) -> Result<(), Error> {
+        let mut state = self.lock_state();
+        state.roll_month();
+        if state.quota_exceeded() {
+            return Err(Error::QuotaExceeded(self.username.clone()));
+        }
+        Ok(())
+    }
+    /// Record the transferred bytes, return how long to wait
+    /// before the next transfer in the same direction.
+    pub fn record(&self, direction: TrafficDirection, bytes: u64) -> Result<Duration, Error> {
+        let mut state = self.lock_state();
+        state.roll_month();
+        if state.quota_exceeded() {
+            return Err(Error::QuotaExceeded(self.username.clone()));
+        }
+        state.dirty = true;
+        let delay = match direction {
+            TrafficDirection::Upload => {
+                state.usage.uploaded = state.usage.uploaded.saturating_add(bytes);
+                state.upload_bucket.consume(bytes)
+            }
+            TrafficDirection::Download => {
+                state.usage.downloaded = state.usage.downloaded.saturating_add(bytes);
+                state.download_bucket.consume(bytes)
+            }
+        };
+        Ok(delay)
+    }
+    /// Follow the limits of the user, they can be changed
+    /// when the user repository refreshed.
+    fn update_limits(&self, user: &ProxyUser) {
+        let mut state = self.lock_state();
+        if state.upload_bucket.rate != user.upload_rate_limit() {
+            state.upload_bucket = TokenBucket::new(user.upload_rate_limit());
+        }
+        if state.download_bucket.rate != user.download_rate_limit() {
+            state.download_bucket = TokenBucket::new(user.download_rate_limit());
+        }
+        state.monthly_traffic_quota = user.monthly_traffic_quota();
+    }
+    /// Save the usage when it changed, the usage is marked as
+    /// changed again when the saving fail so the next one retry.
+    fn save(&self) -> Result<(), Error> {
+        let usage_content = {
+            let mut state = self.lock_state();
+            if !state.dirty {
+                return Ok(());
+            }
+            state.dirty = false;
+            toml::to_string(&state.usage)?
+        };
+        write_usage(&self.username, &usage_content).inspect_err(|_| {
+            self.lock_state().dirty = true;
+        })
+    }
+}
+/// The usage file of the user, the username is refused
+/// when it can escape from the usage directory.
+fn usage_file_path(username: &str) -> Result<PathBuf, Error> {
+    if username.is_empty()
+        || username == "."
+        || username == ".."
+        || username
+            .chars()
+            .any(|c| std::path::is_separator(c) || c == '\\' || c == '\0')
+    {
+        return Err(Error::InvalidUsernameForFile(username.to_owned()));
+    }
+    Ok(get_config()
+        .traffic_usage_directory()
+        .join(format!("{username}.toml")))
+}
+/// Write the usage into a temporary file and rename it to the usage
+/// file, so that the usage file is never left half written.
+fn write_usage(username: &str, usage_content: &str) -> Result<(), Error> {
+    let usage_file_path = usage_file_path(username)?;
+    std::fs::create_dir_all(get_config().traffic_usage_directory())?;
+    let temp_file_path = usage_file_path.with_extension("toml.tmp");
+    std::fs::write(&temp_file_path, usage_content)?;
+    std::fs::rename(&temp_file_path, &usage_file_path)?;
+    Ok(())
+}
+/// Load the usage saved last time, start from empty when the user has no
+/// usage yet. The broken usage file is refused instead of resetting the
+/// quota, it need to be fixed or removed by the administrator.
+fn load_usage(username: &str) -> Result<TrafficUsage, Error> {
+    let usage_content = match std::fs::read_to_string(usage_file_path(username)?) {
+        Ok(usage_content) => usage_content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(TrafficUsage::default()),
+        Err(e) => {
+            error!("Fail to read traffic usage of user [{username}]: {e:?}");
+            return Err(e.into());
+        }
+    };
+    toml::from_str(&usage_content).map_err(|e| {
+        error!("Fail to parse traffic usage of user [{username}]: {e:?}");
+        e.into()
+    })
+}
+/// Get the traffic of the user, the usage is loaded
+/// from the usage directory when first used.
+pub(crate) fn get_user_traffic(user: &ProxyUser) -> Result<Arc<UserTraffic>, Error> {
+    let user_traffics = USER_TRAFFICS.get_or_init(Default::default);
+    let lock_user_traffics = || {
+        user_traffics
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    };
+    let loaded_user_traffic = lock_user_traffics().get(user.username()).cloned();
+    let user_traffic = match loaded_user_traffic {
+        Some(user_traffic) => user_traffic,
+        None => {
+            // The usage is read without the lock, so the slow disk
+            // does not block the handshakes of the other users.
+            let usage = load_usage(user.username())?;
+            lock_user_traffics()
+                .entry(user.username().to_owned())
+                .or_insert_with(|| {
+                    Arc::new(UserTraffic {
+                        username: user.username().to_owned(),
+                        state: Mutex::new(TrafficState {
+                            upload_bucket: TokenBucket::new(user.upload_rate_limit()),
+                            download_bucket: TokenBucket::new(user.download_rate_limit()),
+                            monthly_traffic_quota: user.monthly_traffic_quota(),
+                            usage,
+                            dirty: false,
+                        }),
+                    })
+                })
+                .clone()
+        }
+    };
+    user_traffic.update_limits(user);
+    Ok(user_traffic)
+}
+/// Save the usage of all the users changed after last saved
+pub(crate) fn save_traffic_usages() {
+    let user_traffics = match USER_TRAFFICS.get() {
+        Some(user_traffics) => user_traffics
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .values()
+            .cloned()
+            .collect::<Vec<_>>(),
+        None => return,
+    };
+    for user_traffic in user_traffics {
+        if let Err(e) = user_traffic.save() {
+            error!(
+                "Fail to save traffic usage of user [{}]: {e:?}",
+                user_traffic.username
+            );
+        }
+    }
+}
+/// Save the usage periodically so that it survive the restart
+pub(crate) async fn persist_traffic_usages() {
+    let save_interval = get_config().traffic_usage_save_interval();
+    loop {
+        sleep(Duration::from_secs(save_interval)).await;
+        debug!("Save traffic usages");
+        save_traffic_usages();
+    }
+}
+/// The stream which count the bytes read from it into the user traffic,
+/// the reading pause when the rate limit reached and fail when the
+/// monthly quota used up. The writing is not touched.
+pub(crate) struct TrafficLimitedStream<S> {
+    inner: S,
+    user_traffic: Arc<UserTraffic>,
+    direction: TrafficDirection,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+impl<S> TrafficLimitedStream<S> {
+    pub fn new(inner: S, user_traffic: Arc<UserTraffic>, direction: TrafficDirection) -> Self {
+        Self {
+            inner,
+            user_traffic,
+            direction,
+            delay: None,
+        }
+    }
+}
+impl<S> AsyncRead for TrafficLimitedStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(delay) = &mut this.delay {
+            ready!(delay.as_mut().poll(cx));
+            this.delay = None;
+        }
+        let filled_before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        let size = (buf.filled().len() - filled_before) as u64;
+        if size == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        match this.user_traffic.record(this.direction, size) {
+            Ok(delay) if delay.is_zero() => {}
+            Ok(delay) => this.delay = Some(Box::pin(sleep(delay))),
+            Err(e) => {
+                warn!("Stop relay because of: {e}");
+                return Poll::Ready(Err(std::io::Error::other(e)));
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+impl<S> AsyncWrite for TrafficLimitedStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+#[test]
+fn test() {
+    let mut token_bucket = TokenBucket::new(Some(1000));
+    // The burst of one second pass without waiting
+    assert!(token_bucket.consume(1000).is_zero());
+    // The debt need to be paid before the next transfer
+    let delay = token_bucket.consume(500);
+    assert!(delay > Duration::from_millis(450) && delay <= Duration::from_millis(500));
+    let mut unlimited = TokenBucket::new(None);
+    assert!(unlimited.consume(u64::MAX).is_zero());
+    // The username can not escape from the usage directory
+    for username in ["", ".", "..", "../user", "a/b", "a\\b", "a\0b"] {
+        assert!(usage_file_path(username).is_err(), "{username}");
+    }
+}
@@ -1,44 +1,127 @@
-use crate::client::ClientTcpRelayEndpoint;
-use crate::config::get_config;
+use crate::acl::{check_forwarded_destination, resolve_permitted_destination};
+use crate::client::{ClientFramed, ClientTcpRelayEndpoint};
+use crate::config::{ForwardConfig, get_config};
 use crate::destination;
 use crate::destination::Destination;
+use crate::destination::bind::BindDestListener;
 use crate::destination::udp::UdpDestEndpoint;
 use crate::error::Error;
-use crate::user::{get_forward_user_repo, get_user_repo};
+use crate::forward::{Egress, select_egress};
+use crate::traffic::{TrafficDirection, TrafficLimitedStream, UserTraffic, get_user_traffic};
+use crate::user::{
+    get_forward_user_repo, get_user_mux_stream_limiter, get_user_repo, get_user_session_limiter,
+};
 use bincode::config::Configuration;
+use chrono::{DateTime, TimeDelta, Utc};
 use common::Error as CommonError;
-use common::config::WithUsernameConfig;
-use common::proxy::{DestinationType, ProxyConnection};
+use common::config::{WithUserRepositoryConfig, WithUsernameConfig};
+use common::limit::ConnectionPermit;
+use common::proxy::mux::MuxListener;
+use common::proxy::udp::{UdpDatagram, UdpRelay, UdpRelaySender};
+use common::proxy::{DestinationType, ProxyConnection, ProxyFramed};
 use common::user::User;
-use common::user::UserRepository;
+use common::user::{UserRepository, UserWithExpiredTime, UserWithProxyServers};
 use common::{
-    SecureLengthDelimitedCodec, ServerState, get_handshake_encryption, random_generate_encryption,
-    rsa_decrypt_encryption, rsa_encrypt_encryption,
+    HandshakeTranscript, SecureLengthDelimitedCodec, ServerState, derive_handshake_encryption,
+    random_generate_encryption, sign_server_handshake, sign_server_rejection,
+    supported_capabilities, verify_client_handshake,
 };
+use crypto::X25519KeyExchange;
 use destination::tcp::TcpDestEndpoint;
 use futures_util::{SinkExt, StreamExt};
 use protocol::{
-    ClientHandshake, ClientSetupDestination, Encryption, ServerHandshake, ServerSetupDestination,
+    Capabilities, ClientHandshake, ClientKeyExchange, ClientSetupDestination, Encryption,
+    Error as ProtocolError, HandshakeRejection, PROTOCOL_VERSION, ServerHandshake,
+    ServerKeyExchange, ServerSetupDestination, SetupDestinationFailure, UnifiedAddress,
 };
-use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::future::{Future, pending};
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, copy_bidirectional};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, copy_bidirectional};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{Sender, channel};
+use tokio::task::JoinSet;
+use tokio::time::sleep;
 use tokio_util::codec::Framed;
-use tracing::debug;
+use tracing::{debug, error, warn};
 struct HandshakeResult {
     client_username: String,
+    /// Count the session for the user, taken and kept by the session
+    session_permit: Option<ConnectionPermit<String>>,
+    user_traffic: Arc<UserTraffic>,
     client_encryption: Arc<Encryption>,
     server_encryption: Arc<Encryption>,
+    capabilities: Capabilities,
 }
-struct SetupDestinationResult {
-    client_encryption: Arc<Encryption>,
-    server_encryption: Arc<Encryption>,
-    destination: Destination,
+enum SetupDestinationResult {
+    /// The connection relay with a single destination
+    Relay {
+        client_framed: ClientFramed,
+        client_addr: SocketAddr,
+        user_traffic: Arc<UserTraffic>,
+        destination: Destination,
+    },
+    /// The connection become a multiplexed session, each
+    /// stream in it relay with its own destination
+    Multiplex {
+        client_framed: ClientFramed,
+        client_addr: SocketAddr,
+        client_username: String,
+        user_traffic: Arc<UserTraffic>,
+    },
+    /// The connection relay the datagrams of the client
+    /// with many udp destinations
+    UdpRelay {
+        client_framed: ClientFramed,
+        client_addr: SocketAddr,
+        client_username: String,
+        user_traffic: Arc<UserTraffic>,
+        udp_client_addr: UnifiedAddress,
+    },
 }
 async fn process_handshake(server_state: &mut ServerState) -> Result<HandshakeResult, Error> {
     let mut handshake_framed = Framed::new(
         &mut server_state.incoming_stream,
-        SecureLengthDelimitedCodec::new(get_handshake_encryption(), get_handshake_encryption()),
+        SecureLengthDelimitedCodec::new(Arc::new(Encryption::Plain), Arc::new(Encryption::Plain)),
+    );
+    debug!(
+        "Waiting for receive key exchange from client [{}]",
+        server_state.incoming_connection_addr
+    );
+    let client_key_exchange =
+        handshake_framed
+            .next()
+            .await
+            .ok_or(CommonError::ConnectionExhausted(format!(
+                "Fail to read key exchange message from agent: {}",
+                server_state.incoming_connection_addr
+            )))??;
+    let (client_key_exchange, _) = bincode::decode_from_slice::<ClientKeyExchange, Configuration>(
+        &client_key_exchange,
+        bincode::config::standard(),
+    )
+    .map_err(CommonError::Decode)?;
+    let key_exchange = X25519KeyExchange::new();
+    let transcript = HandshakeTranscript {
+        client_public_key: client_key_exchange.public_key,
+        server_public_key: key_exchange.public_key(),
+    };
+    let server_key_exchange_bytes = bincode::encode_to_vec(
+        ServerKeyExchange {
+            public_key: transcript.server_public_key.clone(),
+        },
+        bincode::config::standard(),
+    )
+    .map_err(CommonError::Encode)?;
+    handshake_framed.send(&server_key_exchange_bytes).await?;
+    let handshake_encryption =
+        derive_handshake_encryption(key_exchange, &transcript.client_public_key, &transcript)?;
+    *handshake_framed.codec_mut() = SecureLengthDelimitedCodec::new(
+        handshake_encryption.client_encryption,
+        handshake_encryption.server_encryption,
     );
     debug!(
         "Waiting for receive handshake from client [{}]",
@@ -51,10 +134,29 @@ async fn process_handshake(server_state: &mut ServerState) -> Result<HandshakeRe
             "Fail to read handshake message from agent: {}",
             server_state.incoming_connection_addr
         )))??;
+    // The version is the first field of the handshake, check it
+    // before decode the whole packet which layout may be different.
+    let (client_version, _) =
+        bincode::decode_from_slice::<u16, Configuration>(&handshake, bincode::config::standard())
+            .map_err(CommonError::Decode)?;
+    if client_version != PROTOCOL_VERSION {
+        // The user is unknown yet, the rejection can not be signed
+        return reject_handshake(
+            &mut handshake_framed,
+            HandshakeRejection::UnsupportedVersion {
+                supported_version: PROTOCOL_VERSION,
+            },
+            Vec::new(),
+        )
+        .await;
+    }
     let (
         ClientHandshake {
+            version: client_version,
+            capabilities: client_capabilities,
             username: client_username,
             encryption: client_encryption,
+            signature: client_signature,
         },
         _,
     ) = bincode::decode_from_slice::<ClientHandshake, Configuration>(
@@ -62,67 +164,174 @@ async fn process_handshake(server_state: &mut ServerState) -> Result<HandshakeRe
         bincode::config::standard(),
     )
         .map_err(CommonError::Decode)?;
-    debug!(
-        "Receive client handshake, client username: {client_username}, client encryption: {client_encryption:?}"
-    );
+    debug!("Receive client handshake, client username: {client_username}");
     let proxy_user_info = get_user_repo()
         .find_user(&client_username)
         .ok_or(CommonError::UserNotExist(client_username.clone()))?;
-    let client_encryption = rsa_decrypt_encryption(
-        client_encryption,
-        proxy_user_info
-            .rsa_crypto()
-            .ok_or(CommonError::UserRsaCryptoNotExist(client_username.clone()))?,
-    )?;
-    let client_encryption = Arc::new(client_encryption);
+    let now = Utc::now();
+    let proxy_user_rsa_keys = proxy_user_info
+        .rsa_keys()
+        .iter()
+        .filter(|rsa_key| rsa_key.is_active(&now))
+        .collect::<Vec<_>>();
+    if proxy_user_rsa_keys.is_empty() {
+        return Err(CommonError::UserRsaCryptoNotExist(client_username).into());
+    }
+    // Only the agent own the private key of this user can create the signature,
+    // the agent may hold any of the active keys while the keys are rotating.
+    let proxy_user_rsa_key = proxy_user_rsa_keys
+        .into_iter()
+        .find(|rsa_key| {
+            verify_client_handshake(
+                &transcript,
+                &client_username,
+                &(client_version, &client_capabilities, &client_encryption),
+                &client_signature,
+                rsa_key.rsa_crypto(),
+            )
+            .is_ok()
+        })
+        .ok_or(CommonError::UserRsaKeyNotMatch(client_username.clone()))?;
+    // The server handshake is signed with the same key, so
+    // the agent holding the rotated key can verify it.
+    let proxy_user_rsa_crypto = proxy_user_rsa_key.rsa_crypto();
     debug!(
-        "Receive handshake from client [{}], username: {client_username}, client_encryption: {client_encryption:?}",
-        server_state.incoming_connection_addr
+        "Receive handshake from client [{}], username: {client_username}, rsa key: {}, capabilities: {client_capabilities:?}",
+        server_state.incoming_connection_addr,
+        proxy_user_rsa_key.id()
     );
-    let server_encryption = random_generate_encryption();
-    let server_encryption = Arc::new(server_encryption);
-    let rsa_encrypted_server_encryption = rsa_encrypt_encryption(
-        &server_encryption,
-        proxy_user_info
-            .rsa_crypto()
-            .ok_or(CommonError::UserRsaCryptoNotExist(client_username.clone()))?,
-    )?;
-    let server_handshake = ServerHandshake {
-        encryption: rsa_encrypted_server_encryption.into_owned(),
+    // Sign the rejection so that nobody in the middle can fake it
+    let sign_rejection = |rejection: &HandshakeRejection| {
+        sign_server_rejection(
+            &transcript,
+            &client_username,
+            rejection,
+            proxy_user_rsa_crypto,
+        )
+    };
+    let client_expired_time = proxy_user_info.expired_time();
+    if let Some(expired_time) = client_expired_time
+        && is_user_expired(expired_time, &now)
+    {
+        warn!("Reject handshake of expired user [{client_username}], expired at {expired_time}");
+        let rejection = HandshakeRejection::UserExpired {
+            expired_time: expired_time.timestamp(),
+        };
+        let signature = sign_rejection(&rejection)?;
+        return reject_handshake(&mut handshake_framed, rejection, signature).await;
+    }
+    let Some(session_permit) = get_user_session_limiter()
+        .try_acquire(client_username.clone(), proxy_user_info.max_sessions())
+    else {
+        let max_sessions = proxy_user_info.max_sessions().unwrap_or_default();
+        warn!(
+            "Reject handshake of user [{client_username}] because it reach the max sessions: {max_sessions}"
+        );
+        let rejection = HandshakeRejection::TooManySessions {
+            max_sessions: max_sessions.try_into().unwrap_or(u32::MAX),
+        };
+        let signature = sign_rejection(&rejection)?;
+        return reject_handshake(&mut handshake_framed, rejection, signature).await;
+    };
+    // Warn the user in the grace period before it lose the access
+    let user_expired_time = client_expired_time
+        .filter(|expired_time| {
+            is_in_expiry_grace_period(expired_time, &now, get_config().user_expiry_grace_period())
+        })
+        .map(|expired_time| {
+            warn!("User [{client_username}] will expire at {expired_time}");
+            expired_time.timestamp()
+        });
+    // The session is refused when the traffic usage of the user can not be loaded
+    let user_traffic = get_user_traffic(&proxy_user_info)?;
+    let capabilities = client_capabilities.intersect(&supported_capabilities());
+    let client_cipher_accepted = client_encryption
+        .cipher_suite()
+        .is_some_and(|cipher| capabilities.ciphers.contains(&cipher));
+    if !client_cipher_accepted {
+        let rejection = HandshakeRejection::NoCommonCipher;
+        let signature = sign_rejection(&rejection)?;
+        return reject_handshake(&mut handshake_framed, rejection, signature).await;
+    }
+    let client_encryption = Arc::new(client_encryption);
+    let server_encryption = random_generate_encryption(&capabilities.ciphers);
+    let server_handshake = ServerHandshake::Success {
+        version: PROTOCOL_VERSION,
+        signature: sign_server_handshake(
+            &transcript,
+            &client_username,
+            &(
+                PROTOCOL_VERSION,
+                &capabilities,
+                &server_encryption,
+                user_expired_time,
+            ),
+            proxy_user_rsa_crypto,
+        )?,
+        capabilities: capabilities.clone(),
+        encryption: server_encryption.clone(),
+        user_expired_time,
     };
+    let server_encryption = Arc::new(server_encryption);
     let server_handshake_bytes =
         bincode::encode_to_vec(server_handshake, bincode::config::standard())
             .map_err(CommonError::Encode)?;
     handshake_framed.send(&server_handshake_bytes).await?;
     debug!(
-        "Send handshake to client [{}], username: {client_username}, client_encryption: {client_encryption:?}, server_encryption: {server_encryption:?}",
+        "Send handshake to client [{}], username: {client_username}",
         server_state.incoming_connection_addr
     );
     Ok(HandshakeResult {
+        user_traffic,
+        session_permit: Some(session_permit),
         client_username,
         client_encryption,
         server_encryption,
+        capabilities,
     })
 }
+/// Send the rejection to client so that the agent can know why
+/// the handshake fail instead of seeing a closed connection.
+async fn reject_handshake(
+    handshake_framed: &mut Framed<&mut TcpStream, SecureLengthDelimitedCodec>,
+    rejection: HandshakeRejection,
+    signature: Vec<u8>,
+) -> Result<HandshakeResult, Error> {
+    let server_handshake_bytes = bincode::encode_to_vec(
+        ServerHandshake::Reject {
+            rejection: rejection.clone(),
+            signature,
+        },
+        bincode::config::standard(),
+    )
+    .map_err(CommonError::Encode)?;
+    handshake_framed.send(&server_handshake_bytes).await?;
+    Err(CommonError::HandshakeRejected(rejection).into())
+}
 async fn process_setup_destination(
-    server_state: &mut ServerState,
+    server_state: ServerState,
     handshake_result: HandshakeResult,
 ) -> Result<SetupDestinationResult, Error> {
     let HandshakeResult {
         client_username,
+        user_traffic,
         client_encryption,
         server_encryption,
+        capabilities,
+        ..
     } = handshake_result;
     debug!("Begin to setup destination for client user: {client_username}");
-    let mut setup_destination_frame = Framed::new(
-        &mut server_state.incoming_stream,
-        SecureLengthDelimitedCodec::new(
-            Arc::clone(&client_encryption),
-            Arc::clone(&server_encryption),
-        ),
+    let client_addr = server_state.incoming_connection_addr;
+    // The peer of bind connect to the same ip the agent connected
+    let bind_ip = server_state.incoming_stream.local_addr()?.ip();
+    // The same codec must be used until the relay finish,
+    // otherwise the AEAD nonce will be reused.
+    let mut client_framed = Framed::new(
+        server_state.incoming_stream,
+        SecureLengthDelimitedCodec::new(client_encryption, server_encryption),
     );
     let setup_destination_data_packet =
-        setup_destination_frame
+        client_framed
             .next()
             .await
             .ok_or(CommonError::ConnectionExhausted(format!(
@@ -135,112 +344,795 @@ async fn process_setup_destination(
             bincode::config::standard(),
         )
             .map_err(CommonError::Decode)?;
-    let destination = match (get_config().forward(), get_forward_user_repo()) {
-        (Some(forward_config), Some(forward_user_repository)) => {
-            let forward_user_info = forward_user_repository
-                .find_user(forward_config.username())
-                .ok_or(CommonError::UserNotExist(
-                    forward_config.username().to_owned(),
-                ))?;
-            match setup_destination {
-                ClientSetupDestination::Tcp(dst_addr) => {
-                    let proxy_connection = ProxyConnection::new(
-                        forward_user_info,
-                        forward_config.proxy_connect_timeout(),
-                    )
-                        .await?;
-                    let proxy_connection = proxy_connection
-                        .setup_destination(dst_addr, DestinationType::Tcp)
-                        .await?;
-                    Destination::Forward(Box::new(proxy_connection))
-                }
-                ClientSetupDestination::Udp { .. } => {
-                    unimplemented!("UDP still not support")
+    if let Err(e) = user_traffic.check_quota() {
+        send_setup_destination_fail(&mut client_framed, &e).await?;
+        return Err(e);
+    }
+    let destination = match setup_destination {
+        ClientSetupDestination::Multiplex if capabilities.multiplexing => None,
+        ClientSetupDestination::Multiplex => {
+            let e: Error = CommonError::MultiplexingNotSupported.into();
+            send_setup_destination_fail(&mut client_framed, &e).await?;
+            return Err(e);
+        }
+        ClientSetupDestination::Bind(peer_addr) => {
+            // The replies of bind are sent during the process
+            let bind = process_bind(&mut client_framed, &client_username, bind_ip, peer_addr);
+            return match bind.await {
+                Ok(destination) => Ok(SetupDestinationResult::Relay {
+                    client_framed,
+                    client_addr,
+                    user_traffic,
+                    destination,
+                }),
+                Err(e) => {
+                    send_setup_destination_fail(&mut client_framed, &e).await?;
+                    Err(e)
                 }
-            }
+            };
         }
-        _ => match setup_destination {
-            ClientSetupDestination::Tcp(dst_addr) => Destination::Tcp(
-                TcpDestEndpoint::connect(dst_addr, get_config().destination_connect_timeout())
-                    .await?,
-            ),
-            ClientSetupDestination::Udp(dst_addr) => Destination::Udp {
-                dst_udp_endpoint: UdpDestEndpoint::bind().await?,
-                dst_addr,
-            },
+        ClientSetupDestination::Udp(udp_client_addr) => {
+            debug!(
+                "Setup udp relay for client [{client_addr}], udp client address: {udp_client_addr}"
+            );
+            // The egress of each datagram is decided when it relayed
+            send_setup_destination(&mut client_framed, ServerSetupDestination::Success).await?;
+            return Ok(SetupDestinationResult::UdpRelay {
+                client_framed,
+                client_addr,
+                client_username,
+                user_traffic,
+                udp_client_addr,
+            });
+        }
+        setup_destination => match connect_destination(&client_username, setup_destination).await {
+            Ok(destination) => Some(destination),
+            Err(e) => {
+                send_setup_destination_fail(&mut client_framed, &e).await?;
+                return Err(e);
+            }
         },
     };
-    let server_setup_destination_data_packet = ServerSetupDestination::Success;
-    let server_setup_destination_data_packet = bincode::encode_to_vec(
-        server_setup_destination_data_packet,
-        bincode::config::standard(),
-    )
-        .map_err(CommonError::Encode)?;
-    setup_destination_frame
+    send_setup_destination(&mut client_framed, ServerSetupDestination::Success).await?;
+    Ok(match destination {
+        Some(destination) => SetupDestinationResult::Relay {
+            client_framed,
+            client_addr,
+            user_traffic,
+            destination,
+        },
+        None => SetupDestinationResult::Multiplex {
+            client_framed,
+            client_addr,
+            client_username,
+            user_traffic,
+        },
+    })
+}
+async fn send_setup_destination(
+    client_framed: &mut ClientFramed,
+    server_setup_destination: ServerSetupDestination,
+) -> Result<(), Error> {
+    let server_setup_destination_data_packet =
+        bincode::encode_to_vec(server_setup_destination, bincode::config::standard())
+            .map_err(CommonError::Encode)?;
+    client_framed
         .send(&server_setup_destination_data_packet)
         .await?;
-    Ok(SetupDestinationResult {
-        client_encryption,
-        server_encryption,
-        destination,
-    })
+    Ok(())
 }
-async fn process_relay(
-    server_state: ServerState,
-    setup_target_endpoint_result: SetupDestinationResult,
+/// Tell the agent why the destination can not be setup,
+/// so it can give the right reply to its client.
+async fn send_setup_destination_fail(
+    client_framed: &mut ClientFramed,
+    error: &Error,
 ) -> Result<(), Error> {
-    let SetupDestinationResult {
-        client_encryption,
-        server_encryption,
-        destination,
-    } = setup_target_endpoint_result;
-    let ServerState {
-        incoming_stream: client_stream,
-        incoming_connection_addr: client_addr,
-    } = server_state;
+    send_setup_destination(
+        client_framed,
+        ServerSetupDestination::Fail(setup_destination_failure(error)),
+    )
+    .await
+}
+/// Listen for the peer of bind, the agent is told the listening address
+/// first, and then the address of the peer when it connected. The bind
+/// go through the forward hop when the peer is routed to it.
+async fn process_bind(
+    client_framed: &mut ClientFramed,
+    client_username: &str,
+    bind_ip: IpAddr,
+    peer_addr: UnifiedAddress,
+) -> Result<Destination, Error> {
+    if let Some(forward_proxy_connection) =
+        connect_forward_proxy(client_username, &peer_addr).await?
+    {
+        let (forward_proxy_connection, bound_addr) =
+            forward_proxy_connection.setup_bind(peer_addr).await?;
+        send_setup_destination(client_framed, ServerSetupDestination::Bound(bound_addr)).await?;
+        let (forward_proxy_connection, inbound_peer_addr) =
+            forward_proxy_connection.accept_peer().await?;
+        send_setup_destination(
+            client_framed,
+            ServerSetupDestination::PeerConnected(inbound_peer_addr),
+        )
+        .await?;
+        return Ok(Destination::Forward(Box::new(forward_proxy_connection)));
+    }
+    let bind_dest_listener = BindDestListener::bind(bind_ip).await?;
+    let bound_addr = bind_dest_listener.local_addr()?;
+    debug!("Listening on [{bound_addr}] for the peer of bind: {peer_addr}");
+    send_setup_destination(client_framed, ServerSetupDestination::Bound(bound_addr)).await?;
+    let dst_tcp_endpoint = bind_dest_listener
+        .accept(peer_addr, get_config().bind_accept_timeout())
+        .await?;
+    send_setup_destination(
+        client_framed,
+        ServerSetupDestination::PeerConnected(dst_tcp_endpoint.dst_addr()),
+    )
+    .await?;
+    Ok(Destination::Tcp(dst_tcp_endpoint))
+}
+/// Accept the streams in multiplexed session, each stream
+/// setup and relay with its destination independently.
+async fn process_mux_session(
+    client_framed: ClientFramed,
+    client_addr: SocketAddr,
+    client_username: String,
+    user_traffic: Arc<UserTraffic>,
+) {
+    debug!("Begin multiplexed session for client [{client_addr}]");
+    let mut mux_listener = MuxListener::accept(client_framed);
+    // The streams are aborted when the session dropped
+    let mut mux_stream_tasks = JoinSet::new();
+    loop {
+        let (setup_destination, mux_stream) = tokio::select! {
+            accepted = mux_listener.next() => match accepted {
+                Some(accepted) => accepted,
+                None => break,
+            },
+            Some(_) = mux_stream_tasks.join_next() => continue,
+        };
+        while mux_stream_tasks.try_join_next().is_some() {}
+        if mux_stream_tasks.len() >= get_config().mux_max_streams() {
+            warn!(
+                "Reject multiplexed stream of client [{client_addr}] because it reach the max streams"
+            );
+            let _ = mux_stream.reply_open(ServerSetupDestination::Fail(
+                SetupDestinationFailure::TooManyStreams,
+            ));
+            continue;
+        }
+        // The streams of the user are limited like the dedicated sessions,
+        // otherwise one multiplexed session can carry unlimited streams.
+        let max_streams = get_user_repo()
+            .find_user(&client_username)
+            .and_then(|user| user.max_sessions());
+        let Some(stream_permit) =
+            get_user_mux_stream_limiter().try_acquire(client_username.clone(), max_streams)
+        else {
+            warn!(
+                "Reject multiplexed stream of user [{client_username}] because it reach the max sessions: {}",
+                max_streams.unwrap_or_default()
+            );
+            let _ = mux_stream.reply_open(ServerSetupDestination::Fail(
+                SetupDestinationFailure::TooManyStreams,
+            ));
+            continue;
+        };
+        let client_username = client_username.clone();
+        let user_traffic = user_traffic.clone();
+        mux_stream_tasks.spawn(async move {
+            let _stream_permit = stream_permit;
+            let destination = match setup_destination {
+                ClientSetupDestination::Multiplex => {
+                    Err(CommonError::MultiplexingNotSupported.into())
+                }
+                setup_destination => match user_traffic.check_quota() {
+                    Ok(()) => connect_destination(&client_username, setup_destination).await,
+                    Err(e) => Err(e),
+                },
+            };
+            let destination = match destination {
+                Ok(destination) => destination,
+                Err(e) => {
+                    error!("Fail to setup destination for multiplexed stream of client [{client_addr}]: {e:?}");
+                    let _ = mux_stream
+                        .reply_open(ServerSetupDestination::Fail(setup_destination_failure(&e)));
+                    return;
+                }
+            };
+            if let Err(e) = mux_stream.reply_open(ServerSetupDestination::Success) {
+                error!("Fail to reply multiplexed stream of client [{client_addr}]: {e:?}");
+                return;
+            }
+            if let Err(e) = process_relay(mux_stream, client_addr, user_traffic, destination).await {
+                error!("Fail to relay multiplexed stream of client [{client_addr}]: {e:?}");
+            }
+        });
+    }
+    while mux_stream_tasks.join_next().await.is_some() {}
+    debug!("Multiplexed session for client [{client_addr}] closed");
+}
+/// Relay the data between the client and the destination, the data
+/// count into the traffic of the user in both directions.
+async fn process_relay<C>(
+    client_tcp_relay_endpoint: C,
+    client_addr: SocketAddr,
+    user_traffic: Arc<UserTraffic>,
+    destination: Destination,
+) -> Result<(), Error>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut client_tcp_relay_endpoint = TrafficLimitedStream::new(
+        client_tcp_relay_endpoint,
+        user_traffic.clone(),
+        TrafficDirection::Upload,
+    );
     match destination {
-        Destination::Tcp(mut dst_tcp_endpoint) => {
+        Destination::Tcp(dst_tcp_endpoint) => {
             debug!(
                 "Begin to relay tcp data from client [{client_addr}] to destination [{}]",
                 dst_tcp_endpoint.dst_addr()
             );
-            let mut client_tcp_relay_endpoint =
-                ClientTcpRelayEndpoint::new(client_stream, client_encryption, server_encryption);
+            let mut dst_tcp_endpoint = TrafficLimitedStream::new(
+                dst_tcp_endpoint,
+                user_traffic,
+                TrafficDirection::Download,
+            );
             copy_bidirectional(&mut client_tcp_relay_endpoint, &mut dst_tcp_endpoint).await?;
         }
-        Destination::Forward(mut forward_proxy_connection) => {
-            let mut client_tcp_relay_endpoint =
-                ClientTcpRelayEndpoint::new(client_stream, client_encryption, server_encryption);
+        Destination::Forward(forward_proxy_connection) => {
+            let mut forward_proxy_connection = TrafficLimitedStream::new(
+                forward_proxy_connection,
+                user_traffic,
+                TrafficDirection::Download,
+            );
             copy_bidirectional(
                 &mut client_tcp_relay_endpoint,
                 &mut forward_proxy_connection,
             )
                 .await?;
         }
-        Destination::Udp {
-            dst_udp_endpoint,
-            dst_addr,
-        } => {
-            let mut client_tcp_relay_endpoint =
-                ClientTcpRelayEndpoint::new(client_stream, client_encryption, server_encryption);
-            let mut client_data = [0u8; 65536];
-            AsyncReadExt::read(&mut client_tcp_relay_endpoint, &mut client_data).await?;
-            let dst_sock_addrs: Vec<SocketAddr> = (&dst_addr).try_into()?;
-            let dst_udp_data = dst_udp_endpoint
-                .replay_to(&dst_sock_addrs[..], &client_data)
-                .await?;
-            AsyncWriteExt::write(&mut client_tcp_relay_endpoint, &dst_udp_data).await?;
-        }
     }
     Ok(())
 }
+/// The datagrams from forward hops can wait before sent to the client
+const FORWARD_DATAGRAM_QUEUE_SIZE: usize = 256;
+/// Relay the datagrams between the client and the destinations until
+/// the client close the relay or no datagram during the idle timeout.
+/// Each datagram exit from local or the forward hop by the forward rules,
+/// the datagrams to the same forward hop share one udp relay. The datagrams
+/// count into the traffic quota of the user but not the rate limit, as
+/// they can not wait like the tcp data.
+async fn process_udp_relay(
+    client_framed: ClientFramed,
+    client_addr: SocketAddr,
+    client_username: String,
+    user_traffic: Arc<UserTraffic>,
+    udp_client_addr: UnifiedAddress,
+) -> Result<(), Error> {
+    let udp_idle_timeout = get_config().udp_idle_timeout();
+    let (client_sender, mut client_receiver) = UdpRelay::new(client_framed).split();
+    // The udp socket is bound when the first datagram exit from local
+    let mut dst_udp_endpoint: Option<UdpDestEndpoint> = None;
+    let mut forward_udp_relays = ForwardUdpRelays::default();
+    let (forward_datagram_sender, mut forward_datagram_receiver) =
+        channel::<UdpDatagram>(FORWARD_DATAGRAM_QUEUE_SIZE);
+    // The datagrams from destinations are sent back to the latest udp client
+    let mut latest_udp_client_addr = None;
+    let mut dst_udp_data = vec![0u8; 65536];
+    loop {
+        tokio::select! {
+            client_datagram = client_receiver.recv() => {
+                let Some(client_datagram) = client_datagram else {
+                    debug!("Udp relay closed by client [{client_addr}]");
+                    return Ok(());
+                };
+                user_traffic.record(TrafficDirection::Upload, client_datagram.payload.len() as u64)?;
+                latest_udp_client_addr = Some(client_datagram.src_addr.clone());
+                let dst_addr = &client_datagram.dst_addr;
+                let forward_hop = match select_egress(dst_addr) {
+                    Ok(Egress::Local) => {
+                        let dst_socket_addr = match resolve_permitted_destination(&client_username, dst_addr).await {
+                            Ok(dst_socket_addrs) => dst_socket_addrs[0],
+                            Err(e) => {
+                                error!("Drop udp data of client [{client_addr}] to destination [{dst_addr}]: {e:?}");
+                                continue;
+                            }
+                        };
+                        let dst_udp_endpoint = match &dst_udp_endpoint {
+                            Some(dst_udp_endpoint) => dst_udp_endpoint,
+                            None => dst_udp_endpoint.insert(UdpDestEndpoint::bind().await?),
+                        };
+                        if let Err(e) = dst_udp_endpoint.send_to(&client_datagram.payload, dst_socket_addr).await {
+                            error!("Fail to send udp data of client [{client_addr}] to destination [{dst_addr}]: {e:?}");
+                        }
+                        continue;
+                    }
+                    Ok(Egress::Hop(forward_hop)) => {
+                        if let Err(e) = check_forwarded_destination(&client_username, dst_addr) {
+                            error!("Drop udp data of client [{client_addr}] to destination [{dst_addr}]: {e:?}");
+                            continue;
+                        }
+                        forward_hop
+                    }
+                    Err(e) => {
+                        error!("Drop udp data of client [{client_addr}] to destination [{dst_addr}]: {e:?}");
+                        continue;
+                    }
+                };
+                // The datagrams keep their addresses, the forward hop send
+                // them to the destinations and the responses come back.
+                let create_forward_relay = || {
+                    create_forward_udp_relay(
+                        forward_hop,
+                        udp_client_addr.clone(),
+                        forward_datagram_sender.clone(),
+                    )
+                };
+                if let Err(e) = forward_udp_relays
+                    .send(forward_hop.name(), client_datagram, create_forward_relay)
+                    .await
+                {
+                    error!("Fail to send udp data of client [{client_addr}] to forward hop [{}]: {e:?}", forward_hop.name());
+                }
+            }
+            received = recv_local(&dst_udp_endpoint, &mut dst_udp_data) => {
+                let (size, dst_addr) = match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        error!("Fail to receive udp data for client [{client_addr}]: {e:?}");
+                        continue;
+                    }
+                };
+                let Some(latest_udp_client_addr) = &latest_udp_client_addr else {
+                    continue;
+                };
+                user_traffic.record(TrafficDirection::Download, size as u64)?;
+                client_sender.send(UdpDatagram {
+                    src_addr: dst_addr.into(),
+                    dst_addr: latest_udp_client_addr.clone(),
+                    payload: dst_udp_data[..size].to_vec(),
+                })?;
+            }
+            Some(forward_datagram) = forward_datagram_receiver.recv() => {
+                user_traffic.record(TrafficDirection::Download, forward_datagram.payload.len() as u64)?;
+                client_sender.send(forward_datagram)?;
+            }
+            _ = sleep(Duration::from_secs(udp_idle_timeout)) => {
+                debug!("Udp relay of client [{client_addr}] idle for {udp_idle_timeout} seconds");
+                return Ok(());
+            }
+        }
+    }
+}
+/// The udp relays with the forward hops, the datagrams to the same hop share
+/// one relay, and the relay closed by the hop is created again.
+#[derive(Default)]
+struct ForwardUdpRelays {
+    relays: HashMap<&'static str, UdpRelaySender>,
+}
+impl ForwardUdpRelays {
+    /// Send the datagram through the relay with the forward hop, the
+    /// relay is created when not exist or closed.
+    async fn send<F, R>(
+        &mut self,
+        forward_hop_name: &'static str,
+        datagram: UdpDatagram,
+        create_relay: F,
+    ) -> Result<(), Error>
+    where
+        F: FnOnce() -> R,
+        R: Future<Output = Result<UdpRelaySender, Error>>,
+    {
+        let forward_sender = match self.relays.get(forward_hop_name) {
+            Some(forward_sender) if !forward_sender.is_closed() => forward_sender,
+            _ => self
+                .relays
+                .entry(forward_hop_name)
+                .insert_entry(create_relay().await?)
+                .into_mut(),
+        };
+        Ok(forward_sender.send(datagram)?)
+    }
+}
+/// Receive the datagram from the destinations exit from local,
+/// wait forever when no datagram exit from local yet.
+async fn recv_local(
+    dst_udp_endpoint: &Option<UdpDestEndpoint>,
+    buf: &mut [u8],
+) -> Result<(usize, SocketAddr), Error> {
+    match dst_udp_endpoint {
+        Some(dst_udp_endpoint) => dst_udp_endpoint.recv_from(buf).await,
+        None => pending().await,
+    }
+}
+/// Setup the udp relay with the forward hop, the datagrams
+/// from the forward hop are delivered to the sender.
+async fn create_forward_udp_relay(
+    forward_hop: &ForwardConfig,
+    udp_client_addr: UnifiedAddress,
+    forward_datagram_sender: Sender<UdpDatagram>,
+) -> Result<UdpRelaySender, Error> {
+    let (forward_sender, mut forward_receiver) = connect_forward_hop(forward_hop)
+        .await?
+        .setup_udp_relay(udp_client_addr)
+        .await?
+        .split();
+    tokio::spawn(async move {
+        while let Some(forward_datagram) = forward_receiver.recv().await {
+            if forward_datagram_sender
+                .send(forward_datagram)
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+    Ok(forward_sender)
+}
+/// Connect to the forward hop of the destination when it is not exit
+/// from local, the returned connection complete handshake already.
+/// The destination is checked by the access control before forwarded.
+async fn connect_forward_proxy(
+    client_username: &str,
+    destination_address: &UnifiedAddress,
+) -> Result<Option<ProxyConnection<ProxyFramed>>, Error> {
+    match select_egress(destination_address)? {
+        Egress::Local => Ok(None),
+        Egress::Hop(forward_hop) => {
+            check_forwarded_destination(client_username, destination_address)?;
+            Ok(Some(connect_forward_hop(forward_hop).await?))
+        }
+    }
+}
+/// Connect to the proxy of the forward hop with its forward user
+async fn connect_forward_hop(
+    forward_hop: &ForwardConfig,
+) -> Result<ProxyConnection<ProxyFramed>, Error> {
+    let forward_user_repository = get_forward_user_repo(forward_hop.name())
+        .ok_or(Error::ForwardHopNotExist(forward_hop.name().to_owned()))?;
+    let forward_user_info = forward_user_repository
+        .find_user(forward_hop.username())
+        .ok_or(CommonError::UserNotExist(forward_hop.username().to_owned()))?;
+    let forward_proxy_servers = forward_user_info.proxy_servers().to_vec();
+    let proxy_connection = ProxyConnection::new(
+        forward_user_info,
+        &forward_proxy_servers,
+        forward_hop.proxy_connect_timeout(),
+    )
+    .await?;
+    Ok(proxy_connection)
+}
+/// Connect to the destination, or the forward hop
+/// when the destination is routed to it.
+async fn connect_destination(
+    client_username: &str,
+    setup_destination: ClientSetupDestination,
+) -> Result<Destination, Error> {
+    let destination = match setup_destination {
+        ClientSetupDestination::Multiplex => {
+            return Err(CommonError::MultiplexingNotSupported.into());
+        }
+        // Bind need more than one reply and udp need the datagram
+        // frames, so they always use the dedicated connection.
+        ClientSetupDestination::Bind(_) => {
+            return Err(Error::NotSupportedInMultiplexedSession("Bind"));
+        }
+        ClientSetupDestination::Udp(_) => {
+            return Err(Error::NotSupportedInMultiplexedSession("Udp"));
+        }
+        ClientSetupDestination::Tcp(dst_addr) => {
+            match connect_forward_proxy(client_username, &dst_addr).await? {
+                Some(proxy_connection) => {
+                    let proxy_connection = proxy_connection
+                        .setup_destination(dst_addr, DestinationType::Tcp)
+                        .await?;
+                    Destination::Forward(Box::new(proxy_connection))
+                }
+                None => {
+                    let dst_socket_addrs =
+                        resolve_permitted_destination(client_username, &dst_addr).await?;
+                    Destination::Tcp(
+                        TcpDestEndpoint::connect(
+                            &dst_socket_addrs,
+                            get_config().destination_connect_timeout(),
+                        )
+                        .await?,
+                    )
+                }
+            }
+        }
+    };
+    Ok(destination)
+}
+/// Classify the error happen in destination setup
+fn setup_destination_failure(error: &Error) -> SetupDestinationFailure {
+    fn io_failure(error: &std::io::Error) -> SetupDestinationFailure {
+        match error.kind() {
+            ErrorKind::ConnectionRefused => SetupDestinationFailure::ConnectionRefused,
+            ErrorKind::TimedOut => SetupDestinationFailure::Timeout,
+            ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable => {
+                SetupDestinationFailure::Unreachable
+            }
+            // Happens when the domain resolved to no address
+            ErrorKind::InvalidInput => SetupDestinationFailure::DnsResolution,
+            _ => SetupDestinationFailure::Other,
+        }
+    }
+    match error {
+        Error::Io(e) => io_failure(e),
+        Error::Protocol(ProtocolError::Io(_)) => SetupDestinationFailure::DnsResolution,
+        Error::Common(CommonError::Io(e)) => io_failure(e),
+        Error::Common(CommonError::ConnectTimeout(_)) => SetupDestinationFailure::Timeout,
+        Error::Common(CommonError::SetupDestination(_, failure)) => *failure,
+        Error::BindAcceptTimeout(_) => SetupDestinationFailure::Timeout,
+        Error::DestinationDenied(_) => SetupDestinationFailure::AclDenied,
+        Error::UserExpired(_) => SetupDestinationFailure::UserExpired,
+        Error::QuotaExceeded(_) => SetupDestinationFailure::QuotaExceeded,
+        _ => SetupDestinationFailure::Other,
+    }
+}
+/// The user can not start new session since the expired time
+fn is_user_expired(expired_time: &DateTime<Utc>, now: &DateTime<Utc>) -> bool {
+    expired_time <= now
+}
+/// The user expires within the grace period, so it is warned in the handshake
+fn is_in_expiry_grace_period(
+    expired_time: &DateTime<Utc>,
+    now: &DateTime<Utc>,
+    grace_period: i64,
+) -> bool {
+    *expired_time - *now <= TimeDelta::seconds(grace_period)
+}
+/// How long to wait before checking the user again, the user is checked at
+/// least once per check interval. `None` means the user expired already.
+fn next_user_expiry_check(
+    expired_time: Option<&DateTime<Utc>>,
+    now: &DateTime<Utc>,
+    check_interval: Duration,
+) -> Option<Duration> {
+    let Some(expired_time) = expired_time else {
+        return Some(check_interval);
+    };
+    if is_user_expired(expired_time, now) {
+        return None;
+    }
+    let remaining = (*expired_time - *now).to_std().unwrap_or_default();
+    Some(remaining.min(check_interval))
+}
+/// Wait until the user expired or removed, the user is found again in each
+/// check so that the live session follows the changes in the user repository.
+async fn wait_user_expired(client_username: &str) -> Error {
+    let check_interval = Duration::from_secs(get_config().refresh_interval_sec());
+    loop {
+        let Some(proxy_user_info) = get_user_repo().find_user(client_username) else {
+            warn!("Close the session of user [{client_username}] because the user is removed");
+            return CommonError::UserNotExist(client_username.to_owned()).into();
+        };
+        let expired_time = proxy_user_info.expired_time();
+        match next_user_expiry_check(expired_time, &Utc::now(), check_interval) {
+            Some(next_check) => sleep(next_check).await,
+            None => {
+                warn!(
+                    "Close the session of user [{client_username}] because the user expired at {expired_time:?}"
+                );
+                return Error::UserExpired(client_username.to_owned());
+            }
+        }
+    }
+}
 pub async fn process(mut server_state: ServerState) -> Result<(), Error> {
     // Process handshake
-    let handshake_result = process_handshake(&mut server_state).await?;
+    let mut handshake_result = process_handshake(&mut server_state).await?;
+    let _session_permit = handshake_result.session_permit.take();
+    let client_username = handshake_result.client_username.clone();
+    // The session is closed when the user expired or removed, all
+    // the relays in the session are dropped together.
+    tokio::select! {
+        result = process_session(server_state, handshake_result) => result,
+        e = wait_user_expired(&client_username) => Err(e),
+    }
+}
+async fn process_session(
+    server_state: ServerState,
+    handshake_result: HandshakeResult,
+) -> Result<(), Error> {
     // Process destination setup
     let setup_target_endpoint_result =
-        process_setup_destination(&mut server_state, handshake_result).await?;
+        process_setup_destination(server_state, handshake_result).await?;
     // Process relay
-    process_relay(server_state, setup_target_endpoint_result).await?;
+    match setup_target_endpoint_result {
+        SetupDestinationResult::Relay {
+            client_framed,
+            client_addr,
+            user_traffic,
+            destination,
+        } => {
+            process_relay(
+                ClientTcpRelayEndpoint::new(client_framed),
+                client_addr,
+                user_traffic,
+                destination,
+            )
+            .await?
+        }
+        SetupDestinationResult::Multiplex {
+            client_framed,
+            client_addr,
+            client_username,
+            user_traffic,
+        } => process_mux_session(client_framed, client_addr, client_username, user_traffic).await,
+        SetupDestinationResult::UdpRelay {
+            client_framed,
+            client_addr,
+            client_username,
+            user_traffic,
+            udp_client_addr,
+        } => {
+            process_udp_relay(
+                client_framed,
+                client_addr,
+                client_username,
+                user_traffic,
+                udp_client_addr,
+            )
+            .await?
+        }
+    }
     Ok(())
 }
+#[tokio::test]
+async fn test() {
+    let now = Utc::now();
+    let check_interval = Duration::from_secs(10);
+    // The user is rejected since the expired time
+    assert!(is_user_expired(&now, &now));
+    assert!(is_user_expired(&(now - TimeDelta::seconds(1)), &now));
+    assert!(!is_user_expired(&(now + TimeDelta::seconds(1)), &now));
+    // The user is only warned within the grace period
+    let grace_period = 7 * 24 * 3600;
+    assert!(is_in_expiry_grace_period(
+        &(now + TimeDelta::days(1)),
+        &now,
+        grace_period
+    ));
+    assert!(is_in_expiry_grace_period(
+        &(now + TimeDelta::days(7)),
+        &now,
+        grace_period
+    ));
+    assert!(!is_in_expiry_grace_period(
+        &(now + TimeDelta::days(8)),
+        &now,
+        grace_period
+    ));
+    // The live session checks the user again before the expired time
+    assert_eq!(
+        next_user_expiry_check(None, &now, check_interval),
+        Some(check_interval)
+    );
+    assert_eq!(
+        next_user_expiry_check(Some(&(now + TimeDelta::seconds(3))), &now, check_interval),
+        Some(Duration::from_secs(3))
+    );
+    assert_eq!(
+        next_user_expiry_check(Some(&(now + TimeDelta::days(1))), &now, check_interval),
+        Some(check_interval)
+    );
+    assert_eq!(
+        next_user_expiry_check(Some(&now), &now, check_interval),
+        None
+    );
+    // The agent is told why the destination setup fail
+    use SetupDestinationFailure as Failure;
+    let io_error = std::io::Error::from;
+    let destination_address = UnifiedAddress::from(SocketAddr::from(([127, 0, 0, 1], 80)));
+    for (error, failure) in [
+        (
+            Error::Io(io_error(ErrorKind::ConnectionRefused)),
+            Failure::ConnectionRefused,
+        ),
+        (Error::Io(io_error(ErrorKind::TimedOut)), Failure::Timeout),
+        (
+            Error::Io(io_error(ErrorKind::HostUnreachable)),
+            Failure::Unreachable,
+        ),
+        (
+            Error::Io(io_error(ErrorKind::NetworkUnreachable)),
+            Failure::Unreachable,
+        ),
+        (
+            Error::Io(io_error(ErrorKind::InvalidInput)),
+            Failure::DnsResolution,
+        ),
+        (Error::Io(io_error(ErrorKind::BrokenPipe)), Failure::Other),
+        (
+            Error::Protocol(ProtocolError::Io(io_error(ErrorKind::NotFound))),
+            Failure::DnsResolution,
+        ),
+        (
+            Error::Common(CommonError::Io(io_error(ErrorKind::ConnectionRefused))),
+            Failure::ConnectionRefused,
+        ),
+        (
+            Error::Common(CommonError::ConnectTimeout(10)),
+            Failure::Timeout,
+        ),
+        (
+            Error::Common(CommonError::SetupDestination(
+                destination_address,
+                Failure::AclDenied,
+            )),
+            Failure::AclDenied,
+        ),
+        (Error::BindAcceptTimeout(60), Failure::Timeout),
+        (
+            Error::DestinationDenied("127.0.0.1:80".to_owned()),
+            Failure::AclDenied,
+        ),
+        (Error::UserExpired("user1".to_owned()), Failure::UserExpired),
+        (
+            Error::QuotaExceeded("user1".to_owned()),
+            Failure::QuotaExceeded,
+        ),
+        (Error::ForwardHopNotExist("hop1".to_owned()), Failure::Other),
+    ] {
+        assert_eq!(setup_destination_failure(&error), failure, "{error:?}");
+    }
+    // The datagrams to the same forward hop share one relay
+    use tokio::io::duplex;
+    let plain = || {
+        SecureLengthDelimitedCodec::new(Arc::new(Encryption::Plain), Arc::new(Encryption::Plain))
+    };
+    // The relays created are delivered to the test as the hop side
+    let (created_sender, mut created_receiver) = tokio::sync::mpsc::unbounded_channel();
+    let create_relay = || {
+        let (proxy_io, hop_io) = duplex(64 * 1024);
+        let (proxy_sender, proxy_receiver) = UdpRelay::new(Framed::new(proxy_io, plain())).split();
+        let (hop_sender, hop_receiver) = UdpRelay::new(Framed::new(hop_io, plain())).split();
+        created_sender
+            .send((proxy_receiver, hop_sender, hop_receiver))
+            .unwrap();
+        async move { Ok(proxy_sender) }
+    };
+    let datagram = |payload: &[u8]| UdpDatagram {
+        src_addr: SocketAddr::from(([127, 0, 0, 1], 5353)).into(),
+        dst_addr: UnifiedAddress::Domain {
+            host: "example.com".to_owned(),
+            port: 53,
+        },
+        payload: payload.to_vec(),
+    };
+    let mut forward_udp_relays = ForwardUdpRelays::default();
+    for payload in [b"1", b"2"] {
+        forward_udp_relays
+            .send("hop1", datagram(payload), create_relay)
+            .await
+            .unwrap();
+    }
+    forward_udp_relays
+        .send("hop2", datagram(b"3"), create_relay)
+        .await
+        .unwrap();
+    // The proxy side receiver and the hop side sender are kept to keep the relay open
+    let mut hop1_relay = created_receiver.try_recv().unwrap();
+    let mut hop2_relay = created_receiver.try_recv().unwrap();
+    assert!(created_receiver.try_recv().is_err());
+    for payload in [b"1", b"2"] {
+        assert_eq!(hop1_relay.2.recv().await.unwrap().payload, payload);
+    }
+    assert_eq!(hop2_relay.2.recv().await.unwrap().payload, b"3");
+    // The relay closed by the forward hop is created again
+    drop(hop1_relay);
+    while !forward_udp_relays.relays["hop1"].is_closed() {
+        sleep(Duration::from_millis(10)).await;
+    }
+    forward_udp_relays
+        .send("hop1", datagram(b"4"), create_relay)
+        .await
+        .unwrap();
+    let mut hop1_relay = created_receiver.try_recv().unwrap();
+    assert_eq!(hop1_relay.2.recv().await.unwrap().payload, b"4");
+}
@@ -1,35 +1,116 @@
-use crate::config::{Config, ForwardConfig, get_config};
+use crate::acl::DestinationAcl;
+use crate::config::{Config, ForwardConfig, UserRepositoryType, get_config};
 use chrono::{DateTime, Utc};
-use common::user::repo::FileSystemUserRepository;
-use common::user::{User, UserRepository, UserWithExpiredTime, UserWithProxyServers};
+use common::Error as CommonError;
+use common::limit::ConnectionLimiter;
+use common::user::repo::{FileSystemUserRepository, HttpUserRepository, SqliteUserRepository};
+use common::user::{
+    User, UserRepository, UserRsaKey, UserWithExpiredTime, UserWithProxyServers,
+};
 use crypto::RsaCrypto;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::OnceLock;
-static USER_REPO: OnceLock<FileSystemUserRepository<ProxyUser, Config>> = OnceLock::new();
-static FORWARD_USER_REPO: OnceLock<Option<FileSystemUserRepository<ForwardUser, ForwardConfig>>> =
-    OnceLock::new();
+use std::ops::Deref;
+use std::sync::{Arc, OnceLock};
+use tracing::error;
+static USER_REPO: OnceLock<ProxyUserRepository> = OnceLock::new();
+static USER_SESSION_LIMITER: OnceLock<Arc<ConnectionLimiter<String>>> = OnceLock::new();
+static USER_MUX_STREAM_LIMITER: OnceLock<Arc<ConnectionLimiter<String>>> = OnceLock::new();
+static FORWARD_USER_REPOS: OnceLock<
+    HashMap<String, FileSystemUserRepository<ForwardUser, ForwardConfig>>,
+> = OnceLock::new();
+
+/// Get the limiter of the concurrent sessions of the proxy users.
+pub fn get_user_session_limiter() -> &'static Arc<ConnectionLimiter<String>> {
+    USER_SESSION_LIMITER.get_or_init(Default::default)
+}
+
+/// Get the limiter of the concurrent multiplexed streams of the proxy users,
+/// the streams in all the sessions of a user share the max sessions of it.
+pub fn get_user_mux_stream_limiter() -> &'static Arc<ConnectionLimiter<String>> {
+    USER_MUX_STREAM_LIMITER.get_or_init(Default::default)
+}
 
 /// Get the repository of the proxy user.
-pub fn get_user_repo() -> &'static FileSystemUserRepository<ProxyUser, Config> {
+pub(crate) fn get_user_repo() -> &'static ProxyUserRepository {
     USER_REPO.get_or_init(|| {
-        FileSystemUserRepository::<ProxyUser, Config>::new(get_config())
-            .expect("Fail to create user repository from file system")
+        ProxyUserRepository::new(get_config()).expect("Fail to create user repository")
     })
 }
 
-/// Get the repository of the forwarding user.
-pub fn get_forward_user_repo()
--> Option<&'static FileSystemUserRepository<ForwardUser, ForwardConfig>> {
-    FORWARD_USER_REPO
+/// The repository of the proxy user, the storage
+/// is chosen by the user repository type.
+pub(crate) enum ProxyUserRepository {
+    FileSystem(FileSystemUserRepository<ProxyUser, Config>),
+    Sqlite(SqliteUserRepository<ProxyUser, Config>),
+    Http(HttpUserRepository<ProxyUser, Config>),
+}
+impl UserRepository for ProxyUserRepository {
+    type UserInfoType = ProxyUser;
+    type UserRepoConfigType = Config;
+    fn new<T>(config: T) -> Result<Self, CommonError>
+    where
+        T: Deref<Target = Self::UserRepoConfigType> + Send + Sync + 'static,
+    {
+        match config.user_repo_type() {
+            UserRepositoryType::FileSystem => {
+                Ok(Self::FileSystem(FileSystemUserRepository::new(config)?))
+            }
+            UserRepositoryType::Sqlite => Ok(Self::Sqlite(SqliteUserRepository::new(config)?)),
+            UserRepositoryType::Http => Ok(Self::Http(HttpUserRepository::new(config)?)),
+        }
+    }
+    fn find_user(&self, username: &str) -> Option<Arc<Self::UserInfoType>> {
+        match self {
+            Self::FileSystem(user_repo) => user_repo.find_user(username),
+            Self::Sqlite(user_repo) => user_repo.find_user(username),
+            Self::Http(user_repo) => user_repo.find_user(username),
+        }
+    }
+    fn list_users(&self) -> Vec<Arc<Self::UserInfoType>> {
+        match self {
+            Self::FileSystem(user_repo) => user_repo.list_users(),
+            Self::Sqlite(user_repo) => user_repo.list_users(),
+            Self::Http(user_repo) => user_repo.list_users(),
+        }
+    }
+    fn save_user(&self, user: Self::UserInfoType) {
+        match self {
+            Self::FileSystem(user_repo) => user_repo.save_user(user),
+            Self::Sqlite(user_repo) => user_repo.save_user(user),
+            Self::Http(user_repo) => user_repo.save_user(user),
+        }
+    }
+}
+
+/// Get the repository of the forwarding user of the forward hop.
+pub fn get_forward_user_repo(
+    forward_hop_name: &str,
+) -> Option<&'static FileSystemUserRepository<ForwardUser, ForwardConfig>> {
+    FORWARD_USER_REPOS
         .get_or_init(|| {
-            let forward_user_repo = FileSystemUserRepository::<ForwardUser, ForwardConfig>::new(
-                get_config().forward()?,
-            )
-            .ok()?;
-            Some(forward_user_repo)
+            get_config()
+                .forward_hops()
+                .iter()
+                .filter_map(|forward_hop| {
+                    match FileSystemUserRepository::<ForwardUser, ForwardConfig>::new(forward_hop)
+                    {
+                        Ok(forward_user_repo) => {
+                            Some((forward_hop.name().to_owned(), forward_user_repo))
+                        }
+                        Err(e) => {
+                            error!(
+                                "Fail to create user repository of forward hop [{}]: {e:?}",
+                                forward_hop.name()
+                            );
+                            None
+                        }
+                    }
+                })
+                .collect()
         })
-        .as_ref()
+        .get(forward_hop_name)
 }
 
 /// The user in proxy side
@@ -37,8 +118,43 @@ pub fn get_forward_user_repo()
 pub struct ProxyUser {
     username: String,
     expired_time: Option<DateTime<Utc>>,
+    /// The access control of the destinations for this user,
+    /// applied together with the global one.
+    #[serde(default)]
+    destination_acl: DestinationAcl,
+    /// The bytes per second from the client to the destinations
+    upload_rate_limit: Option<u64>,
+    /// The bytes per second from the destinations to the client
+    download_rate_limit: Option<u64>,
+    /// The bytes can be transferred in both directions each month
+    monthly_traffic_quota: Option<u64>,
+    /// The max concurrent sessions, each connection from the agent is
+    /// a session, include the pooled ones. The streams in the multiplexed
+    /// sessions are limited by the same number separately.
+    max_sessions: Option<usize>,
     #[serde(skip)]
     rsa_crypto: Option<RsaCrypto>,
+    /// The keys can be used by the agent, the
+    /// rotated keys are kept until they expire.
+    #[serde(skip)]
+    rsa_keys: Vec<UserRsaKey>,
+}
+impl ProxyUser {
+    pub(crate) fn destination_acl(&self) -> &DestinationAcl {
+        &self.destination_acl
+    }
+    pub(crate) fn upload_rate_limit(&self) -> Option<u64> {
+        self.upload_rate_limit
+    }
+    pub(crate) fn download_rate_limit(&self) -> Option<u64> {
+        self.download_rate_limit
+    }
+    pub(crate) fn monthly_traffic_quota(&self) -> Option<u64> {
+        self.monthly_traffic_quota
+    }
+    pub(crate) fn max_sessions(&self) -> Option<usize> {
+        self.max_sessions
+    }
 }
 
 impl User for ProxyUser {
@@ -51,6 +167,12 @@ impl User for ProxyUser {
     fn set_rsa_crypto(&mut self, rsa_crypto: RsaCrypto) {
         self.rsa_crypto = Some(rsa_crypto)
     }
+    fn rsa_keys(&self) -> &[UserRsaKey] {
+        &self.rsa_keys
+    }
+    fn set_rsa_keys(&mut self, rsa_keys: Vec<UserRsaKey>) {
+        self.rsa_keys = rsa_keys
+    }
 }
 impl UserWithExpiredTime for ProxyUser {
     fn expired_time(&self) -> Option<&DateTime<Utc>> {